@@ -0,0 +1,252 @@
+// Layout of a macOS application bundle.
+//
+//     Foo.app/Contents/Info.plist
+//     Foo.app/Contents/MacOS/<executables>
+//     Foo.app/Contents/Frameworks/<libraries>
+//     Foo.app/Contents/Resources/
+//
+// Executables given as input go to `MacOS`, libraries given as input and
+// every dependency to `Frameworks`. `Info.plist` is generated from the values
+// below, the minimum macOS version defaults to the one the main executable
+// was built for.
+//
+// Existing `.app` and `.framework` bundles can be repaired in place, their
+// binaries are looked up in the folders listed in `binary_folders`.
+
+use crate::error::Error;
+use crate::helpers::read_slices;
+use crate::macho::{PLATFORM_MACOS, Version};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const EXECUTABLES_PATH: &str = "Contents/MacOS";
+pub const FRAMEWORKS_PATH: &str = "Contents/Frameworks";
+pub const RESOURCES_PATH: &str = "Contents/Resources";
+pub const INFO_PLIST_PATH: &str = "Contents/Info.plist";
+
+// Folders of an app which may contain code, relative to `Contents`.
+const APP_CODE_FOLDERS: [&str; 5] = ["MacOS", "Frameworks", "PlugIns", "Helpers", "XPCServices"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleKind {
+    App,
+    Framework,
+}
+
+impl BundleKind {
+    /// Kind of a bundle folder by its extension, `None` for anything else.
+    pub fn of(path: &Path) -> Option<Self> {
+        if !path.is_dir() {
+            return None;
+        }
+        match path.extension()?.to_str()? {
+            "app" => Some(BundleKind::App),
+            "framework" => Some(BundleKind::Framework),
+            _ => None,
+        }
+    }
+
+    /// Folders containing the binaries of `bundle`, relative to it. A
+    /// framework is searched as a whole, its `Versions/Current` links are
+    /// skipped by the search.
+    pub fn binary_folders(self, bundle: &Path) -> Vec<PathBuf> {
+        match self {
+            BundleKind::App => APP_CODE_FOLDERS
+                .iter()
+                .map(|folder| Path::new("Contents").join(folder))
+                .filter(|folder| bundle.join(folder).is_dir())
+                .collect(),
+            BundleKind::Framework => vec![PathBuf::new()],
+        }
+    }
+
+    /// Where missing libraries are copied to, relative to the bundle. A
+    /// versioned framework keeps them in its current version.
+    pub fn libs_path(self, bundle: &Path) -> Result<PathBuf> {
+        match self {
+            BundleKind::App => Ok(PathBuf::from(FRAMEWORKS_PATH)),
+            BundleKind::Framework => {
+                let current = bundle.join("Versions/Current");
+                if current.symlink_metadata().is_err() {
+                    return Ok(PathBuf::from("Frameworks"));
+                }
+                let version =
+                    std::fs::canonicalize(&current).map_err(Error::io("resolving", &current))?;
+                let Some(version) = version.file_name() else {
+                    return Err(anyhow!(
+                        "Error while reading current version of: {}",
+                        bundle.display()
+                    ));
+                };
+                Ok(Path::new("Versions").join(version).join("Frameworks"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppInfo {
+    /// `CFBundleIdentifier`, e.g. `com.example.foo`.
+    pub identifier: String,
+    /// `CFBundleShortVersionString` and `CFBundleVersion`.
+    pub version: String,
+    /// Defaults to the name of the `.app` folder.
+    pub name: Option<String>,
+    /// File name of the main executable, defaults to the first executable
+    /// given as input.
+    pub executable: Option<String>,
+    /// Defaults to the LC_BUILD_VERSION of the executable.
+    pub minimum_system_version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct InfoPlist {
+    #[serde(rename = "CFBundleDevelopmentRegion")]
+    development_region: String,
+    #[serde(rename = "CFBundleExecutable")]
+    executable: String,
+    #[serde(rename = "CFBundleIdentifier")]
+    identifier: String,
+    #[serde(rename = "CFBundleInfoDictionaryVersion")]
+    info_dictionary_version: String,
+    #[serde(rename = "CFBundleName")]
+    name: String,
+    #[serde(rename = "CFBundlePackageType")]
+    package_type: String,
+    #[serde(rename = "CFBundleShortVersionString")]
+    short_version: String,
+    #[serde(rename = "CFBundleVersion")]
+    version: String,
+    #[serde(
+        rename = "LSMinimumSystemVersion",
+        skip_serializing_if = "Option::is_none"
+    )]
+    minimum_system_version: Option<String>,
+}
+
+impl AppInfo {
+    /// Contents of `Info.plist` for the app in `app_folder`. `executable` is
+    /// the source of the main executable and `executable_name` its path
+    /// inside `Contents/MacOS`.
+    pub fn info_plist(
+        &self,
+        app_folder: &Path,
+        executable: &Path,
+        executable_name: &str,
+    ) -> Result<String> {
+        let name = match self.name {
+            Some(ref name) => name.clone(),
+            None => file_stem(app_folder)?,
+        };
+        let minimum_system_version = match self.minimum_system_version {
+            Some(ref version) => Some(version.clone()),
+            None => minimum_macos(executable)?.map(format_version),
+        };
+        if minimum_system_version.is_none() {
+            log::warn!(
+                "No macOS build version found in: {}\nLSMinimumSystemVersion is left out",
+                executable.display()
+            );
+        }
+
+        let info = InfoPlist {
+            development_region: "en".to_string(),
+            executable: executable_name.to_string(),
+            identifier: self.identifier.clone(),
+            info_dictionary_version: "6.0".to_string(),
+            name,
+            package_type: "APPL".to_string(),
+            short_version: self.version.clone(),
+            version: self.version.clone(),
+            minimum_system_version,
+        };
+        let mut xml = vec![];
+        plist::to_writer_xml(&mut xml, &info)?;
+        Ok(String::from_utf8(xml)? + "\n")
+    }
+}
+
+/// Lowest macOS version any slice of `file_path` was built for.
+fn minimum_macos(file_path: &Path) -> Result<Option<Version>> {
+    Ok(read_slices(file_path)?
+        .iter()
+        .filter_map(|macho| macho.build_version())
+        .filter(|build| build.platform == PLATFORM_MACOS)
+        .map(|build| build.minos)
+        .min())
+}
+
+// `11.0` or `10.15.4`, the way Info.plist files spell it.
+fn format_version(version: Version) -> String {
+    if version.patch() == 0 {
+        format!("{}.{}", version.major(), version.minor())
+    } else {
+        version.to_string()
+    }
+}
+
+fn file_stem(path: &Path) -> Result<String> {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("Error while reading file name of: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_info_plist() {
+        let exe = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let info = AppInfo {
+            identifier: "com.example.pdftoppm".to_string(),
+            version: "1.2.3".to_string(),
+            ..Default::default()
+        };
+        let xml = info
+            .info_plist(Path::new("/tmp/Poppler.app"), &exe, "pdftoppm")
+            .unwrap();
+        let parsed: InfoPlist = plist::from_bytes(xml.as_bytes()).unwrap();
+        assert_eq!(parsed.executable, "pdftoppm");
+        assert_eq!(parsed.name, "Poppler");
+        assert_eq!(parsed.identifier, "com.example.pdftoppm");
+        assert_eq!(parsed.short_version, "1.2.3");
+        let minos = minimum_macos(&exe).unwrap().unwrap();
+        assert_eq!(
+            parsed.minimum_system_version.as_deref(),
+            Some(format_version(minos).as_str())
+        );
+        assert_eq!(format_version(Version(0x000b_0000)), "11.0");
+        assert_eq!(format_version(Version(0x000a_0f04)), "10.15.4");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bundle_kind() {
+        let root = tempfile::tempdir().unwrap();
+        let framework = root.path().join("Qt.framework");
+        std::fs::create_dir_all(framework.join("Versions/A")).unwrap();
+        assert_eq!(BundleKind::of(&framework), Some(BundleKind::Framework));
+        assert_eq!(
+            BundleKind::Framework.libs_path(&framework).unwrap(),
+            Path::new("Frameworks")
+        );
+        std::os::unix::fs::symlink("A", framework.join("Versions/Current")).unwrap();
+        assert_eq!(
+            BundleKind::Framework.libs_path(&framework).unwrap(),
+            Path::new("Versions/A/Frameworks")
+        );
+
+        let app = root.path().join("Foo.app");
+        std::fs::create_dir_all(app.join("Contents/MacOS")).unwrap();
+        std::fs::create_dir_all(app.join("Contents/PlugIns")).unwrap();
+        assert_eq!(BundleKind::of(&app), Some(BundleKind::App));
+        assert_eq!(
+            BundleKind::App.binary_folders(&app),
+            [Path::new("Contents/MacOS"), Path::new("Contents/PlugIns")]
+        );
+        assert_eq!(BundleKind::of(root.path()), None);
+    }
+}
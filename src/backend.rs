@@ -0,0 +1,944 @@
+// Access to Mach-O files behind a trait.
+//
+// Bundling only needs three operations on a binary: reading its identity,
+// dependencies and rpaths, changing load commands and signing it. They are
+// provided by:
+//
+// - `NativeBackend`: the in-process Mach-O parser, rewriter and signer. This
+//   is the default and works on any host.
+// - `ToolsBackend`: Apple's `otool`, `install_name_tool` and `codesign`, for
+//   comparing against the reference tools on macOS.
+// - `RecordBackend` / `ReplayBackend`: capture what another backend saw and
+//   did into a JSON fixture and play it back later without any Mach-O file.
+
+use crate::codesign::{sign_bundle, sign_file};
+use crate::error::Error;
+use crate::helpers::{merge_dependencies, merge_id, read_slices};
+use crate::macho::{Dylib, DylibKind, MH_BUNDLE, MH_DYLIB, MH_EXECUTE, MachO, Version, arch_name};
+use crate::rewrite::{Edit, edit_file_with};
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub trait Backend: fmt::Debug {
+    /// Reads the identity, dependencies and rpaths of a binary.
+    fn inspect(&self, file_path: &Path) -> Result<Image>;
+
+    /// Applies load command edits. Adding an rpath which is already there or
+    /// deleting one which is not is skipped per architecture slice.
+    fn change(&self, file_path: &Path, edits: &[Edit]) -> Result<()>;
+
+    /// Ad-hoc signs a binary, or a framework version folder together with
+    /// its resources.
+    fn sign(&self, file_path: &Path) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageKind {
+    Executable,
+    Dylib,
+    /// Loadable bundle (MH_BUNDLE), e.g. a plugin.
+    Bundle,
+    Other,
+}
+
+/// What a backend knows about a binary. For universal binaries the slices
+/// are merged, dependencies and ids have to agree between them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Image {
+    pub kind: ImageKind,
+    pub archs: Vec<String>,
+    #[serde(with = "uuid_strings")]
+    pub uuids: Vec<[u8; 16]>,
+    pub id: Option<String>,
+    /// Versions of the id.
+    pub current_version: Option<Version>,
+    pub compatibility_version: Option<Version>,
+    pub dependencies: Vec<Dylib>,
+    pub rpaths: Vec<String>,
+}
+
+// UUIDs are written the way otool and dwarfdump print them.
+mod uuid_strings {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(uuids: &[[u8; 16]], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(uuids.iter().map(super::format_uuid))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<[u8; 16]>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|uuid| super::parse_uuid(uuid).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn push_unique(list: &mut Vec<String>, value: &str) {
+    if !list.iter().any(|v| v == value) {
+        list.push(value.to_string());
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NativeBackend;
+
+impl Backend for NativeBackend {
+    fn inspect(&self, file_path: &Path) -> Result<Image> {
+        let slices = read_slices(file_path)?;
+        let kind = if slices.iter().all(MachO::is_executable) {
+            ImageKind::Executable
+        } else if slices.iter().all(MachO::is_dylib) {
+            ImageKind::Dylib
+        } else if slices.iter().all(MachO::is_bundle) {
+            ImageKind::Bundle
+        } else {
+            ImageKind::Other
+        };
+        let id = match kind {
+            ImageKind::Dylib => Some(merge_id(file_path, &slices)?),
+            _ => None,
+        };
+        let id_dylib = slices.iter().find_map(|macho| macho.id());
+        let mut rpaths = vec![];
+        for rpath in slices.iter().flat_map(|macho| macho.rpaths()) {
+            push_unique(&mut rpaths, rpath);
+        }
+
+        Ok(Image {
+            kind,
+            archs: slices
+                .iter()
+                .map(|macho| macho.header.arch_name().to_string())
+                .collect(),
+            uuids: slices.iter().filter_map(|macho| macho.uuid()).collect(),
+            id,
+            current_version: id_dylib.map(|id| id.current_version),
+            compatibility_version: id_dylib.map(|id| id.compatibility_version),
+            dependencies: merge_dependencies(file_path, &slices)?,
+            rpaths,
+        })
+    }
+
+    fn change(&self, file_path: &Path, edits: &[Edit]) -> Result<()> {
+        edit_file_with(file_path, |macho| {
+            edits
+                .iter()
+                .filter(|edit| match edit {
+                    Edit::AddRpath(rpath) => !macho.rpaths().any(|rp| rp == rpath),
+                    Edit::DeleteRpath(rpath) => macho.rpaths().any(|rp| rp == rpath),
+                    _ => true,
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn sign(&self, file_path: &Path) -> Result<()> {
+        if file_path.is_dir() {
+            sign_bundle(file_path)
+        } else {
+            sign_file(file_path)
+        }
+    }
+}
+
+/// Output of an external tool.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToolOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs external tools, replaced in tests by canned outputs.
+pub trait Runner: fmt::Debug {
+    fn run(&self, program: &str, args: &[String]) -> Result<ToolOutput>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn run(&self, program: &str, args: &[String]) -> Result<ToolOutput> {
+        log::debug!("Running: {} {}", program, args.join(" "));
+        let output = std::process::Command::new(program)
+            .args(args)
+            .output()
+            .map_err(Error::io("running", Path::new(program)))?;
+        Ok(ToolOutput {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ToolsBackend<R: Runner = SystemRunner> {
+    runner: R,
+}
+
+impl<R: Runner> ToolsBackend<R> {
+    pub fn new(runner: R) -> Self {
+        ToolsBackend { runner }
+    }
+
+    fn run(&self, program: &str, args: Vec<String>) -> Result<String> {
+        let output = self.runner.run(program, &args)?;
+        if !output.success {
+            return Err(Error::ToolFailure {
+                program: program.to_string(),
+                command: format!("{} {}", program, args.join(" ")),
+                stdout: output.stdout.trim().to_string(),
+                stderr: output.stderr.trim().to_string(),
+            }
+            .into());
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl<R: Runner> Backend for ToolsBackend<R> {
+    fn inspect(&self, file_path: &Path) -> Result<Image> {
+        let path = file_path.display().to_string();
+        let headers = parse_otool_headers(&self.run(
+            "otool",
+            vec!["-arch".into(), "all".into(), "-h".into(), path.clone()],
+        )?)?;
+        let slices = parse_otool_load_commands(&self.run(
+            "otool",
+            vec!["-arch".into(), "all".into(), "-l".into(), path],
+        )?)?;
+        if headers.is_empty() || headers.len() != slices.len() {
+            return Err(anyhow!(
+                "Unexpected otool output for: {}",
+                file_path.display()
+            ));
+        }
+
+        let kind = if headers.iter().all(|h| h.filetype == MH_EXECUTE) {
+            ImageKind::Executable
+        } else if headers.iter().all(|h| h.filetype == MH_DYLIB) {
+            ImageKind::Dylib
+        } else if headers.iter().all(|h| h.filetype == MH_BUNDLE) {
+            ImageKind::Bundle
+        } else {
+            ImageKind::Other
+        };
+
+        let first = &slices[0];
+        for slice in &slices[1..] {
+            let names = |slice: &OtoolSlice| -> Vec<(String, DylibKind)> {
+                slice
+                    .dependencies
+                    .iter()
+                    .map(|d| (d.name.clone(), d.kind))
+                    .collect()
+            };
+            if slice.id != first.id || names(slice) != names(first) {
+                return Err(anyhow!(
+                    "Architecture slices disagree on dependencies of: {}",
+                    file_path.display()
+                ));
+            }
+        }
+        if kind == ImageKind::Dylib && first.id.is_none() {
+            return Err(anyhow!("No id found in: {}", file_path.display()));
+        }
+        let mut rpaths = vec![];
+        for rpath in slices.iter().flat_map(|slice| &slice.rpaths) {
+            push_unique(&mut rpaths, rpath);
+        }
+
+        Ok(Image {
+            kind,
+            archs: headers.iter().map(|h| h.arch.to_string()).collect(),
+            uuids: slices.iter().filter_map(|slice| slice.uuid).collect(),
+            id: first.id.clone(),
+            current_version: first.current_version,
+            compatibility_version: first.compatibility_version,
+            dependencies: first.dependencies.clone(),
+            rpaths,
+        })
+    }
+
+    fn change(&self, file_path: &Path, edits: &[Edit]) -> Result<()> {
+        // install_name_tool fails on rpath edits which are no-ops and applies
+        // every edit to all slices. Rpath edits which apply to no slice are
+        // dropped, those which apply to some slices only are left to the
+        // native backend together with the removal of load commands, which
+        // install_name_tool can't do at all.
+        let rpaths = if edits
+            .iter()
+            .any(|edit| matches!(edit, Edit::AddRpath(_) | Edit::DeleteRpath(_)))
+        {
+            let slices = parse_otool_load_commands(&self.run(
+                "otool",
+                vec![
+                    "-arch".into(),
+                    "all".into(),
+                    "-l".into(),
+                    file_path.display().to_string(),
+                ],
+            )?)?;
+            slices.into_iter().map(|slice| slice.rpaths).collect()
+        } else {
+            vec![]
+        };
+        let mut native = vec![];
+        let mut tool = vec![];
+        for edit in edits {
+            let applies: Vec<bool> = rpaths
+                .iter()
+                .map(|rpaths: &Vec<String>| match edit {
+                    Edit::AddRpath(rpath) => !rpaths.contains(rpath),
+                    Edit::DeleteRpath(rpath) => rpaths.contains(rpath),
+                    _ => true,
+                })
+                .collect();
+            if matches!(edit, Edit::RemoveDylib(_)) {
+                native.push(edit.clone());
+            } else if applies.iter().all(|applies| *applies) {
+                tool.push(edit.clone());
+            } else if applies.iter().any(|applies| *applies) {
+                native.push(edit.clone());
+            } else {
+                log::debug!("Skipping no-op edit of {}: {:?}", file_path.display(), edit);
+            }
+        }
+        if !native.is_empty() {
+            NativeBackend.change(file_path, &native)?;
+        }
+        if tool.is_empty() {
+            return Ok(());
+        }
+        let mut args: Vec<String> = vec![];
+        for edit in &tool {
+            match edit {
+                Edit::SetId(id) => args.extend(["-id".into(), id.clone()]),
+                Edit::ChangeInstallName { old, new } => {
+                    args.extend(["-change".into(), old.clone(), new.clone()])
+                }
+                Edit::AddRpath(rpath) => args.extend(["-add_rpath".into(), rpath.clone()]),
+                Edit::DeleteRpath(rpath) => args.extend(["-delete_rpath".into(), rpath.clone()]),
+                Edit::ChangeRpath { old, new } => {
+                    args.extend(["-rpath".into(), old.clone(), new.clone()])
+                }
+                Edit::RemoveDylib(_) => {}
+            }
+        }
+        args.push(file_path.display().to_string());
+        self.run("install_name_tool", args)?;
+        Ok(())
+    }
+
+    fn sign(&self, file_path: &Path) -> Result<()> {
+        let args = vec![
+            "--force".into(),
+            "--sign".into(),
+            "-".into(),
+            file_path.display().to_string(),
+        ];
+        self.run("codesign", args)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OtoolHeader {
+    arch: &'static str,
+    filetype: u32,
+}
+
+// `otool -h` prints one header table per architecture:
+//
+//       magic  cputype cpusubtype  caps    filetype ncmds sizeofcmds      flags
+//  0xfeedfacf 16777228          0  0x00           6    21       2424 0x00118085
+fn parse_otool_headers(output: &str) -> Result<Vec<OtoolHeader>> {
+    let mut headers = vec![];
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 || !fields[0].starts_with("0xfeedfac") {
+            continue;
+        }
+        let (Ok(cputype), Ok(cpusubtype), Ok(filetype)) = (
+            fields[1].parse::<i64>(),
+            fields[2].parse::<i64>(),
+            fields[4].parse::<u32>(),
+        ) else {
+            return Err(anyhow!("Unrecognized otool header line: {}", line.trim()));
+        };
+        headers.push(OtoolHeader {
+            arch: arch_name(cputype as i32, cpusubtype as i32),
+            filetype,
+        });
+    }
+    Ok(headers)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct OtoolSlice {
+    id: Option<String>,
+    current_version: Option<Version>,
+    compatibility_version: Option<Version>,
+    dependencies: Vec<Dylib>,
+    rpaths: Vec<String>,
+    uuid: Option<[u8; 16]>,
+}
+
+// `otool -l` prints the file name (with the architecture for universal
+// binaries) followed by one block per load command:
+//
+//     Load command 12
+//               cmd LC_LOAD_DYLIB
+//           cmdsize 56
+//              name @rpath/libfoo.dylib (offset 24)
+//        time stamp 2 Thu Jan  1 00:00:02 1970
+//           current version 1.2.3
+//     compatibility version 1.0.0
+fn parse_otool_load_commands(output: &str) -> Result<Vec<OtoolSlice>> {
+    let mut slices: Vec<OtoolSlice> = vec![];
+    let mut block: Vec<&str> = vec![];
+
+    for line in output.lines().chain(std::iter::once("")) {
+        let is_file_line = !line.starts_with(char::is_whitespace)
+            && line.ends_with(':')
+            && !line.starts_with("Load command");
+        if line.starts_with("Load command") || is_file_line || line.is_empty() {
+            if !block.is_empty() {
+                let Some(slice) = slices.last_mut() else {
+                    return Err(anyhow!("Load command before file name in otool output"));
+                };
+                parse_otool_block(&block, slice)?;
+                block.clear();
+            }
+            if is_file_line {
+                slices.push(OtoolSlice::default());
+            }
+            continue;
+        }
+        block.push(line.trim());
+    }
+    Ok(slices)
+}
+
+fn parse_otool_block(block: &[&str], slice: &mut OtoolSlice) -> Result<()> {
+    let field = |key: &str| {
+        block.iter().find_map(|line| {
+            line.strip_prefix(key)
+                .filter(|rest| rest.starts_with(' '))
+                .map(str::trim)
+        })
+    };
+    // `name` and `path` end with the offset of the string in the command.
+    let string_field = |key: &str| {
+        field(key).map(|value| match value.rfind(" (offset ") {
+            Some(end) => value[..end].to_string(),
+            None => value.to_string(),
+        })
+    };
+
+    let Some(cmd) = field("cmd") else {
+        return Ok(());
+    };
+    match cmd {
+        "LC_RPATH" => {
+            if let Some(path) = string_field("path") {
+                slice.rpaths.push(path);
+            }
+        }
+        "LC_UUID" => {
+            if let Some(uuid) = field("uuid") {
+                slice.uuid = Some(parse_uuid(uuid)?);
+            }
+        }
+        _ => {
+            let Some(kind) = DYLIB_COMMANDS
+                .iter()
+                .find(|kind| kind.name() == cmd)
+                .copied()
+            else {
+                return Ok(());
+            };
+            let Some(name) = string_field("name") else {
+                return Err(anyhow!("No name found in otool output for: {}", cmd));
+            };
+            let timestamp = field("time stamp")
+                .and_then(|value| value.split_whitespace().next())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0);
+            let dylib = Dylib {
+                kind,
+                name,
+                timestamp,
+                current_version: field("current version").unwrap_or("0").parse()?,
+                compatibility_version: field("compatibility version").unwrap_or("0").parse()?,
+            };
+            if kind == DylibKind::Id {
+                slice.id = Some(dylib.name);
+                slice.current_version = Some(dylib.current_version);
+                slice.compatibility_version = Some(dylib.compatibility_version);
+            } else {
+                slice.dependencies.push(dylib);
+            }
+        }
+    }
+    Ok(())
+}
+
+const DYLIB_COMMANDS: [DylibKind; 6] = [
+    DylibKind::Id,
+    DylibKind::Load,
+    DylibKind::Weak,
+    DylibKind::Reexport,
+    DylibKind::Lazy,
+    DylibKind::Upward,
+];
+
+fn parse_uuid(value: &str) -> Result<[u8; 16]> {
+    let hex: String = value.chars().filter(|c| *c != '-').collect();
+    let mut uuid = [0u8; 16];
+    if hex.len() != 32 {
+        return Err(anyhow!("Unrecognized uuid: {}", value));
+    }
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("Unrecognized uuid: {}", value))?;
+    }
+    Ok(uuid)
+}
+
+/// Everything a backend was asked, with paths relative to named roots so a
+/// recording can be replayed in another folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recording {
+    pub images: BTreeMap<String, Image>,
+    pub calls: Vec<Call>,
+}
+
+/// A call changing a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Call {
+    Change { file: String, edits: Vec<Edit> },
+    Sign { file: String },
+}
+
+impl Recording {
+    pub fn load(file_path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(file_path).map_err(Error::io("reading", file_path))?;
+        serde_json::from_str(&data)
+            .with_context(|| format!("Error while parsing: {}", file_path.display()))
+    }
+
+    pub fn save(&self, file_path: &Path) -> Result<()> {
+        std::fs::write(file_path, serde_json::to_string_pretty(self)? + "\n")
+            .map_err(Error::io("writing", file_path))?;
+        Ok(())
+    }
+}
+
+/// Named folders, e.g. `$INPUT` and `$OUTPUT`, paths inside them are written
+/// as `$INPUT/libs/libfoo.dylib`.
+#[derive(Debug, Clone, Default)]
+struct Roots(Vec<(String, PathBuf)>);
+
+impl Roots {
+    fn new(roots: &[(&str, &Path)]) -> Self {
+        Roots(
+            roots
+                .iter()
+                .map(|(name, path)| (name.to_string(), path.to_path_buf()))
+                .collect(),
+        )
+    }
+
+    fn key(&self, file_path: &Path) -> String {
+        for (name, root) in &self.0 {
+            if let Ok(rest) = file_path.strip_prefix(root) {
+                return Path::new(name).join(rest).display().to_string();
+            }
+        }
+        file_path.display().to_string()
+    }
+}
+
+/// Forwards to another backend and records what it returned and was asked
+/// to change.
+#[derive(Debug)]
+pub struct RecordBackend<B: Backend> {
+    inner: B,
+    roots: Roots,
+    recording: RefCell<Recording>,
+}
+
+impl<B: Backend> RecordBackend<B> {
+    pub fn new(inner: B, roots: &[(&str, &Path)]) -> Self {
+        RecordBackend {
+            inner,
+            roots: Roots::new(roots),
+            recording: RefCell::new(Recording::default()),
+        }
+    }
+
+    pub fn recording(&self) -> Recording {
+        self.recording.borrow().clone()
+    }
+}
+
+impl<B: Backend> Backend for RecordBackend<B> {
+    fn inspect(&self, file_path: &Path) -> Result<Image> {
+        let image = self.inner.inspect(file_path)?;
+        self.recording
+            .borrow_mut()
+            .images
+            .insert(self.roots.key(file_path), image.clone());
+        Ok(image)
+    }
+
+    fn change(&self, file_path: &Path, edits: &[Edit]) -> Result<()> {
+        self.inner.change(file_path, edits)?;
+        self.recording.borrow_mut().calls.push(Call::Change {
+            file: self.roots.key(file_path),
+            edits: edits.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn sign(&self, file_path: &Path) -> Result<()> {
+        self.inner.sign(file_path)?;
+        self.recording.borrow_mut().calls.push(Call::Sign {
+            file: self.roots.key(file_path),
+        });
+        Ok(())
+    }
+}
+
+/// Answers `inspect` from a recording and only records changes, the files
+/// themselves are never read or written.
+#[derive(Debug)]
+pub struct ReplayBackend {
+    images: BTreeMap<String, Image>,
+    roots: Roots,
+    calls: RefCell<Vec<Call>>,
+}
+
+impl ReplayBackend {
+    pub fn new(recording: &Recording, roots: &[(&str, &Path)]) -> Self {
+        ReplayBackend {
+            images: recording.images.clone(),
+            roots: Roots::new(roots),
+            calls: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.borrow().clone()
+    }
+}
+
+impl Backend for ReplayBackend {
+    fn inspect(&self, file_path: &Path) -> Result<Image> {
+        let key = self.roots.key(file_path);
+        let Some(image) = self.images.get(&key) else {
+            return Err(anyhow!("No recorded image for: {}", key));
+        };
+        Ok(image.clone())
+    }
+
+    fn change(&self, file_path: &Path, edits: &[Edit]) -> Result<()> {
+        self.calls.borrow_mut().push(Call::Change {
+            file: self.roots.key(file_path),
+            edits: edits.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn sign(&self, file_path: &Path) -> Result<()> {
+        self.calls.borrow_mut().push(Call::Sign {
+            file: self.roots.key(file_path),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const OTOOL_H: &str = "\
+/tmp/libfoo.dylib (architecture x86_64):
+Mach header
+      magic  cputype cpusubtype  caps    filetype ncmds sizeofcmds      flags
+ 0xfeedfacf 16777223          3  0x00           6    14       1392 0x00118085
+/tmp/libfoo.dylib (architecture arm64):
+Mach header
+      magic  cputype cpusubtype  caps    filetype ncmds sizeofcmds      flags
+ 0xfeedfacf 16777228          0  0x00           6    15       1480 0x00118085
+";
+
+    fn _otool_l_slice(arch: &str, uuid: &str, rpath: &str) -> String {
+        format!(
+            "\
+/tmp/libfoo.dylib (architecture {arch}):
+Load command 0
+      cmd LC_SEGMENT_64
+  cmdsize 72
+  segname __PAGEZERO
+Load command 1
+          cmd LC_ID_DYLIB
+      cmdsize 48
+         name @rpath/libfoo.1.dylib (offset 24)
+   time stamp 1 Thu Jan  1 00:00:01 1970
+      current version 1.4.2
+compatibility version 1.0.0
+Load command 2
+     cmd LC_UUID
+ cmdsize 24
+    uuid {uuid}
+Load command 3
+          cmd LC_LOAD_DYLIB
+      cmdsize 56
+         name @rpath/libbar.2.dylib (offset 24)
+   time stamp 2 Thu Jan  1 00:00:02 1970
+      current version 2.1.0
+compatibility version 2.0.0
+Load command 4
+          cmd LC_LOAD_WEAK_DYLIB
+      cmdsize 56
+         name /usr/lib/libSystem.B.dylib (offset 24)
+   time stamp 2 Thu Jan  1 00:00:02 1970
+      current version 1351.0.0
+compatibility version 1.0.0
+Load command 5
+          cmd LC_RPATH
+      cmdsize 32
+         path {rpath} (offset 12)
+"
+        )
+    }
+
+    #[derive(Debug, Default)]
+    struct FakeRunner {
+        outputs: HashMap<String, ToolOutput>,
+        runs: RefCell<Vec<String>>,
+    }
+
+    impl FakeRunner {
+        fn with(mut self, command: &str, success: bool, stdout: &str, stderr: &str) -> Self {
+            self.outputs.insert(
+                command.to_string(),
+                ToolOutput {
+                    success,
+                    stdout: stdout.to_string(),
+                    stderr: stderr.to_string(),
+                },
+            );
+            self
+        }
+    }
+
+    impl Runner for &FakeRunner {
+        fn run(&self, program: &str, args: &[String]) -> Result<ToolOutput> {
+            let command = format!("{} {}", program, args.join(" "));
+            self.runs.borrow_mut().push(command.clone());
+            Ok(self.outputs.get(&command).cloned().unwrap_or(ToolOutput {
+                success: true,
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[test]
+    fn test_tools_inspect() {
+        let otool_l = _otool_l_slice(
+            "x86_64",
+            "00112233-4455-6677-8899-AABBCCDDEEFF",
+            "@loader_path/../lib",
+        ) + &_otool_l_slice(
+            "arm64",
+            "FFEEDDCC-BBAA-9988-7766-554433221100",
+            "@loader_path",
+        );
+        let runner = FakeRunner::default()
+            .with("otool -arch all -h /tmp/libfoo.dylib", true, OTOOL_H, "")
+            .with("otool -arch all -l /tmp/libfoo.dylib", true, &otool_l, "");
+        let image = ToolsBackend::new(&runner)
+            .inspect(Path::new("/tmp/libfoo.dylib"))
+            .unwrap();
+
+        assert_eq!(image.kind, ImageKind::Dylib);
+        assert_eq!(image.archs, vec!["x86_64", "arm64"]);
+        assert_eq!(image.id.as_deref(), Some("@rpath/libfoo.1.dylib"));
+        assert_eq!(image.current_version, Some(Version::new(1, 4, 2)));
+        assert_eq!(image.compatibility_version, Some(Version::new(1, 0, 0)));
+        assert_eq!(image.uuids.len(), 2);
+        assert_eq!(image.uuids[0][0], 0x00);
+        assert_eq!(image.uuids[0][15], 0xff);
+        assert_eq!(image.rpaths, vec!["@loader_path/../lib", "@loader_path"]);
+
+        assert_eq!(image.dependencies.len(), 2);
+        let bar = &image.dependencies[0];
+        assert_eq!(bar.kind, DylibKind::Load);
+        assert_eq!(bar.name, "@rpath/libbar.2.dylib");
+        assert_eq!(bar.current_version.to_string(), "2.1.0");
+        assert_eq!(bar.compatibility_version.to_string(), "2.0.0");
+        assert_eq!(image.dependencies[1].kind, DylibKind::Weak);
+    }
+
+    #[test]
+    fn test_tools_change_and_sign() {
+        let file = Path::new("/tmp/libfoo.dylib");
+        let otool_l = _otool_l_slice(
+            "x86_64",
+            "00112233-4455-6677-8899-AABBCCDDEEFF",
+            "/opt/homebrew/lib",
+        ) + &_otool_l_slice(
+            "arm64",
+            "FFEEDDCC-BBAA-9988-7766-554433221100",
+            "/opt/homebrew/lib",
+        );
+        let runner = FakeRunner::default()
+            .with("otool -arch all -l /tmp/libfoo.dylib", true, &otool_l, "")
+            .with(
+                "codesign --force --sign - /tmp/libfoo.dylib",
+                false,
+                "",
+                "/tmp/libfoo.dylib: invalid or unsupported format for signature",
+            );
+        let backend = ToolsBackend::new(&runner);
+        backend
+            .change(
+                file,
+                &[
+                    Edit::SetId("@rpath/libfoo.dylib".into()),
+                    Edit::DeleteRpath("/opt/homebrew/lib".into()),
+                    Edit::AddRpath("@loader_path".into()),
+                    Edit::AddRpath("/opt/homebrew/lib".into()),
+                    Edit::DeleteRpath("/usr/local/lib".into()),
+                    Edit::ChangeInstallName {
+                        old: "/opt/homebrew/lib/libbar.2.dylib".into(),
+                        new: "@rpath/libbar.2.dylib".into(),
+                    },
+                ],
+            )
+            .unwrap();
+        assert_eq!(runner.runs.borrow().len(), 2);
+        assert_eq!(
+            runner.runs.borrow()[1],
+            "install_name_tool -id @rpath/libfoo.dylib -delete_rpath /opt/homebrew/lib \
+             -add_rpath @loader_path -change /opt/homebrew/lib/libbar.2.dylib \
+             @rpath/libbar.2.dylib /tmp/libfoo.dylib"
+        );
+
+        let err = backend.sign(file).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid or unsupported format for signature")
+        );
+        let Some(Error::ToolFailure {
+            program, stderr, ..
+        }) = err.downcast_ref::<Error>()
+        else {
+            panic!("not a tool failure: {:?}", err);
+        };
+        assert_eq!(program, "codesign");
+        assert!(stderr.contains("invalid or unsupported format"));
+    }
+
+    #[test]
+    fn test_native_inspect() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        let image = NativeBackend.inspect(&resources.join("pdftoppm")).unwrap();
+        assert_eq!(image.kind, ImageKind::Executable);
+        assert_eq!(image.archs, vec!["arm64"]);
+        assert_eq!(image.id, None);
+        assert_eq!(image.uuids.len(), 1);
+        assert!(image.rpaths.contains(&"@loader_path/../lib".to_string()));
+        assert!(
+            image
+                .dependencies
+                .iter()
+                .any(|d| d.name == "@rpath/libpoppler.154.0.0.dylib")
+        );
+
+        let image = NativeBackend
+            .inspect(&resources.join("libs/libpng16.16.dylib"))
+            .unwrap();
+        assert_eq!(image.kind, ImageKind::Dylib);
+        assert_eq!(image.id.as_deref(), Some("@rpath/libpng16.16.dylib"));
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        let root = tempfile::tempdir().unwrap();
+        let lib = root.path().join("libpng16.16.dylib");
+        std::fs::copy(resources.join("libs/libpng16.16.dylib"), &lib).unwrap();
+
+        let recorder = RecordBackend::new(NativeBackend, &[("$ROOT", root.path())]);
+        let image = recorder.inspect(&lib).unwrap();
+        recorder
+            .change(&lib, &[Edit::AddRpath("@loader_path/../lib".into())])
+            .unwrap();
+        recorder.sign(&lib).unwrap();
+        let recording = recorder.recording();
+        assert!(recording.images.contains_key("$ROOT/libpng16.16.dylib"));
+
+        let saved = root.path().join("recording.json");
+        recording.save(&saved).unwrap();
+        let recording = Recording::load(&saved).unwrap();
+
+        let other = tempfile::tempdir().unwrap();
+        let replay = ReplayBackend::new(&recording, &[("$ROOT", other.path())]);
+        let replayed = replay
+            .inspect(&other.path().join("libpng16.16.dylib"))
+            .unwrap();
+        assert_eq!(replayed, image);
+        assert!(replay.inspect(&lib).is_err());
+        replay
+            .change(
+                &other.path().join("libpng16.16.dylib"),
+                &[Edit::AddRpath("@loader_path/../lib".into())],
+            )
+            .unwrap();
+        replay
+            .sign(&other.path().join("libpng16.16.dylib"))
+            .unwrap();
+        assert_eq!(replay.calls(), recording.calls);
+    }
+
+    // Compares the parsers of both backends against the real tools.
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_tools_match_native() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        for file in [
+            resources.join("pdftoppm"),
+            resources.join("libs/libpoppler.154.0.0.dylib"),
+        ] {
+            let tools = ToolsBackend::new(SystemRunner).inspect(&file).unwrap();
+            let native = NativeBackend.inspect(&file).unwrap();
+            assert_eq!(tools, native);
+        }
+    }
+}
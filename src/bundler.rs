@@ -0,0 +1,1131 @@
+// Library entry point for bundling.
+//
+// The CLI is a thin wrapper over `Bundler`, build scripts and other tools can
+// use it the same way:
+//
+//     let report = Bundler::new("/opt/homebrew/bin/pdftoppm", "dist")
+//         .libs_path("../Frameworks")
+//         .create_output(true)
+//         .run()?;
+//
+// Nothing is written into the output folder directly. The bundle is built in
+// a staging folder next to it, verified there and only then moved into place
+// with renames, so a failing run leaves the previous output untouched. When
+// the libraries go outside of the output folder (e.g. `../Frameworks`) both
+// folders are staged next to each other and swapped one after the other,
+// nothing else around them is copied.
+//
+// In app mode the output is the `.app` folder itself, see `app` for the
+// layout. An existing `.app` or `.framework` can also be repaired in place:
+// every binary in it stays where it is, missing libraries are copied into
+// its libraries folder and all install names are rewritten to point inside.
+
+use crate::app::{self, AppInfo, BundleKind};
+use crate::backend::{Backend, ImageKind, NativeBackend};
+use crate::deployment;
+use crate::error::Error;
+use crate::helpers::{copy_tree, normalize_path};
+use crate::macho::{FileKind, Version, detect_file_kind};
+use crate::model::{Binary, Graph};
+use crate::plan::{GeneratedFile, Plan};
+use crate::policy::SystemPolicy;
+use crate::report::BundleReport;
+use crate::resolver::Resolver;
+use crate::sysroot::PathMapper;
+use crate::verify::verify_bundle;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct Bundler {
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    libs_path: Option<PathBuf>,
+    create_output: bool,
+    clean_output: bool,
+    validate: bool,
+    mapper: PathMapper,
+    policy: Arc<SystemPolicy>,
+    backend: Arc<dyn Backend>,
+    app: Option<AppInfo>,
+    in_place: Option<BundleKind>,
+    prune_unused: bool,
+    allow_incompatible_versions: bool,
+    deployment_target: Option<Version>,
+}
+
+impl Bundler {
+    pub fn new(input: impl Into<PathBuf>, output: impl Into<PathBuf>) -> Self {
+        Bundler {
+            inputs: vec![input.into()],
+            output: output.into(),
+            libs_path: None,
+            create_output: false,
+            clean_output: false,
+            validate: true,
+            mapper: PathMapper::default(),
+            policy: Arc::default(),
+            backend: Arc::new(NativeBackend),
+            app: None,
+            in_place: None,
+            prune_unused: false,
+            allow_incompatible_versions: false,
+            deployment_target: None,
+        }
+    }
+
+    /// Repairs an existing `.app` or `.framework` bundle, which is both the
+    /// input and the output.
+    pub fn in_place(bundle: impl Into<PathBuf>) -> Result<Self> {
+        let bundle = bundle.into();
+        let Some(kind) = BundleKind::of(&bundle) else {
+            return Err(anyhow!(
+                "Not an app or framework bundle: {}",
+                bundle.display()
+            ));
+        };
+        let mut bundler = Bundler::new(&bundle, &bundle);
+        bundler.in_place = Some(kind);
+        Ok(bundler)
+    }
+
+    /// Bundles another binary next to the first one, sharing the libraries
+    /// folder. A folder input adds every executable and dynamic library found
+    /// in it, keeping their relative paths.
+    pub fn input(mut self, input: impl Into<PathBuf>) -> Self {
+        self.inputs.push(input.into());
+        self
+    }
+
+    /// Folder for the libraries, relative to the output folder. Defaults to
+    /// `libs`.
+    pub fn libs_path(mut self, libs_path: impl Into<PathBuf>) -> Self {
+        self.libs_path = Some(libs_path.into());
+        self
+    }
+
+    /// Create the output folder if it doesn't exist instead of failing.
+    pub fn create_output(mut self, create_output: bool) -> Self {
+        self.create_output = create_output;
+        self
+    }
+
+    /// Remove whatever an earlier run left in the output and libraries
+    /// folders. By default the bundle is merged into them, files which are
+    /// bundled again are replaced and everything else is kept.
+    pub fn clean_output(mut self, clean_output: bool) -> Self {
+        self.clean_output = clean_output;
+        self
+    }
+
+    /// Verify the staged bundle before publishing it, see `verify_bundle`.
+    /// Enabled by default.
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// How absolute paths found in binaries are looked up, see `--sysroot`
+    /// and `--prefix-map`.
+    pub fn path_mapper(mut self, mapper: PathMapper) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    /// Which libraries are left to the system, see `SystemPolicy`.
+    pub fn system_policy(mut self, policy: SystemPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// How binaries are read, changed and signed. Defaults to the native
+    /// implementation which doesn't need any Apple tools.
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Bundle into a macOS application, the output being the `.app` folder.
+    /// Executables go to `Contents/MacOS`, libraries to `Contents/Frameworks`
+    /// unless `libs_path` says otherwise and `Info.plist` is generated from
+    /// `info`. The `.app` folder is created when its parent exists.
+    pub fn app(mut self, info: AppInfo) -> Self {
+        self.app = Some(info);
+        self
+    }
+
+    /// Drop load commands no imported symbol is bound to and leave out the
+    /// libraries nothing else loads. By default they are only reported.
+    pub fn prune_unused(mut self, prune_unused: bool) -> Self {
+        self.prune_unused = prune_unused;
+        self
+    }
+
+    /// Only warn about bundled libraries older than the compatibility
+    /// version their users require. By default discovery fails.
+    pub fn allow_incompatible_versions(mut self, allow: bool) -> Self {
+        self.allow_incompatible_versions = allow;
+        self
+    }
+
+    /// Oldest macOS the bundle has to launch on, libraries built for a
+    /// newer one are reported. Defaults to the target of the inputs.
+    pub fn deployment_target(mut self, version: Version) -> Self {
+        self.deployment_target = Some(version);
+        self
+    }
+
+    pub fn run(&self) -> Result<BundleReport> {
+        self.apply(&self.plan()?)
+    }
+
+    /// Discovers the dependencies and returns what `run` would do, nothing is
+    /// written.
+    pub fn plan(&self) -> Result<Plan> {
+        if let Some(kind) = self.in_place {
+            return self.plan_in_place(kind);
+        }
+        let backend = self.backend.as_ref();
+        let mut graph = Graph::default();
+        let mut executables = vec![];
+        for input in &self.inputs {
+            for (file_path, folder) in find_inputs(input, backend)? {
+                let resolver = self.resolver(&file_path);
+                let binary = Binary::open(file_path, backend)?;
+                let folder = match self.app {
+                    Some(_) if binary.is_executable() => {
+                        Path::new(app::EXECUTABLES_PATH).join(folder)
+                    }
+                    Some(_) => Path::new(app::FRAMEWORKS_PATH).join(folder),
+                    None => folder,
+                };
+                let is_executable = binary.is_executable();
+                let id = graph.add(binary, &folder, &resolver, backend)?;
+                if is_executable {
+                    executables.push(graph.nodes()[id].file_path().to_path_buf());
+                }
+            }
+        }
+        log::trace!("Dependency graph:\n {:#?}", graph);
+        self.check_unused(&mut graph);
+        self.check_versions(&graph)?;
+        self.check_deployment(&graph)?;
+
+        let Some(ref info) = self.app else {
+            return graph.plan(&self.output, self.libs_path.as_deref());
+        };
+        let libs_path = self
+            .libs_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(app::FRAMEWORKS_PATH));
+        let mut plan = graph.plan(&self.output, Some(&libs_path))?;
+        self.add_app_files(&mut plan, info, &executables)?;
+        Ok(plan)
+    }
+
+    fn check_unused(&self, graph: &mut Graph) {
+        if self.prune_unused {
+            graph.prune_unused();
+            return;
+        }
+        for edge in graph.unused_dependencies() {
+            log::warn!(
+                "No symbols are imported from: {}\nby: {}",
+                edge.install_name,
+                graph.node(edge.from).file_path().display()
+            );
+        }
+    }
+
+    // dyld refuses to load a library older than the compatibility version
+    // of the load command, pruned load commands are not checked.
+    fn check_versions(&self, graph: &Graph) -> Result<()> {
+        let incompatible = graph.incompatible_versions();
+        for edge in &incompatible {
+            let library = graph.node(edge.to);
+            let message = format!(
+                "Library is older than required: {}\ncompatibility version: {} (current version {}), required: {}\nReferenced from: {}",
+                library.file_path().display(),
+                library.compatibility_version().unwrap_or_default(),
+                library.current_version().unwrap_or_default(),
+                edge.compatibility_version,
+                graph.node(edge.from).file_path().display()
+            );
+            if self.allow_incompatible_versions {
+                log::warn!("{}", message);
+            } else {
+                log::error!("{}", message);
+            }
+        }
+        if !incompatible.is_empty() && !self.allow_incompatible_versions {
+            return Err(Error::IncompatibleVersions {
+                problems: incompatible.len(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    // Only reported, a library built for a newer macOS or another platform
+    // can't be fixed by bundling.
+    fn check_deployment(&self, graph: &Graph) -> Result<()> {
+        let report = deployment::check(graph, self.deployment_target)?;
+        if let Some(minimum) = report.minimum {
+            log::info!("Minimum macOS version of the bundle: {}", minimum);
+        }
+        if let Some(target) = report.target {
+            for slice in &report.newer {
+                log::warn!(
+                    "Built for a newer macOS than the deployment target {}: {}",
+                    target,
+                    slice
+                );
+            }
+        }
+        for slice in &report.mismatched {
+            log::warn!("Not built for macOS: {}", slice);
+        }
+        Ok(())
+    }
+
+    fn resolver(&self, executable: &Path) -> Resolver {
+        Resolver::new(executable)
+            .with_mapper(self.mapper.clone())
+            .with_policy(self.policy.clone())
+    }
+
+    // Executables go first, libraries relying on their rpaths are reached
+    // through them. `@executable_path` of everything else is the main
+    // executable of an app.
+    fn plan_in_place(&self, kind: BundleKind) -> Result<Plan> {
+        let backend = self.backend.as_ref();
+        let bundle = &self.output;
+        let mut binaries = vec![];
+        for folder in kind.binary_folders(bundle) {
+            find_binaries(bundle, &bundle.join(folder), backend, &mut binaries)?;
+        }
+        if binaries.is_empty() {
+            return Err(anyhow!("No binary found in: {}", bundle.display()));
+        }
+        let mut opened = vec![];
+        for (file_path, folder) in binaries {
+            opened.push((Binary::open(file_path, backend)?, folder));
+        }
+        opened.sort_by_key(|(binary, _)| !binary.is_executable());
+        let main = opened
+            .iter()
+            .find(|(binary, folder)| {
+                binary.is_executable() && folder == Path::new(app::EXECUTABLES_PATH)
+            })
+            .map(|(binary, _)| binary.file_path().to_path_buf());
+
+        let mut graph = Graph::default();
+        for (binary, folder) in opened {
+            let executable = match main {
+                Some(ref main) if !binary.is_executable() => main.clone(),
+                _ => binary.file_path().to_path_buf(),
+            };
+            let resolver = self.resolver(&executable);
+            graph.add_in_place(binary, &folder, &resolver, backend)?;
+        }
+        log::trace!("Dependency graph:\n {:#?}", graph);
+        self.check_unused(&mut graph);
+        self.check_versions(&graph)?;
+        self.check_deployment(&graph)?;
+
+        let libs_path = match self.libs_path {
+            Some(ref libs_path) => libs_path.clone(),
+            None => kind.libs_path(bundle)?,
+        };
+        graph.plan(bundle, Some(&libs_path))
+    }
+
+    // Info.plist names the main executable by its path inside
+    // `Contents/MacOS`.
+    fn add_app_files(
+        &self,
+        plan: &mut Plan,
+        info: &AppInfo,
+        executables: &[PathBuf],
+    ) -> Result<()> {
+        let mut main = None;
+        for source in executables {
+            let Some(file) = plan.files.iter().find(|file| file.source == *source) else {
+                continue;
+            };
+            let Ok(name) = file.destination.strip_prefix(app::EXECUTABLES_PATH) else {
+                continue;
+            };
+            let name = name.to_string_lossy().into_owned();
+            let is_main = match info.executable {
+                Some(ref executable) => *executable == name,
+                None => true,
+            };
+            if is_main {
+                main = Some((source, name));
+                break;
+            }
+        }
+        let Some((source, name)) = main else {
+            return Err(anyhow!(
+                "No main executable found for the app: {}\nGive an executable as input or check `--executable`.",
+                info.executable.as_deref().unwrap_or_default()
+            ));
+        };
+
+        plan.folders.push(PathBuf::from(app::RESOURCES_PATH));
+        plan.generated.push(GeneratedFile {
+            destination: PathBuf::from(app::INFO_PLIST_PATH),
+            contents: info.info_plist(&self.output, source, &name)?,
+        });
+        Ok(())
+    }
+
+    /// Executes a plan, possibly edited by hand, into the output folder of
+    /// the bundler. The files are staged, verified and published like `run`
+    /// does.
+    pub fn apply(&self, plan: &Plan) -> Result<BundleReport> {
+        if self.output.is_file() {
+            return Err(anyhow!(
+                "Output path is a file!\nOutput path must be a folder."
+            ));
+        }
+
+        // An app folder is created next to the other apps.
+        let is_creatable = self.create_output
+            || (self.app.is_some()
+                && std::path::absolute(&self.output)
+                    .ok()
+                    .and_then(|output| output.parent().map(Path::is_dir))
+                    .unwrap_or(false));
+        if self.in_place.is_some() && self.clean_output {
+            return Err(anyhow!(
+                "A bundle repaired in place can't be cleaned: {}",
+                self.output.display()
+            ));
+        }
+
+        if !self.output.exists() && !is_creatable {
+            return Err(anyhow!(
+                "Destination path not exist: {}\nPlease make sure it exists or consider using <-c | --create-bundle-path> flag to create folder!",
+                self.output.display()
+            ));
+        }
+
+        let output = normalize_path(
+            &std::path::absolute(&self.output).map_err(Error::io("resolving", &self.output))?,
+        );
+        // Every folder files are copied to, the libraries may be outside of
+        // the output folder.
+        let mut folders = vec![output.clone()];
+        for file in &plan.files {
+            let destination = normalize_path(&output.join(&file.destination));
+            if let Some(folder) = destination.parent()
+                && !folders.iter().any(|f| f == folder)
+            {
+                folders.push(folder.to_path_buf());
+            }
+        }
+        // Folders published as a whole: the output folder and the libraries
+        // folder when it is outside of it. A framework counts as part of the
+        // folder it is in.
+        let frameworks: Vec<PathBuf> = plan
+            .frameworks
+            .iter()
+            .map(|framework| normalize_path(&output.join(&framework.destination)))
+            .collect();
+        let mut roots = vec![output.clone()];
+        for file in &plan.files {
+            let destination = normalize_path(&output.join(&file.destination));
+            let folder = match frameworks.iter().find(|f| destination.starts_with(f)) {
+                Some(framework) => framework.parent(),
+                None => destination.parent(),
+            };
+            if let Some(folder) = folder
+                && !roots.iter().any(|root| folder.starts_with(root))
+            {
+                roots.retain(|root| !root.starts_with(folder));
+                roots.push(folder.to_path_buf());
+            }
+        }
+        // The staging folder keeps the roots at the same relative location so
+        // that rpaths between them can be verified, but holds nothing else.
+        let base = match roots.as_slice() {
+            [root] => root.parent().map(Path::to_path_buf),
+            _ => Some(
+                roots
+                    .iter()
+                    .fold(output.clone(), |base, root| common_ancestor(&base, root)),
+            ),
+        };
+        let (Some(base), Some(name)) = (base, output.file_name()) else {
+            return Err(anyhow!(
+                "Bundle can't be placed at the root of the file system: {}",
+                output.display()
+            ));
+        };
+        std::fs::create_dir_all(&base).map_err(Error::io("creating folder", &base))?;
+
+        let staging = tempfile::Builder::new()
+            .prefix(&format!(".{}.staging-", name.to_string_lossy()))
+            .tempdir_in(&base)
+            .map_err(Error::io("creating folder", &base))?;
+        log::debug!("Staging bundle in: {}", staging.path().display());
+        for root in &roots {
+            let staged = staging.path().join(root.strip_prefix(&base)?);
+            std::fs::create_dir_all(&staged).map_err(Error::io("creating folder", &staged))?;
+            if root.exists() && !(self.clean_output && folders.contains(root)) {
+                let skip = if self.clean_output { &folders[..] } else { &[] };
+                copy_tree(root, &staged, skip)?;
+            }
+        }
+        let staged_output = staging.path().join(output.strip_prefix(&base)?);
+
+        let mut report = plan.apply(&staged_output, self.backend.as_ref())?;
+
+        if self.validate {
+            let issues = verify_bundle(staging.path(), self.policy.clone())?;
+            if !issues.is_empty() {
+                for issue in &issues {
+                    log::error!("{}", issue);
+                }
+                return Err(Error::VerificationFailed {
+                    path: self.output.clone(),
+                    problems: issues.len(),
+                }
+                .into());
+            }
+        }
+
+        // Each root is swapped on its own, a failure after the first one
+        // leaves the libraries folder and the output of different runs.
+        for root in &roots {
+            publish(&staging.path().join(root.strip_prefix(&base)?), root)?;
+        }
+        report.rebase(staging.path(), &base);
+        Ok(report)
+    }
+}
+
+// Binaries to bundle for one input with their folder relative to the output.
+// Files of a folder input which are not executables or dynamic libraries,
+// e.g. resources, are skipped.
+fn find_inputs(input: &Path, backend: &dyn Backend) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if !input.is_dir() {
+        return Ok(vec![(input.to_path_buf(), PathBuf::new())]);
+    }
+    let mut files = vec![];
+    find_binaries(input, input, backend, &mut files)?;
+    if files.is_empty() {
+        return Err(anyhow!(
+            "No executable or dynamic library found in: {}",
+            input.display()
+        ));
+    }
+    Ok(files)
+}
+
+fn find_binaries(
+    root: &Path,
+    folder: &Path,
+    backend: &dyn Backend,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    let mut entries = std::fs::read_dir(folder)
+        .map_err(Error::io("reading folder", folder))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(Error::io("reading folder", folder))?;
+    entries.sort();
+    for path in entries {
+        if path.is_symlink() {
+            continue;
+        }
+        if path.is_dir() {
+            find_binaries(root, &path, backend, files)?;
+            continue;
+        }
+        if !matches!(detect_file_kind(&path)?, FileKind::MachO | FileKind::Fat) {
+            continue;
+        }
+        match backend.inspect(&path).map(|image| image.kind) {
+            Ok(ImageKind::Executable | ImageKind::Dylib | ImageKind::Bundle) => {
+                let relative = folder.strip_prefix(root)?.to_path_buf();
+                files.push((path, relative));
+            }
+            _ => log::debug!("Skipping input: {}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// The deepest folder containing both `a` and `b`.
+fn common_ancestor(a: &Path, b: &Path) -> PathBuf {
+    a.components()
+        .zip(b.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect()
+}
+
+// Moves a staged folder to `root`. An existing `root` is first renamed to a
+// backup which is put back if the second rename fails and removed otherwise.
+fn publish(staged: &Path, root: &Path) -> Result<()> {
+    if !root.exists() {
+        std::fs::rename(staged, root).map_err(Error::io("publishing", root))?;
+        return Ok(());
+    }
+    let permissions = root
+        .metadata()
+        .map_err(Error::io("reading", root))?
+        .permissions();
+    std::fs::set_permissions(staged, permissions)
+        .map_err(Error::io("setting permissions", staged))?;
+
+    let parent = root.parent().unwrap_or(Path::new("/"));
+    let name = root.file_name().unwrap_or_default().to_string_lossy();
+    let backup = tempfile::Builder::new()
+        .prefix(&format!(".{}.previous-", name))
+        .tempdir_in(parent)
+        .map_err(Error::io("creating folder", parent))?;
+    std::fs::rename(root, backup.path()).map_err(Error::io("moving", root))?;
+    if let Err(e) = std::fs::rename(staged, root) {
+        let _ = std::fs::rename(backup.path(), root);
+        return Err(Error::io("publishing", root)(e).into());
+    }
+    log::debug!("Published bundle: {}", root.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::get_dependencies;
+
+    fn _pdftoppm() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm")
+    }
+
+    #[test]
+    fn test_report() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("MacOS");
+        let report = Bundler::new(_pdftoppm(), &output)
+            .libs_path("../Frameworks")
+            .create_output(true)
+            .run()
+            .unwrap();
+
+        let exe = output.join("pdftoppm");
+        let libpoppler = output.join("../Frameworks/libpoppler.154.0.0.dylib");
+        assert!(report.copied.iter().any(|c| c.destination == exe));
+        assert_eq!(report.copied.len(), report.signed.len());
+        assert!(report.rewritten_in(&libpoppler).any(|c| {
+            c.command == "LC_ID_DYLIB"
+                && c.new.as_deref() == Some("@rpath/libpoppler.154.0.0.dylib")
+        }));
+        assert!(report.rewritten_in(&exe).any(|c| {
+            c.command == "LC_RPATH" && c.new.as_deref() == Some("@loader_path/../Frameworks")
+        }));
+        assert!(
+            report.rewritten_in(&exe).any(|c| {
+                c.command == "LC_RPATH" && c.old.as_deref() == Some("@loader_path/libs")
+            })
+        );
+        let deps = get_dependencies(&exe).unwrap();
+        assert!(
+            deps.iter()
+                .any(|d| d.name == "@rpath/libpoppler.154.0.0.dylib")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_libs_outside_output() {
+        use std::os::unix::fs::MetadataExt;
+        let root = tempfile::tempdir().unwrap();
+        let project = root.path().join("foo");
+        std::fs::create_dir_all(project.join(".git")).unwrap();
+        std::fs::write(project.join(".git/HEAD"), "ref").unwrap();
+        std::fs::hard_link(project.join(".git/HEAD"), project.join("HEAD")).unwrap();
+        // A stray binary next to the bundle would fail verification.
+        std::fs::copy(_pdftoppm(), project.join("pdftoppm")).unwrap();
+
+        let output = project.join("bar");
+        Bundler::new(_pdftoppm(), &output)
+            .libs_path("../Frameworks")
+            .create_output(true)
+            .run()
+            .unwrap();
+        assert!(output.join("pdftoppm").is_file());
+        assert_eq!(_entries(&project.join("Frameworks")).len(), 22);
+        assert_eq!(
+            _entries(&project),
+            [".git", "Frameworks", "HEAD", "bar", "pdftoppm"]
+        );
+        assert_eq!(project.join("HEAD").metadata().unwrap().nlink(), 2);
+
+        // A second run swaps both folders again.
+        Bundler::new(_pdftoppm(), &output)
+            .libs_path("../Frameworks")
+            .clean_output(true)
+            .run()
+            .unwrap();
+        assert_eq!(
+            _entries(&project),
+            [".git", "Frameworks", "HEAD", "bar", "pdftoppm"]
+        );
+    }
+
+    #[test]
+    fn test_incompatible_versions() {
+        use crate::error::exit_code;
+        use crate::helpers::add_rpath;
+        use crate::macho::Version;
+        use crate::macho::tests::_set_compatibility_version;
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("pdftoppm");
+        std::fs::copy(_pdftoppm(), &exe).unwrap();
+        add_rpath(&exe, &_pdftoppm().with_file_name("libs")).unwrap();
+        _set_compatibility_version(&exe, "@rpath/liblcms2.2.dylib", Version::new(99, 0, 0));
+
+        let output = root.path().join("dist");
+        let bundler = Bundler::new(&exe, &output).create_output(true);
+        let error = bundler.plan().unwrap_err();
+        assert_eq!(exit_code(&error), 17);
+        assert!(!output.exists());
+
+        let plan = bundler.allow_incompatible_versions(true).plan().unwrap();
+        assert!(
+            plan.files
+                .iter()
+                .any(|file| file.destination == Path::new("libs/liblcms2.2.dylib"))
+        );
+    }
+
+    #[test]
+    fn test_prune_unused() {
+        // libpoppler links NSS and gpgme libraries without importing
+        // anything from them, the bundle still passes verification.
+        let output = tempfile::tempdir().unwrap();
+        let report = Bundler::new(_pdftoppm(), output.path())
+            .prune_unused(true)
+            .run()
+            .unwrap();
+
+        assert!(!report.pruned.is_empty());
+        assert!(report.pruned_size() > 0);
+        for library in &report.pruned {
+            let name = library.source.file_name().unwrap();
+            assert!(!output.path().join("libs").join(name).exists());
+        }
+        let libpoppler = output.path().join("libs/libpoppler.154.0.0.dylib");
+        let deps = get_dependencies(&libpoppler).unwrap();
+        assert!(deps.iter().all(|d| d.name != "@rpath/libssl3.dylib"));
+        assert!(
+            report.rewritten_in(&libpoppler).any(|c| {
+                c.old.as_deref() == Some("@rpath/libgpgme.45.dylib") && c.new.is_none()
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_output() {
+        let output = tempfile::tempdir().unwrap();
+        let missing = output.path().join("bundle");
+        assert!(Bundler::new(_pdftoppm(), &missing).run().is_err());
+        Bundler::new(_pdftoppm(), &missing)
+            .create_output(true)
+            .run()
+            .unwrap();
+        assert!(missing.join("libs/libpoppler.154.0.0.dylib").is_file());
+    }
+
+    // Inspects and changes files natively but fails to sign them.
+    #[derive(Debug)]
+    struct FailingSign;
+
+    impl Backend for FailingSign {
+        fn inspect(&self, file_path: &Path) -> Result<crate::backend::Image> {
+            NativeBackend.inspect(file_path)
+        }
+
+        fn change(&self, file_path: &Path, edits: &[crate::rewrite::Edit]) -> Result<()> {
+            NativeBackend.change(file_path, edits)
+        }
+
+        fn sign(&self, file_path: &Path) -> Result<()> {
+            Err(anyhow!("Signing failed: {}", file_path.display()))
+        }
+    }
+
+    fn _entries(folder: &Path) -> Vec<String> {
+        let mut entries: Vec<String> = std::fs::read_dir(folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn test_merge_and_clean() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("bundle");
+        std::fs::create_dir_all(output.join("libs")).unwrap();
+        std::fs::write(output.join("README"), "keep").unwrap();
+        std::fs::write(output.join("libs/libstale.dylib"), "stale").unwrap();
+        std::fs::write(output.join("libs/libpoppler.154.0.0.dylib"), "stale").unwrap();
+
+        Bundler::new(_pdftoppm(), &output).run().unwrap();
+        assert!(output.join("README").is_file());
+        assert!(output.join("libs/libstale.dylib").is_file());
+        assert!(get_dependencies(&output.join("libs/libpoppler.154.0.0.dylib")).is_ok());
+
+        Bundler::new(_pdftoppm(), &output)
+            .clean_output(true)
+            .run()
+            .unwrap();
+        assert!(!output.join("README").exists());
+        assert!(!output.join("libs/libstale.dylib").exists());
+        assert!(output.join("pdftoppm").is_file());
+        assert_eq!(_entries(root.path()), ["bundle"]);
+    }
+
+    #[test]
+    fn test_failure_keeps_previous_output() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("bundle");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("README"), "keep").unwrap();
+
+        let err = Bundler::new(_pdftoppm(), &output)
+            .clean_output(true)
+            .backend(FailingSign)
+            .run()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Signing failed"), "{:#}", err);
+        assert_eq!(_entries(&output), ["README"]);
+        assert_eq!(_entries(root.path()), ["bundle"]);
+    }
+
+    #[test]
+    fn test_validate_with_policy() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("bundle");
+        let policy = SystemPolicy::default()
+            .allow(&["@rpath/liblcms2.2.dylib".to_string()])
+            .unwrap();
+        Bundler::new(_pdftoppm(), &output)
+            .create_output(true)
+            .system_policy(policy.clone())
+            .validate(true)
+            .run()
+            .unwrap();
+        assert!(!output.join("libs/liblcms2.2.dylib").exists());
+
+        assert!(verify_bundle(&output, Arc::new(policy)).unwrap().is_empty());
+        let issues = verify_bundle(&output, Arc::default()).unwrap();
+        assert!(
+            issues
+                .iter()
+                .any(|i| i.message
+                    == "LC_LOAD_DYLIB not found in the bundle: @rpath/liblcms2.2.dylib")
+        );
+    }
+
+    #[test]
+    fn test_apply_plan_with_policy() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("bundle");
+        let policy = SystemPolicy::default()
+            .allow(&["@rpath/liblcms2.2.dylib".to_string()])
+            .unwrap();
+        let plan = Bundler::new(_pdftoppm(), &output)
+            .system_policy(policy.clone())
+            .plan()
+            .unwrap();
+        let saved = root.path().join("plan.json");
+        plan.save(&saved).unwrap();
+        let plan = Plan::load(&saved).unwrap();
+
+        let err = Bundler::new(_pdftoppm(), &output)
+            .create_output(true)
+            .apply(&plan)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::VerificationFailed { .. })
+        ));
+        Bundler::new(_pdftoppm(), &output)
+            .create_output(true)
+            .system_policy(policy)
+            .apply(&plan)
+            .unwrap();
+        assert!(!output.join("libs/liblcms2.2.dylib").exists());
+    }
+
+    #[test]
+    fn test_apply_edited_plan() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("bundle");
+        let bundler = Bundler::new(_pdftoppm(), &output).create_output(true);
+        let mut plan = bundler.plan().unwrap();
+        assert!(!output.exists());
+
+        let exe = plan
+            .files
+            .iter_mut()
+            .find(|file| file.destination == Path::new("pdftoppm"))
+            .unwrap();
+        exe.edits.push(crate::rewrite::Edit::AddRpath(
+            "@loader_path/share".to_string(),
+        ));
+        let report = bundler.apply(&plan).unwrap();
+
+        let exe = output.join("pdftoppm");
+        assert!(report.rewritten_in(&exe).any(|c| {
+            c.command == "LC_RPATH" && c.new.as_deref() == Some("@loader_path/share")
+        }));
+        assert!(
+            crate::helpers::get_rpaths(&exe, true)
+                .unwrap()
+                .contains(&"@loader_path/share".to_string())
+        );
+    }
+
+    #[test]
+    fn test_app_layout() {
+        let root = tempfile::tempdir().unwrap();
+        let output = root.path().join("Poppler.app");
+        let info = AppInfo {
+            identifier: "com.example.poppler".to_string(),
+            version: "2.0".to_string(),
+            ..Default::default()
+        };
+        let report = Bundler::new(_pdftoppm(), &output).app(info).run().unwrap();
+
+        let contents = output.join("Contents");
+        assert_eq!(
+            _entries(&contents),
+            ["Frameworks", "Info.plist", "MacOS", "Resources"]
+        );
+        assert_eq!(_entries(&contents.join("MacOS")), ["pdftoppm"]);
+        assert_eq!(_entries(&contents.join("Frameworks")).len(), 22);
+        assert_eq!(report.generated, [contents.join("Info.plist")]);
+
+        let exe = contents.join("MacOS/pdftoppm");
+        assert_eq!(
+            crate::helpers::get_rpaths(&exe, true).unwrap(),
+            ["@loader_path/../Frameworks"]
+        );
+        let plist = std::fs::read_to_string(contents.join("Info.plist")).unwrap();
+        assert!(plist.contains("<string>com.example.poppler</string>"));
+        assert!(plist.contains("<key>CFBundleExecutable</key>\n\t<string>pdftoppm</string>"));
+
+        let missing = AppInfo {
+            identifier: "com.example.poppler".to_string(),
+            executable: Some("pdftotext".to_string()),
+            ..Default::default()
+        };
+        let error = Bundler::new(_pdftoppm(), root.path().join("Other.app"))
+            .app(missing)
+            .plan()
+            .unwrap_err();
+        assert!(error.to_string().contains("No main executable found"));
+    }
+
+    // Same executable with another UUID, so that it isn't merged with the
+    // original.
+    fn _copy_with_new_uuid(from: &Path, to: &Path, tweak: u8) {
+        use crate::macho::{LC_UUID, MachO};
+        let mut data = std::fs::read(from).unwrap();
+        let offset = MachO::parse(&data)
+            .unwrap()
+            .commands
+            .iter()
+            .find(|lc| lc.cmd == LC_UUID)
+            .unwrap()
+            .offset;
+        data[offset + 8] ^= tweak;
+        std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+        std::fs::write(to, data).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_multiple_inputs() {
+        let root = tempfile::tempdir().unwrap();
+        let input = root.path().join("input");
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        std::fs::create_dir_all(input.join("plugins")).unwrap();
+        std::fs::copy(_pdftoppm(), input.join("pdftoppm")).unwrap();
+        std::fs::write(input.join("README"), "not a binary").unwrap();
+        // Linked by libfreetype as well, so it ends up with the libraries.
+        std::fs::copy(
+            resources.join("libs/libpng16.16.dylib"),
+            input.join("plugins/libpng16.16.dylib"),
+        )
+        .unwrap();
+        let tool = root.path().join("tool");
+        _copy_with_new_uuid(&_pdftoppm(), &input.join("tools/pdftool"), 1);
+        _copy_with_new_uuid(&_pdftoppm(), &tool, 2);
+        // pdftoppm finds its libraries through `@loader_path/libs`.
+        for folder in [&input, &input.join("tools"), &root.path().to_path_buf()] {
+            std::os::unix::fs::symlink(resources.join("libs"), folder.join("libs")).unwrap();
+        }
+
+        let output = root.path().join("bundle");
+        let report = Bundler::new(&input, &output)
+            .input(&tool)
+            .create_output(true)
+            .run()
+            .unwrap();
+
+        assert_eq!(_entries(&output), ["libs", "pdftoppm", "tool", "tools"]);
+        assert_eq!(report.copied.len(), 22 + 3);
+        let rpaths = |file: &str| crate::helpers::get_rpaths(&output.join(file), true).unwrap();
+        assert_eq!(rpaths("pdftoppm"), ["@loader_path/libs"]);
+        assert_eq!(rpaths("tool"), ["@loader_path/libs"]);
+        assert_eq!(rpaths("tools/pdftool"), ["@loader_path/../libs"]);
+    }
+
+    #[test]
+    fn test_repair_app_in_place() {
+        use crate::helpers::{add_rpath, get_id, get_rpaths, remove_rpath};
+        let root = tempfile::tempdir().unwrap();
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        // Everything but libpng leaked to a Homebrew like folder outside of
+        // the app.
+        let homebrew = root.path().join("opt/homebrew/lib");
+        std::fs::create_dir_all(&homebrew).unwrap();
+        for entry in std::fs::read_dir(resources.join("libs")).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name() != "libpng16.16.dylib" {
+                std::fs::copy(entry.path(), homebrew.join(entry.file_name())).unwrap();
+            }
+        }
+        let app = root.path().join("Foo.app");
+        let contents = app.join("Contents");
+        for folder in ["MacOS", "Frameworks", "Resources"] {
+            std::fs::create_dir_all(contents.join(folder)).unwrap();
+        }
+        let exe = contents.join("MacOS/pdftoppm");
+        std::fs::copy(_pdftoppm(), &exe).unwrap();
+        remove_rpath(&exe, "@loader_path/../lib").unwrap();
+        remove_rpath(&exe, "@loader_path/libs").unwrap();
+        add_rpath(&exe, &homebrew).unwrap();
+        add_rpath(&exe, Path::new("@executable_path/../Frameworks")).unwrap();
+        _copy_with_new_uuid(&exe, &contents.join("Helpers/pdfhelper"), 1);
+        std::fs::copy(
+            resources.join("libs/libpng16.16.dylib"),
+            contents.join("Frameworks/libpng16.16.dylib"),
+        )
+        .unwrap();
+        std::fs::write(contents.join("Resources/readme.txt"), "kept").unwrap();
+
+        let report = Bundler::in_place(&app).unwrap().run().unwrap();
+
+        assert_eq!(report.copied.len(), 22 + 2);
+        assert_eq!(_entries(&contents.join("Frameworks")).len(), 22);
+        assert_eq!(
+            std::fs::read_to_string(contents.join("Resources/readme.txt")).unwrap(),
+            "kept"
+        );
+        // The rpath into the app stays, the one to Homebrew goes.
+        for file in [&exe, &contents.join("Helpers/pdfhelper")] {
+            assert_eq!(
+                get_rpaths(file, true).unwrap(),
+                [
+                    "@executable_path/../Frameworks",
+                    "@loader_path/../Frameworks"
+                ]
+            );
+        }
+        assert_eq!(
+            get_id(&contents.join("Frameworks/libpng16.16.dylib")).unwrap(),
+            "@rpath/libpng16.16.dylib"
+        );
+        assert!(verify_bundle(&app, Arc::default()).unwrap().is_empty());
+
+        assert!(Bundler::in_place(root.path()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_framework_dependency() {
+        use crate::helpers::{fix_install_name, get_dependencies, get_id};
+        use std::os::unix::fs::symlink;
+        let root = tempfile::tempdir().unwrap();
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        // bin/pdftoppm finds its libraries through `@loader_path/../lib`,
+        // liblcms2 is linked from a framework by absolute path instead.
+        std::fs::create_dir_all(root.path().join("bin")).unwrap();
+        let exe = root.path().join("bin/pdftoppm");
+        std::fs::copy(_pdftoppm(), &exe).unwrap();
+        symlink(resources.join("libs"), root.path().join("lib")).unwrap();
+        let framework = root.path().join("Frameworks/Lcms2.framework");
+        let version = framework.join("Versions/A");
+        std::fs::create_dir_all(version.join("Resources")).unwrap();
+        std::fs::create_dir_all(version.join("Headers")).unwrap();
+        std::fs::copy(
+            resources.join("libs/liblcms2.2.dylib"),
+            version.join("Lcms2"),
+        )
+        .unwrap();
+        std::fs::write(version.join("Headers/lcms2.h"), "// header").unwrap();
+        std::fs::write(
+            version.join("Resources/Info.plist"),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<plist version=\"1.0\"><dict>\
+             <key>CFBundleExecutable</key><string>Lcms2</string>\
+             <key>CFBundleIdentifier</key><string>com.example.lcms2</string>\
+             </dict></plist>\n",
+        )
+        .unwrap();
+        symlink("A", framework.join("Versions/Current")).unwrap();
+        for name in ["Lcms2", "Resources", "Headers"] {
+            symlink(
+                Path::new("Versions/Current").join(name),
+                framework.join(name),
+            )
+            .unwrap();
+        }
+        let linked = version.join("Lcms2");
+        fix_install_name(&exe, "@rpath/liblcms2.2.dylib", &linked.to_string_lossy()).unwrap();
+
+        let output = root.path().join("bundle");
+        let report = Bundler::new(&exe, &output)
+            .create_output(true)
+            .run()
+            .unwrap();
+
+        let copied = output.join("libs/Lcms2.framework");
+        assert_eq!(
+            std::fs::read_link(copied.join("Versions/Current")).unwrap(),
+            Path::new("A")
+        );
+        assert!(copied.join("Headers/lcms2.h").is_file());
+        let binary = copied.join("Versions/A/Lcms2");
+        assert_eq!(
+            get_id(&binary).unwrap(),
+            "@rpath/Lcms2.framework/Versions/A/Lcms2"
+        );
+        assert!(
+            get_dependencies(&output.join("pdftoppm"))
+                .unwrap()
+                .iter()
+                .any(|dylib| dylib.name == "@rpath/Lcms2.framework/Versions/A/Lcms2")
+        );
+        assert!(!output.join("libs/liblcms2.2.dylib").exists());
+
+        // Signed as a bundle, not as a bare binary.
+        assert!(report.signed.contains(&copied.join("Versions/A")));
+        assert!(!report.signed.contains(&binary));
+        let seal = std::fs::read_to_string(copied.join("Versions/A/_CodeSignature/CodeResources"))
+            .unwrap();
+        assert!(seal.contains("<key>Headers/lcms2.h</key>"));
+        assert!(seal.contains("<key>Resources/Info.plist</key>"));
+        crate::codesign::verify_slice(&std::fs::read(&binary).unwrap()).unwrap();
+    }
+}
@@ -8,14 +8,14 @@ pub fn init_cli() -> clap::ArgMatches {
             .short('i')
             .long("input")
             .required(true)
-            .help("Path of the binary\ndesired to be bundled."),
+            .action(clap::ArgAction::Append)
+            .help("Path of the binary\ndesired to be bundled.\nCan be given multiple times or be a folder of binaries,\nall of them share the libraries folder.\nAn `.app` or `.framework` without `-o` is repaired in place."),
     );
     let parser = parser.arg(
         clap::Arg::new("OUTPUT_PATH")
             .short('o')
             .long("output")
-            .required(true)
-            .help("Path of the destination folder for bundle"),
+            .help("Path of the destination folder for bundle.\n<Required unless an `.app` or `.framework` is repaired in place>"),
     );
 
     let parser = parser.arg(
@@ -28,17 +28,202 @@ pub fn init_cli() -> clap::ArgMatches {
         clap::Arg::new("LOG_LEVEL")
             .short('l')
             .long("log")
+            .global(true)
             .default_value("INFO")
             .help("[TRACE, INFO, DEBUG, WARNING, ERROR]"),
     );
 
+    let parser = parser.args(output_args());
+
+    let parser = parser.arg(
+        clap::Arg::new("PLAN")
+            .long("plan")
+            .value_name("FILE")
+            .help("Only write the plan of the bundle to FILE, `-` for stdout.\nTOML if FILE ends with `.toml`, JSON otherwise."),
+    );
+
+    let parser = parser.args(app_args());
+
     let parser = parser.arg(
+        clap::Arg::new("PRUNE_UNUSED")
+            .long("prune-unused")
+            .action(clap::ArgAction::SetTrue)
+            .help("Drop load commands of libraries no symbol is imported from\nand leave out the libraries nothing else loads."),
+    );
+
+    let parser = parser.arg(
+        clap::Arg::new("ALLOW_INCOMPATIBLE_VERSIONS")
+            .long("allow-incompatible-versions")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only warn when a bundled library is older than\nthe compatibility version a binary requires."),
+    );
+
+    let parser = parser.arg(
+        clap::Arg::new("DEPLOYMENT_TARGET")
+            .long("deployment-target")
+            .value_name("VERSION")
+            .help("Oldest macOS the bundle has to launch on, e.g. `12.0`.\nLibraries built for a newer one are reported.\n<Defaults to the minimum macOS version of the inputs>"),
+    );
+
+    let parser = parser.arg(
+        clap::Arg::new("SYSROOT")
+            .long("sysroot")
+            .global(true)
+            .help("Root of a copied macOS file system.\nAbsolute paths are looked up under it."),
+    );
+
+    let parser = parser.arg(
+        clap::Arg::new("PREFIX_MAP")
+            .long("prefix-map")
+            .value_name("FROM=TO")
+            .global(true)
+            .action(clap::ArgAction::Append)
+            .help("Look up absolute paths starting with FROM under TO.\nCan be given multiple times, longest match wins."),
+    );
+
+    let parser = parser.args(policy_args());
+
+    // Bundling is the default action, subcommands only inspect binaries and
+    // don't need an output folder.
+    let parser = parser.subcommand_negates_reqs(true).subcommand(
+        clap::Command::new("graph")
+            .about("Prints the resolved dependency graph of a binary.")
+            .arg(
+                clap::Arg::new("BINARY_PATH")
+                    .short('i')
+                    .long("input")
+                    .required(true)
+                    .help("Path of the binary\ndesired to be inspected."),
+            )
+            .arg(
+                clap::Arg::new("FORMAT")
+                    .short('f')
+                    .long("format")
+                    .default_value("dot")
+                    .help("[dot, json, mermaid]"),
+            )
+            .arg(
+                clap::Arg::new("GRAPH_OUTPUT")
+                    .short('o')
+                    .long("output")
+                    .help("Write the graph to a file instead of stdout."),
+            ),
+    );
+
+    let parser = parser.subcommand(
+        clap::Command::new("verify")
+            .about("Checks that a bundle only depends on itself and system libraries.")
+            .arg(
+                clap::Arg::new("BUNDLE_PATH")
+                    .short('i')
+                    .long("input")
+                    .required(true)
+                    .help("Path of the bundle folder\ndesired to be verified."),
+            ),
+    );
+
+    let parser = parser.subcommand(
+        clap::Command::new("apply")
+            .about("Bundles exactly what a plan written with `--plan` lists.")
+            .arg(
+                clap::Arg::new("PLAN")
+                    .short('p')
+                    .long("plan")
+                    .required(true)
+                    .help("Path of the plan file."),
+            )
+            .arg(
+                clap::Arg::new("OUTPUT_PATH")
+                    .short('o')
+                    .long("output")
+                    .help("Path of the destination folder for bundle.\n<Defaults to the output of the plan>"),
+            )
+            .args(output_args()),
+    );
+
+    parser.get_matches()
+}
+
+// Values of a `.app` bundle, Info.plist is generated from them.
+fn app_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("APP")
+            .long("app")
+            .action(clap::ArgAction::SetTrue)
+            .requires("BUNDLE_ID")
+            .help("Bundle into a macOS application, the destination being the `.app` folder.\nExecutables go to Contents/MacOS and libraries to Contents/Frameworks."),
+        clap::Arg::new("BUNDLE_ID")
+            .long("bundle-id")
+            .requires("APP")
+            .help("CFBundleIdentifier of the app, e.g. `com.example.tool`."),
+        clap::Arg::new("BUNDLE_VERSION")
+            .long("bundle-version")
+            .requires("APP")
+            .default_value("1.0")
+            .help("CFBundleShortVersionString and CFBundleVersion of the app."),
+        clap::Arg::new("BUNDLE_NAME")
+            .long("bundle-name")
+            .requires("APP")
+            .help("CFBundleName of the app.\n<Defaults to the name of the `.app` folder>"),
+        clap::Arg::new("EXECUTABLE")
+            .long("executable")
+            .requires("APP")
+            .help("Main executable of the app, relative to Contents/MacOS.\n<Defaults to the first executable given>"),
+        clap::Arg::new("MIN_OS")
+            .long("min-os")
+            .requires("APP")
+            .help("LSMinimumSystemVersion of the app.\n<Defaults to the minimum macOS version of the main executable>"),
+    ]
+}
+
+// Which libraries are left to the system, shared by bundling and `graph`.
+fn policy_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("ALLOW_SYSTEM")
+            .long("allow-system")
+            .value_name("GLOB")
+            .global(true)
+            .action(clap::ArgAction::Append)
+            .help("Leave install names matching GLOB to the system, e.g. `/Library/Frameworks/**`.\nCan be given multiple times."),
+        clap::Arg::new("DENY_SYSTEM")
+            .long("deny-system")
+            .value_name("GLOB")
+            .global(true)
+            .action(clap::ArgAction::Append)
+            .help("Always bundle install names matching GLOB, even system libraries.\nCan be given multiple times."),
+        clap::Arg::new("MACOS_VERSION")
+            .long("macos-version")
+            .value_name("VERSION")
+            .global(true)
+            .help("Only trust `/usr/lib` libraries shipped with this macOS version, e.g. `11.0`."),
+        clap::Arg::new("SDK")
+            .long("sdk")
+            .global(true)
+            .help("macOS SDK whose `.tbd` stubs list the `/usr/lib` libraries shipped with macOS."),
+    ]
+}
+
+// How a bundle is written, shared by bundling and `apply`.
+fn output_args() -> Vec<clap::Arg> {
+    vec![
         clap::Arg::new("CREATE_OUTPUT_PATH")
             .short('c')
             .long("create-output-path")
             .action(clap::ArgAction::SetTrue)
             .help("Create destination folder path if not exist."),
-    );
-
-    parser.get_matches()
+        clap::Arg::new("CLEAN_OUTPUT")
+            .long("clean")
+            .action(clap::ArgAction::SetTrue)
+            .help("Remove files of earlier runs from the destination and libraries folders.\nBy default the bundle is merged into them."),
+        clap::Arg::new("NO_VALIDATE")
+            .long("no-validate")
+            .action(clap::ArgAction::SetTrue)
+            .help("Publish the bundle without verifying it first."),
+        clap::Arg::new("BACKEND")
+            .short('b')
+            .long("backend")
+            .default_value("native")
+            .value_parser(["native", "tools"])
+            .help("How binaries are read, changed and signed.\n`tools` runs otool, install_name_tool and codesign."),
+    ]
 }
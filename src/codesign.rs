@@ -0,0 +1,672 @@
+// In-process ad-hoc code signing, the equivalent of `codesign --force --sign -`.
+//
+// Every architecture slice gets a fresh embedded signature made of a
+// SuperBlob holding a CodeDirectory with SHA-256 page hashes, an empty
+// requirements set and an empty CMS wrapper, just like `codesign` produces for
+// ad-hoc signatures. The old signature (if any) is dropped, `__LINKEDIT` is
+// resized to the new signature and LC_CODE_SIGNATURE is updated or added.
+//
+// A framework version folder is signed as a bundle: its resources are sealed
+// in `_CodeSignature/CodeResources` and the binary carries the hashes of that
+// file and of `Info.plist` in the special slots of its CodeDirectory.
+
+use crate::error::Error;
+use crate::framework::framework_name;
+use crate::macho::{
+    CPU_TYPE_ARM, CPU_TYPE_ARM64, Command, FileKind, LC_CODE_SIGNATURE, LC_SEGMENT_64, MH_EXECUTE,
+    MachO, build_fat, detect_kind, parse_fat_header, read_u32, write_u32, write_u64,
+};
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade_0c02;
+const CSMAGIC_REQUIREMENTS: u32 = 0xfade_0c01;
+const CSMAGIC_BLOBWRAPPER: u32 = 0xfade_0b01;
+
+const CSSLOT_CODEDIRECTORY: u32 = 0;
+const CSSLOT_INFOSLOT: u32 = 1;
+const CSSLOT_REQUIREMENTS: u32 = 2;
+const CSSLOT_RESOURCEDIR: u32 = 3;
+const CSSLOT_SIGNATURESLOT: u32 = 0x10000;
+
+const CS_ADHOC: u32 = 0x2;
+const CS_EXECSEG_MAIN_BINARY: u64 = 0x1;
+const CS_HASHTYPE_SHA256: u8 = 2;
+const CS_SHA256_LEN: usize = 32;
+const CODEDIRECTORY_VERSION: u32 = 0x20400;
+const CODEDIRECTORY_HEADER_SIZE: usize = 88;
+// Larger pages than 64 KiB are not used by any signer.
+const MAX_PAGE_SHIFT: u8 = 16;
+
+/// Hashes of bundle files sealed by a binary besides its own code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpecialSlots {
+    pub info_plist: Option<[u8; CS_SHA256_LEN]>,
+    /// Hash of `_CodeSignature/CodeResources`.
+    pub resources: Option<[u8; CS_SHA256_LEN]>,
+}
+
+/// Ad-hoc signs every architecture slice of `file_path` in place.
+pub fn sign_file(file_path: &Path) -> Result<()> {
+    let identifier = file_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("Error while reading file name of: {}", file_path.display()))?;
+    sign_file_with(file_path, &identifier, &SpecialSlots::default())
+}
+
+fn sign_file_with(file_path: &Path, identifier: &str, slots: &SpecialSlots) -> Result<()> {
+    let data = std::fs::read(file_path).map_err(Error::io("reading", file_path))?;
+    let signed = sign_data_with(&data, identifier, slots)
+        .with_context(|| format!("Error while signing: {}", file_path.display()))?;
+    std::fs::write(file_path, signed).map_err(Error::io("writing", file_path))?;
+    Ok(())
+}
+
+/// Ad-hoc signs a framework version folder, e.g. `QtCore.framework/Versions/A`
+/// or a flat framework. The binary is named by `CFBundleExecutable` of
+/// `Resources/Info.plist`, or after the framework when there is none.
+pub fn sign_bundle(folder: &Path) -> Result<()> {
+    let info_path = folder.join("Resources/Info.plist");
+    let info = match info_path.is_file() {
+        true => Some(std::fs::read(&info_path).map_err(Error::io("reading", &info_path))?),
+        false => None,
+    };
+    let dictionary = match info {
+        Some(ref info) => Some(
+            plist::from_bytes::<plist::Dictionary>(info)
+                .with_context(|| format!("Error while parsing: {}", info_path.display()))?,
+        ),
+        None => None,
+    };
+    let value = |key: &str| {
+        dictionary
+            .as_ref()
+            .and_then(|dictionary| dictionary.get(key))
+            .and_then(plist::Value::as_string)
+            .map(str::to_string)
+    };
+    let Some(executable) = value("CFBundleExecutable").or_else(|| framework_name(folder)) else {
+        return Err(anyhow!(
+            "No executable found for bundle: {}",
+            folder.display()
+        ));
+    };
+    let identifier = value("CFBundleIdentifier").unwrap_or_else(|| executable.clone());
+
+    let resources = code_resources(folder, Path::new(&executable))?;
+    let signature_folder = folder.join("_CodeSignature");
+    std::fs::create_dir_all(&signature_folder)
+        .map_err(Error::io("creating folder", &signature_folder))?;
+    let resources_path = signature_folder.join("CodeResources");
+    std::fs::write(&resources_path, &resources).map_err(Error::io("writing", &resources_path))?;
+
+    let slots = SpecialSlots {
+        info_plist: info.as_deref().map(sha256),
+        resources: Some(sha256(&resources)),
+    };
+    sign_file_with(&folder.join(&executable), &identifier, &slots)
+}
+
+/// Signs a thin or universal file and returns the new file contents.
+/// Slices of a universal file may change size, so the fat header is rebuilt.
+pub fn sign_data(data: &[u8], identifier: &str) -> Result<Vec<u8>> {
+    sign_data_with(data, identifier, &SpecialSlots::default())
+}
+
+fn sign_data_with(data: &[u8], identifier: &str, slots: &SpecialSlots) -> Result<Vec<u8>> {
+    match detect_kind(data) {
+        FileKind::MachO => sign_slice_with(data, identifier, slots),
+        FileKind::Fat => {
+            let arches = parse_fat_header(data)?;
+            let mut slices = Vec::with_capacity(arches.len());
+            for arch in &arches {
+                let signed =
+                    sign_slice_with(&data[arch.range()], identifier, slots).with_context(|| {
+                        format!("Error while signing architecture {}", arch.arch_name())
+                    })?;
+                slices.push((signed, arch.align));
+            }
+            let slices: Vec<(&[u8], u32)> = slices.iter().map(|(d, a)| (&d[..], *a)).collect();
+            build_fat(&slices)
+        }
+        _ => Err(anyhow!("Not a Mach-O file")),
+    }
+}
+
+/// Signs a single Mach-O image.
+pub fn sign_slice(data: &[u8], identifier: &str) -> Result<Vec<u8>> {
+    sign_slice_with(data, identifier, &SpecialSlots::default())
+}
+
+fn sign_slice_with(data: &[u8], identifier: &str, slots: &SpecialSlots) -> Result<Vec<u8>> {
+    let macho = MachO::parse(data)?;
+    let le = macho.header.little_endian;
+    let page_size = page_size(macho.header.cputype);
+
+    let Some(linkedit) = macho
+        .commands
+        .iter()
+        .find(|lc| matches!(lc.command, Command::Segment(ref seg) if seg.segname == "__LINKEDIT"))
+    else {
+        return Err(anyhow!("No __LINKEDIT segment found"));
+    };
+    let Command::Segment(ref linkedit_seg) = linkedit.command else {
+        unreachable!();
+    };
+
+    // Everything up to the old signature (or the end of __LINKEDIT when there
+    // is none) is the code we sign. The signature itself starts 16 aligned.
+    let code_end = match macho.code_signature() {
+        Some(cs) => Some(cs.dataoff as u64),
+        None => linkedit_seg.fileoff.checked_add(linkedit_seg.filesize),
+    };
+    let Some(code_end) =
+        code_end.filter(|end| (linkedit_seg.fileoff..=data.len() as u64).contains(end))
+    else {
+        return Err(anyhow!(
+            "Code signature or __LINKEDIT outside of the image: {} bytes",
+            data.len()
+        ));
+    };
+    let code_end = code_end as usize;
+    let code_limit = code_end.next_multiple_of(16);
+    if code_limit > u32::MAX as usize {
+        return Err(anyhow!("Image too large to sign: {} bytes", code_limit));
+    }
+
+    let mut image = data[..code_end].to_vec();
+    image.resize(code_limit, 0);
+
+    let cs_offset = match macho.commands.iter().find(|lc| lc.cmd == LC_CODE_SIGNATURE) {
+        Some(lc) => lc.offset,
+        None => add_code_signature_command(&mut image, &macho)?,
+    };
+
+    let is_executable = macho.header.filetype == MH_EXECUTE;
+    let (exec_seg_base, exec_seg_limit) = macho
+        .segment("__TEXT")
+        .map(|seg| (seg.fileoff, seg.filesize))
+        .unwrap_or_default();
+
+    let code_slots = code_limit.div_ceil(page_size);
+    let special_slots = match slots.resources {
+        Some(_) => CSSLOT_RESOURCEDIR,
+        None => CSSLOT_REQUIREMENTS,
+    } as usize;
+    let ident_size = identifier.len() + 1;
+    let hash_offset = CODEDIRECTORY_HEADER_SIZE + ident_size + special_slots * CS_SHA256_LEN;
+    let cd_size = hash_offset + code_slots * CS_SHA256_LEN;
+    let requirements = empty_requirements();
+    let cms = empty_cms_wrapper();
+    let superblob_header = 12 + 3 * 8;
+    let sig_size = superblob_header + cd_size + requirements.len() + cms.len();
+    let sig_datasize = sig_size.next_multiple_of(16);
+
+    // Point LC_CODE_SIGNATURE and __LINKEDIT at the new signature before
+    // hashing, the load commands live in the first page.
+    write_u32(&mut image, cs_offset + 8, code_limit as u32, le)?;
+    write_u32(&mut image, cs_offset + 12, sig_datasize as u32, le)?;
+    let file_end = (code_limit + sig_datasize) as u64;
+    let filesize = file_end - linkedit_seg.fileoff;
+    let vmsize = filesize.next_multiple_of(page_size as u64);
+    if linkedit.cmd == LC_SEGMENT_64 {
+        write_u64(&mut image, linkedit.offset + 32, vmsize, le)?;
+        write_u64(&mut image, linkedit.offset + 48, filesize, le)?;
+    } else {
+        write_u32(&mut image, linkedit.offset + 28, vmsize as u32, le)?;
+        write_u32(&mut image, linkedit.offset + 36, filesize as u32, le)?;
+    }
+
+    let mut cd = Vec::with_capacity(cd_size);
+    push_u32(&mut cd, CSMAGIC_CODEDIRECTORY);
+    push_u32(&mut cd, cd_size as u32);
+    push_u32(&mut cd, CODEDIRECTORY_VERSION);
+    push_u32(&mut cd, CS_ADHOC);
+    push_u32(&mut cd, hash_offset as u32);
+    push_u32(&mut cd, CODEDIRECTORY_HEADER_SIZE as u32);
+    push_u32(&mut cd, special_slots as u32);
+    push_u32(&mut cd, code_slots as u32);
+    push_u32(&mut cd, code_limit as u32);
+    cd.push(CS_SHA256_LEN as u8);
+    cd.push(CS_HASHTYPE_SHA256);
+    cd.push(0); // platform
+    cd.push(page_size.trailing_zeros() as u8);
+    push_u32(&mut cd, 0); // spare2
+    push_u32(&mut cd, 0); // scatterOffset
+    push_u32(&mut cd, 0); // teamOffset
+    push_u32(&mut cd, 0); // spare3
+    push_u64(&mut cd, 0); // codeLimit64
+    push_u64(&mut cd, exec_seg_base);
+    push_u64(&mut cd, exec_seg_limit);
+    push_u64(
+        &mut cd,
+        if is_executable {
+            CS_EXECSEG_MAIN_BINARY
+        } else {
+            0
+        },
+    );
+    debug_assert_eq!(cd.len(), CODEDIRECTORY_HEADER_SIZE);
+    cd.extend_from_slice(identifier.as_bytes());
+    cd.push(0);
+    // Special slots are stored in reverse order right before slot zero.
+    for slot in (CSSLOT_INFOSLOT..=special_slots as u32).rev() {
+        let hash = match slot {
+            CSSLOT_INFOSLOT => slots.info_plist,
+            CSSLOT_REQUIREMENTS => Some(sha256(&requirements)),
+            _ => slots.resources,
+        };
+        cd.extend_from_slice(&hash.unwrap_or([0u8; CS_SHA256_LEN]));
+    }
+    for page in image.chunks(page_size) {
+        cd.extend_from_slice(&sha256(page));
+    }
+
+    let mut signature = Vec::with_capacity(sig_datasize);
+    push_u32(&mut signature, CSMAGIC_EMBEDDED_SIGNATURE);
+    push_u32(&mut signature, sig_size as u32);
+    push_u32(&mut signature, 3);
+    let mut offset = superblob_header;
+    for (slot, blob) in [
+        (CSSLOT_CODEDIRECTORY, &cd),
+        (CSSLOT_REQUIREMENTS, &requirements),
+        (CSSLOT_SIGNATURESLOT, &cms),
+    ] {
+        push_u32(&mut signature, slot);
+        push_u32(&mut signature, offset as u32);
+        offset += blob.len();
+    }
+    signature.extend_from_slice(&cd);
+    signature.extend_from_slice(&requirements);
+    signature.extend_from_slice(&cms);
+    signature.resize(sig_datasize, 0);
+
+    image.extend_from_slice(&signature);
+    Ok(image)
+}
+
+/// Checks that the embedded signature of a single Mach-O image matches its
+/// contents.
+pub fn verify_slice(data: &[u8]) -> Result<()> {
+    let macho = MachO::parse(data)?;
+    let Some(cs) = macho.code_signature() else {
+        return Err(anyhow!("No code signature found"));
+    };
+    let blob = cs
+        .dataoff
+        .checked_add(cs.datasize)
+        .and_then(|end| data.get(cs.dataoff as usize..end as usize))
+        .ok_or_else(|| anyhow!("Code signature exceeds file size"))?;
+    if read_u32(blob, 0, false)? != CSMAGIC_EMBEDDED_SIGNATURE {
+        return Err(anyhow!("Invalid embedded signature magic"));
+    }
+
+    let count = read_u32(blob, 8, false)? as usize;
+    let mut cd_offset = None;
+    for i in 0..count {
+        if read_u32(blob, 12 + i * 8, false)? == CSSLOT_CODEDIRECTORY {
+            cd_offset = Some(read_u32(blob, 16 + i * 8, false)? as usize);
+        }
+    }
+    let cd_offset = cd_offset.ok_or_else(|| anyhow!("No code directory found"))?;
+    let cd = blob
+        .get(cd_offset..)
+        .ok_or_else(|| anyhow!("Code directory exceeds signature size"))?;
+    if read_u32(cd, 0, false)? != CSMAGIC_CODEDIRECTORY {
+        return Err(anyhow!("Invalid code directory magic"));
+    }
+
+    let hash_offset = read_u32(cd, 16, false)? as usize;
+    let code_slots = read_u32(cd, 28, false)? as usize;
+    let code_limit = read_u32(cd, 32, false)? as usize;
+    let Some(&[hash_size, hash_type, _, page_shift]) = cd.get(36..40) else {
+        return Err(anyhow!("Truncated code directory"));
+    };
+    let hash_size = hash_size as usize;
+    if page_shift > MAX_PAGE_SHIFT {
+        return Err(anyhow!("Unsupported page size: 2^{}", page_shift));
+    }
+    let page_size = 1usize << page_shift;
+    if hash_type != CS_HASHTYPE_SHA256 || hash_size != CS_SHA256_LEN {
+        return Err(anyhow!("Unsupported hash type: {}", hash_type));
+    }
+    if code_limit != cs.dataoff as usize {
+        return Err(anyhow!(
+            "Code limit {} does not match signature offset {}",
+            code_limit,
+            cs.dataoff
+        ));
+    }
+
+    for (slot, page) in data[..code_limit].chunks(page_size).enumerate() {
+        if slot >= code_slots {
+            return Err(anyhow!("Missing hash for page {}", slot));
+        }
+        let start = hash_offset + slot * CS_SHA256_LEN;
+        let expected = cd
+            .get(start..start + CS_SHA256_LEN)
+            .ok_or_else(|| anyhow!("Truncated code directory"))?;
+        if expected != sha256(page) {
+            return Err(anyhow!("Hash mismatch for page {}", slot));
+        }
+    }
+    Ok(())
+}
+
+// `_CodeSignature/CodeResources` of a bundle folder with the rules
+// `codesign` uses by default. Version 1 `files` only cover `Resources`, the
+// `files2` entries cover everything but the binary, `Info.plist` and the
+// signature itself. Nested code is sealed by the hash of its contents, it is
+// signed before the bundle containing it.
+fn code_resources(folder: &Path, executable: &Path) -> Result<Vec<u8>> {
+    use plist::{Dictionary, Value};
+
+    let mut entries = vec![];
+    list_files(folder, folder, &mut entries)?;
+    entries.sort();
+
+    let mut files = Dictionary::new();
+    let mut files2 = Dictionary::new();
+    for relative in entries {
+        let name = relative.to_string_lossy().replace('\\', "/");
+        let path = folder.join(&relative);
+        let omitted = relative == executable
+            || relative.starts_with("_CodeSignature")
+            || relative.file_name().is_some_and(|name| name == ".DS_Store")
+            || name == "Info.plist"
+            || name == "PkgInfo";
+        if omitted {
+            continue;
+        }
+        if path.is_symlink() {
+            let target = std::fs::read_link(&path).map_err(Error::io("reading link", &path))?;
+            let mut entry = Dictionary::new();
+            entry.insert(
+                "symlink".to_string(),
+                Value::String(target.to_string_lossy().into_owned()),
+            );
+            files2.insert(name, Value::Dictionary(entry));
+            continue;
+        }
+        let data = std::fs::read(&path).map_err(Error::io("reading", &path))?;
+        if relative.starts_with("Resources") {
+            files.insert(name.clone(), Value::Data(sha1(&data).to_vec()));
+        }
+        let mut entry = Dictionary::new();
+        entry.insert("hash2".to_string(), Value::Data(sha256(&data).to_vec()));
+        files2.insert(name, Value::Dictionary(entry));
+    }
+
+    let rule = |pairs: &[(&str, Value)]| {
+        let mut dictionary = Dictionary::new();
+        for (key, value) in pairs {
+            dictionary.insert(key.to_string(), value.clone());
+        }
+        Value::Dictionary(dictionary)
+    };
+    let optional = |weight: f64| {
+        rule(&[
+            ("optional", Value::Boolean(true)),
+            ("weight", Value::Real(weight)),
+        ])
+    };
+    let omit = |weight: f64| {
+        rule(&[
+            ("omit", Value::Boolean(true)),
+            ("weight", Value::Real(weight)),
+        ])
+    };
+    let weight = |weight: f64| rule(&[("weight", Value::Real(weight))]);
+    let rules = rule(&[
+        ("^Resources/", Value::Boolean(true)),
+        ("^Resources/.*\\.lproj/", optional(1000.0)),
+        ("^Resources/.*\\.lproj/locversion.plist$", omit(1100.0)),
+        ("^Resources/Base\\.lproj/", weight(1010.0)),
+        ("^version.plist$", Value::Boolean(true)),
+    ]);
+    let rules2 = rule(&[
+        (".*\\.dSYM($|/)", weight(11.0)),
+        ("^(.*/)?\\.DS_Store$", omit(2000.0)),
+        ("^.*", Value::Boolean(true)),
+        ("^.*\\.lproj/", optional(1000.0)),
+        ("^.*\\.lproj/locversion.plist$", omit(1100.0)),
+        ("^Base\\.lproj/", weight(1010.0)),
+        ("^Info\\.plist$", omit(20.0)),
+        ("^PkgInfo$", omit(20.0)),
+        ("^embedded\\.provisionprofile$", weight(20.0)),
+        ("^version\\.plist$", weight(20.0)),
+    ]);
+    let resources = rule(&[
+        ("files", Value::Dictionary(files)),
+        ("files2", Value::Dictionary(files2)),
+        ("rules", rules),
+        ("rules2", rules2),
+    ]);
+
+    let mut xml = vec![];
+    resources.to_writer_xml(&mut xml)?;
+    xml.push(b'\n');
+    Ok(xml)
+}
+
+// Files and links below `folder`, relative to `root`. Links are not
+// followed.
+fn list_files(root: &Path, folder: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(folder).map_err(Error::io("reading folder", folder))? {
+        let path = entry.map_err(Error::io("reading folder", folder))?.path();
+        if path.is_dir() && !path.is_symlink() {
+            list_files(root, &path, files)?;
+        } else {
+            files.push(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Appends a LC_CODE_SIGNATURE command to the load commands of `image` and
+/// returns its offset. The values are filled in by the caller.
+fn add_code_signature_command(image: &mut [u8], macho: &MachO) -> Result<usize> {
+    const CMDSIZE: usize = 16;
+    let le = macho.header.little_endian;
+    if macho.header_padding(image.len()) < CMDSIZE {
+        return Err(Error::InsufficientPadding {
+            action: "add LC_CODE_SIGNATURE",
+            needed: CMDSIZE - macho.header_padding(image.len()),
+            available: macho.header_padding(image.len()),
+        }
+        .into());
+    }
+    let offset = macho.load_commands_end();
+    write_u32(image, offset, LC_CODE_SIGNATURE, le)?;
+    write_u32(image, offset + 4, CMDSIZE as u32, le)?;
+    write_u32(image, 16, macho.header.ncmds + 1, le)?;
+    write_u32(image, 20, macho.header.sizeofcmds + CMDSIZE as u32, le)?;
+    Ok(offset)
+}
+
+/// Apple silicon uses 16K pages, everything else 4K.
+fn page_size(cputype: i32) -> usize {
+    match cputype {
+        CPU_TYPE_ARM64 | CPU_TYPE_ARM => 0x4000,
+        _ => 0x1000,
+    }
+}
+
+fn empty_requirements() -> Vec<u8> {
+    let mut blob = Vec::with_capacity(12);
+    push_u32(&mut blob, CSMAGIC_REQUIREMENTS);
+    push_u32(&mut blob, 12);
+    push_u32(&mut blob, 0);
+    blob
+}
+
+fn empty_cms_wrapper() -> Vec<u8> {
+    let mut blob = Vec::with_capacity(8);
+    push_u32(&mut blob, CSMAGIC_BLOBWRAPPER);
+    push_u32(&mut blob, 8);
+    blob
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    sha1::Sha1::digest(data).into()
+}
+
+fn sha256(data: &[u8]) -> [u8; CS_SHA256_LEN] {
+    Sha256::digest(data).into()
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macho::{parse_slices, slice_ranges, tests::_fake_x86_64};
+    use crate::rewrite::{Edit, apply_edits};
+    use std::path::PathBuf;
+
+    fn _read_resource(relative: &str) -> Vec<u8> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources")
+            .join(relative);
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn test_sign_executable() {
+        let data = _read_resource("poppler/pdftoppm");
+        let signed = sign_slice(&data, "pdftoppm").unwrap();
+        verify_slice(&signed).unwrap();
+
+        let macho = MachO::parse(&signed).unwrap();
+        let cs = macho.code_signature().unwrap();
+        let linkedit = macho.segment("__LINKEDIT").unwrap();
+        assert_eq!((cs.dataoff + cs.datasize) as usize, signed.len());
+        assert_eq!(linkedit.fileoff + linkedit.filesize, signed.len() as u64);
+        assert!(linkedit.vmsize >= linkedit.filesize);
+    }
+
+    #[test]
+    fn test_sign_is_deterministic() {
+        let data = _read_resource("poppler/libs/libassuan.9.dylib");
+        let signed = sign_slice(&data, "libassuan.9").unwrap();
+        let resigned = sign_slice(&signed, "libassuan.9").unwrap();
+        assert_eq!(signed, resigned);
+    }
+
+    #[test]
+    fn test_sign_after_edit() {
+        let mut data = _read_resource("poppler/libs/libassuan.9.dylib");
+        apply_edits(&mut data, &[Edit::SetId("@rpath/libfoo.dylib".to_string())]).unwrap();
+        assert!(verify_slice(&data).is_err());
+        let signed = sign_slice(&data, "libfoo").unwrap();
+        verify_slice(&signed).unwrap();
+    }
+
+    #[test]
+    fn test_verify_malformed() {
+        let signed = sign_slice(&_read_resource("poppler/pdftoppm"), "pdftoppm").unwrap();
+        let cs = MachO::parse(&signed)
+            .unwrap()
+            .code_signature()
+            .unwrap()
+            .clone();
+        let blob = cs.dataoff as usize;
+        let end = blob + cs.datasize as usize;
+        // Offset field of the code directory slot, it comes first.
+        let slot = blob + 16;
+        assert_eq!(
+            read_u32(&signed, blob + 12, false).unwrap(),
+            CSSLOT_CODEDIRECTORY
+        );
+        let cd = blob + read_u32(&signed, slot, false).unwrap() as usize;
+
+        let mut outside = signed.clone();
+        write_u32(&mut outside, slot, u32::MAX, false).unwrap();
+        assert!(verify_slice(&outside).is_err());
+
+        // Only 38 bytes of a code directory are left before the end.
+        let mut truncated = signed.clone();
+        let short = end - 38;
+        truncated.copy_within(cd..cd + 36, short);
+        write_u32(&mut truncated, slot, (short - blob) as u32, false).unwrap();
+        let error = verify_slice(&truncated).unwrap_err();
+        assert_eq!(error.to_string(), "Truncated code directory");
+
+        let mut huge_pages = signed.clone();
+        huge_pages[cd + 39] = 64;
+        let error = verify_slice(&huge_pages).unwrap_err();
+        assert_eq!(error.to_string(), "Unsupported page size: 2^64");
+    }
+
+    #[test]
+    fn test_sign_malformed() {
+        let data = _read_resource("poppler/libs/libpng16.16.dylib");
+        let macho = MachO::parse(&data).unwrap();
+        let dataoff = macho
+            .commands
+            .iter()
+            .find(|lc| lc.cmd == LC_CODE_SIGNATURE)
+            .unwrap()
+            .offset
+            + 8;
+        let linkedit = macho.segment("__LINKEDIT").unwrap().fileoff as u32;
+
+        let mut before_linkedit = data.clone();
+        write_u32(&mut before_linkedit, dataoff, linkedit - 0x1000, false).unwrap();
+        assert!(sign_slice(&before_linkedit, "libpng16.16").is_err());
+
+        let mut past_end = data.clone();
+        write_u32(&mut past_end, dataoff, u32::MAX, false).unwrap();
+        assert!(sign_slice(&past_end, "libpng16.16").is_err());
+    }
+
+    #[test]
+    fn test_sign_universal() {
+        let arm64 = _read_resource("poppler/libs/libassuan.9.dylib");
+        let fat = build_fat(&[(&_fake_x86_64(&arm64), 12), (&arm64, 14)]).unwrap();
+        let signed = sign_data(&fat, "libassuan.9").unwrap();
+        assert_eq!(parse_slices(&signed).unwrap().len(), 2);
+        for range in slice_ranges(&signed).unwrap() {
+            verify_slice(&signed[range]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sign_bundle() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("Assuan.framework/Versions/A");
+        std::fs::create_dir_all(folder.join("Resources")).unwrap();
+        std::fs::write(
+            folder.join("Assuan"),
+            _read_resource("poppler/libs/libassuan.9.dylib"),
+        )
+        .unwrap();
+        std::fs::write(folder.join("Resources/notes.txt"), "notes").unwrap();
+        sign_bundle(&folder).unwrap();
+
+        let resources = std::fs::read(folder.join("_CodeSignature/CodeResources")).unwrap();
+        let seal: plist::Dictionary = plist::from_bytes(&resources).unwrap();
+        let files2 = seal.get("files2").unwrap().as_dictionary().unwrap();
+        assert_eq!(files2.keys().collect::<Vec<_>>(), ["Resources/notes.txt"]);
+
+        let signed = std::fs::read(folder.join("Assuan")).unwrap();
+        verify_slice(&signed).unwrap();
+        let macho = MachO::parse(&signed).unwrap();
+        let cs = macho.code_signature().unwrap();
+        let blob = &signed[cs.dataoff as usize..];
+        let cd = &blob[read_u32(blob, 16, false).unwrap() as usize..];
+        assert_eq!(read_u32(cd, 24, false).unwrap(), CSSLOT_RESOURCEDIR);
+        let hash_offset = read_u32(cd, 16, false).unwrap() as usize;
+        let slot = hash_offset - CSSLOT_RESOURCEDIR as usize * CS_SHA256_LEN;
+        assert_eq!(&cd[slot..slot + CS_SHA256_LEN], sha256(&resources));
+    }
+}
@@ -0,0 +1,179 @@
+// Minimum macOS version and platform of the binaries in a bundle.
+//
+// Every slice states the OS it was built for in LC_BUILD_VERSION, or in
+// LC_VERSION_MIN_MACOSX with older toolchains. A bundle doesn't launch on a
+// macOS older than the highest minimum of any binary in it, so a Homebrew
+// library built on a newer machine silently raises it above the target of
+// the executable. Slices built for another platform only, e.g. the iOS
+// simulator or Mac Catalyst, can't be loaded by a macOS process at all.
+// Zippered libraries carry a macOS build version next to the Catalyst one
+// and are fine.
+
+use crate::helpers::read_slices;
+use crate::macho::{PLATFORM_MACOS, Version, platform_name};
+use crate::model::Graph;
+use anyhow::Result;
+use std::fmt;
+use std::path::PathBuf;
+
+/// A platform an architecture slice of a binary was built for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SliceTarget {
+    pub file: PathBuf,
+    pub arch: String,
+    pub platform: u32,
+    pub minos: Version,
+}
+
+impl fmt::Display for SliceTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {} {})",
+            self.file.display(),
+            self.arch,
+            platform_name(self.platform),
+            self.minos
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeploymentReport {
+    /// The deployment target given, otherwise the highest minimum macOS
+    /// version of the base binaries.
+    pub target: Option<Version>,
+    /// Oldest macOS every binary of the bundle launches on.
+    pub minimum: Option<Version>,
+    /// macOS slices built for a newer version than `target`.
+    pub newer: Vec<SliceTarget>,
+    /// Build versions of the slices which have none for macOS.
+    pub mismatched: Vec<SliceTarget>,
+}
+
+/// Reads the build versions of the base binaries and every bundled library
+/// of `graph`. Slices without any build version are not checked.
+pub fn check(graph: &Graph, target: Option<Version>) -> Result<DeploymentReport> {
+    let mut report = DeploymentReport::default();
+    let mut base_minimum = None;
+    let mut macos = vec![];
+    for (id, node) in graph.nodes().iter().enumerate() {
+        if node.is_system() || node.is_missing() {
+            continue;
+        }
+        for macho in read_slices(node.file_path())? {
+            let slice_target = |platform: u32, minos: Version| SliceTarget {
+                file: node.file_path().to_path_buf(),
+                arch: macho.header.arch_name().to_string(),
+                platform,
+                minos,
+            };
+            match macho
+                .build_versions()
+                .find(|build| build.platform == PLATFORM_MACOS)
+            {
+                Some(build) => {
+                    if graph.roots().contains(&id) {
+                        base_minimum = base_minimum.max(Some(build.minos));
+                    }
+                    macos.push(slice_target(build.platform, build.minos));
+                }
+                None => report.mismatched.extend(
+                    macho
+                        .build_versions()
+                        .map(|build| slice_target(build.platform, build.minos)),
+                ),
+            }
+        }
+    }
+
+    report.target = target.or(base_minimum);
+    report.minimum = macos.iter().map(|slice| slice.minos).max();
+    if let Some(target) = report.target {
+        report.newer = macos
+            .into_iter()
+            .filter(|slice| slice.minos > target)
+            .collect();
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NativeBackend;
+    use crate::macho::{Command, MachO, PLATFORM_IOSSIMULATOR, write_u32};
+    use crate::model::Binary;
+    use crate::resolver::Resolver;
+    use std::path::Path;
+
+    fn _graph(input: &Path) -> Graph {
+        let resolver = Resolver::new(input);
+        let binary = Binary::new(input.to_path_buf(), true, true).unwrap();
+        Graph::collect(binary, &resolver, &NativeBackend).unwrap()
+    }
+
+    #[test]
+    fn test_deployment_target() {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let graph = _graph(&input);
+
+        // pdftoppm and some of its libraries target macOS 26, the others 15.
+        let report = check(&graph, None).unwrap();
+        assert_eq!(report.target, Some(Version::new(26, 0, 0)));
+        assert_eq!(report.minimum, Some(Version::new(26, 0, 0)));
+        assert!(report.newer.is_empty());
+        assert!(report.mismatched.is_empty());
+
+        let report = check(&graph, Some(Version::new(15, 0, 0))).unwrap();
+        assert_eq!(report.target, Some(Version::new(15, 0, 0)));
+        assert!(report.newer.iter().any(|slice| slice.file == input));
+        assert!(
+            report
+                .newer
+                .iter()
+                .any(|slice| slice.file.ends_with("libpoppler.154.0.0.dylib"))
+        );
+        assert!(
+            report
+                .newer
+                .iter()
+                .all(|slice| !slice.file.ends_with("libpng16.16.dylib"))
+        );
+    }
+
+    #[test]
+    fn test_platform_mismatch() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("libs")).unwrap();
+        let input = root.path().join("pdftoppm");
+        std::fs::copy(resources.join("pdftoppm"), &input).unwrap();
+        for entry in std::fs::read_dir(resources.join("libs")).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(
+                entry.path(),
+                root.path().join("libs").join(entry.file_name()),
+            )
+            .unwrap();
+        }
+        let liblcms2 = root.path().join("libs/liblcms2.2.dylib");
+        let mut data = std::fs::read(&liblcms2).unwrap();
+        let macho = MachO::parse(&data).unwrap();
+        let build = macho
+            .commands
+            .iter()
+            .find(|lc| matches!(lc.command, Command::BuildVersion(_)))
+            .unwrap();
+        write_u32(&mut data, build.offset + 8, PLATFORM_IOSSIMULATOR, true).unwrap();
+        std::fs::write(&liblcms2, data).unwrap();
+
+        let report = check(&_graph(&input), None).unwrap();
+        assert_eq!(report.mismatched.len(), 1);
+        let slice = &report.mismatched[0];
+        assert!(slice.file.ends_with("liblcms2.2.dylib"));
+        assert_eq!(slice.platform, PLATFORM_IOSSIMULATOR);
+        assert!(slice.to_string().ends_with("(arm64, iOS Simulator 15.0.0)"));
+    }
+}
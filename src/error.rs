@@ -0,0 +1,122 @@
+// Failures callers and wrapper scripts need to tell apart.
+//
+// Functions of the crate still return `anyhow::Result` so that context can be
+// attached on the way up, the root cause of a failure is one of the variants
+// below whenever it falls into one of these categories. `exit_code` digs it
+// out of the error chain, anything else exits with `1`.
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Library not found: {install_name}\nReferenced from: {loader}")]
+    MissingDependency {
+        install_name: String,
+        loader: PathBuf,
+    },
+
+    #[error(
+        "No rpath can be resolved for: {install_name}\nReferenced from: {loader}\nrpaths: {rpaths:?}"
+    )]
+    UnresolvedRpath {
+        install_name: String,
+        loader: PathBuf,
+        rpaths: Vec<String>,
+    },
+
+    #[error(
+        "Not enough header padding to {action}: {needed} more bytes needed, {available} available\nRelink with `-headerpad_max_install_names` to reserve more space."
+    )]
+    InsufficientPadding {
+        action: &'static str,
+        needed: usize,
+        available: usize,
+    },
+
+    #[error("{reason}: {path}")]
+    UnsupportedBinary { path: PathBuf, reason: String },
+
+    #[error("{program} failed: {command}\nstdout: {stdout}\nstderr: {stderr}")]
+    ToolFailure {
+        program: String,
+        command: String,
+        stdout: String,
+        stderr: String,
+    },
+
+    #[error("Error while {action}: {path}\n{source}")]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Bundle verification failed with {problems} problem(s): {path}")]
+    VerificationFailed { path: PathBuf, problems: usize },
+
+    #[error(
+        "{problems} load command(s) require a newer version of the library they load than the one bundled"
+    )]
+    IncompatibleVersions { problems: usize },
+}
+
+impl Error {
+    /// Exit code of the command line tool. These values are stable, `1` is
+    /// used for every other failure and `2` by clap for usage errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::MissingDependency { .. } => 10,
+            Error::UnresolvedRpath { .. } => 11,
+            Error::InsufficientPadding { .. } => 12,
+            Error::UnsupportedBinary { .. } => 13,
+            Error::ToolFailure { .. } => 14,
+            Error::Io { .. } => 15,
+            Error::VerificationFailed { .. } => 16,
+            Error::IncompatibleVersions { .. } => 17,
+        }
+    }
+
+    /// Wraps an I/O error of `path`, meant for `map_err`.
+    pub fn io(action: &'static str, path: &Path) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.to_path_buf();
+        move |source| Error::Io {
+            action,
+            path,
+            source,
+        }
+    }
+}
+
+/// Exit code for any error returned by the crate.
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+        .map_or(1, Error::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code_through_context() {
+        let result: anyhow::Result<()> = Err(Error::MissingDependency {
+            install_name: "@rpath/libfoo.dylib".to_string(),
+            loader: PathBuf::from("/bin/foo"),
+        }
+        .into());
+        let error = result.context("Error while bundling").unwrap_err();
+        assert_eq!(exit_code(&error), 10);
+
+        let io = std::fs::read("/nonexistent/file")
+            .map_err(Error::io("reading", Path::new("/nonexistent/file")))
+            .context("outer")
+            .unwrap_err();
+        assert_eq!(exit_code(&io), 15);
+        assert!(format!("{:#}", io).contains("Error while reading: /nonexistent/file"));
+
+        assert_eq!(exit_code(&anyhow::anyhow!("anything else")), 1);
+    }
+}
@@ -0,0 +1,285 @@
+// Textual representations of the dependency graph.
+//
+// DOT and Mermaid are meant to be looked at, JSON is meant for scripts. All
+// of them carry the same information: every node with its path, the install
+// name it gets inside the bundle, its current LC_ID_DYLIB, architectures and
+// whether it is a system library, and every edge with its load command kind.
+
+use crate::model::{Binary, Edge, Graph, NodeId};
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+    Mermaid,
+}
+
+impl FromStr for GraphFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => Err(anyhow!(
+                "Unknown graph format: {}\nMust be one of: dot, json, mermaid",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NodeView<'a> {
+    index: NodeId,
+    path: String,
+    install_name: Option<&'a str>,
+    id: Option<&'a str>,
+    archs: &'a [String],
+    status: &'static str,
+}
+
+impl<'a> NodeView<'a> {
+    fn new(index: NodeId, node: &'a Binary) -> Self {
+        NodeView {
+            index,
+            path: node.file_path().display().to_string(),
+            install_name: node.install_name_new(),
+            id: node.install_name_id(),
+            archs: node.archs(),
+            status: status(node),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EdgeView<'a> {
+    from: NodeId,
+    to: NodeId,
+    install_name: &'a str,
+    kind: &'static str,
+}
+
+impl<'a> EdgeView<'a> {
+    fn new(edge: &'a Edge) -> Self {
+        EdgeView {
+            from: edge.from,
+            to: edge.to,
+            install_name: &edge.install_name,
+            kind: edge.kind.name(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GraphView<'a> {
+    nodes: Vec<NodeView<'a>>,
+    edges: Vec<EdgeView<'a>>,
+}
+
+pub fn export(graph: &Graph, format: GraphFormat) -> Result<String> {
+    match format {
+        GraphFormat::Dot => Ok(to_dot(graph)),
+        GraphFormat::Json => to_json(graph),
+        GraphFormat::Mermaid => Ok(to_mermaid(graph)),
+    }
+}
+
+fn status(node: &Binary) -> &'static str {
+    if node.is_system() {
+        "system"
+    } else if node.is_missing() {
+        "missing"
+    } else {
+        "bundled"
+    }
+}
+
+fn file_name(node: &Binary) -> String {
+    node.file_path()
+        .file_name()
+        .map(|name| name.display().to_string())
+        .unwrap_or_else(|| node.file_path().display().to_string())
+}
+
+// Lines shown for a node in DOT and Mermaid, the path of a system or missing
+// library is its install name so it is not repeated.
+fn label_lines(node: &Binary) -> Vec<String> {
+    let mut lines = vec![file_name(node)];
+    if !node.is_system() && !node.is_missing() {
+        lines.push(node.file_path().display().to_string());
+    }
+    if let Some(install_name) = node.install_name_new() {
+        lines.push(format!("install name: {}", install_name));
+    }
+    if let Some(id) = node.install_name_id() {
+        lines.push(format!("id: {}", id));
+    }
+    if !node.archs().is_empty() {
+        lines.push(node.archs().join(", "));
+    }
+    lines.push(status(node).to_string());
+    lines
+}
+
+fn to_json(graph: &Graph) -> Result<String> {
+    let view = GraphView {
+        nodes: graph
+            .nodes()
+            .iter()
+            .enumerate()
+            .map(|(index, node)| NodeView::new(index, node))
+            .collect(),
+        edges: graph.edges().iter().map(EdgeView::new).collect(),
+    };
+    Ok(serde_json::to_string_pretty(&view)?)
+}
+
+fn dot_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn to_dot(graph: &Graph) -> String {
+    let mut out = String::from("digraph dependencies {\n    node [shape=box];\n");
+    for (index, node) in graph.nodes().iter().enumerate() {
+        let label: Vec<String> = label_lines(node).iter().map(|l| dot_escape(l)).collect();
+        let style = if node.is_system() {
+            ", style=dashed"
+        } else if node.is_missing() {
+            ", style=dotted"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            "    n{} [label=\"{}\"{}];",
+            index,
+            label.join("\\n"),
+            style
+        );
+    }
+    for edge in graph.edges() {
+        let _ = writeln!(
+            out,
+            "    n{} -> n{} [label=\"{}\\n{}\"];",
+            edge.from,
+            edge.to,
+            edge.kind.name(),
+            dot_escape(&edge.install_name)
+        );
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn mermaid_escape(value: &str) -> String {
+    value.replace('"', "#quot;")
+}
+
+fn to_mermaid(graph: &Graph) -> String {
+    let mut out = String::from("graph TD\n");
+    for (index, node) in graph.nodes().iter().enumerate() {
+        let label: Vec<String> = label_lines(node)
+            .iter()
+            .map(|l| mermaid_escape(l))
+            .collect();
+        // Libraries which aren't bundled get the rounded stadium shape.
+        let (open, close) = if node.is_system() || node.is_missing() {
+            ("([", "])")
+        } else {
+            ("[", "]")
+        };
+        let _ = writeln!(
+            out,
+            "    n{}{}\"{}\"{}",
+            index,
+            open,
+            label.join("<br/>"),
+            close
+        );
+    }
+    for edge in graph.edges() {
+        let _ = writeln!(
+            out,
+            "    n{} -->|\"{}<br/>{}\"| n{}",
+            edge.from,
+            edge.kind.name(),
+            mermaid_escape(&edge.install_name),
+            edge.to
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NativeBackend;
+    use crate::resolver::Resolver;
+    use std::path::PathBuf;
+
+    fn _poppler_graph() -> Graph {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let resolver = Resolver::new(&input);
+        let binary = Binary::new(input, true, true).unwrap();
+        let mut graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+        graph.calculate_install_names().unwrap();
+        graph
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("DOT".parse::<GraphFormat>().unwrap(), GraphFormat::Dot);
+        assert_eq!(
+            "mermaid".parse::<GraphFormat>().unwrap(),
+            GraphFormat::Mermaid
+        );
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let graph = _poppler_graph();
+        let json = export(&graph, GraphFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        let nodes = value["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), graph.nodes().len());
+        assert_eq!(nodes[0]["archs"][0], "arm64");
+        assert_eq!(nodes[0]["status"], "bundled");
+        assert!(
+            nodes
+                .iter()
+                .any(|n| { n["path"] == "/usr/lib/libSystem.B.dylib" && n["status"] == "system" })
+        );
+        assert!(nodes.iter().any(|n| {
+            n["install_name"] == "@rpath/libpoppler.154.0.0.dylib"
+                && n["id"] == "@rpath/libpoppler.154.0.0.dylib"
+        }));
+
+        let edges = value["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), graph.edges().len());
+        assert!(edges.iter().all(|e| e["kind"] == "LC_LOAD_DYLIB"));
+    }
+
+    #[test]
+    fn test_dot_and_mermaid() {
+        let graph = _poppler_graph();
+        let dot = export(&graph, GraphFormat::Dot).unwrap();
+        assert!(dot.starts_with("digraph dependencies {"));
+        assert!(dot.contains("n0 -> n"));
+        assert!(dot.contains("style=dashed"));
+        assert_eq!(dot.matches(" -> ").count(), graph.edges().len());
+
+        let mermaid = export(&graph, GraphFormat::Mermaid).unwrap();
+        assert!(mermaid.starts_with("graph TD\n"));
+        assert!(mermaid.contains("([\"libSystem.B.dylib"));
+        assert_eq!(mermaid.matches(" -->|").count(), graph.edges().len());
+    }
+}
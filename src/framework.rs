@@ -0,0 +1,114 @@
+// Frameworks among the dependencies.
+//
+// A library living inside a framework, e.g.
+// `/opt/homebrew/opt/qt/lib/QtCore.framework/Versions/A/QtCore`, is not copied
+// as a bare file. The whole framework folder is reproduced in the libraries
+// folder with its `Versions/Current` links, resources and headers, the binary
+// is then named `@rpath/QtCore.framework/Versions/A/QtCore` and the version
+// folder is signed as a whole.
+
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framework {
+    /// The `.framework` folder.
+    pub root: PathBuf,
+    /// Path of the binary inside `root`, `Versions/A/QtCore` or `QtCore` for
+    /// the flat layout.
+    pub binary: PathBuf,
+}
+
+impl Framework {
+    /// The framework `file_path` is the binary of, if any. The path may be
+    /// relative.
+    pub fn of(file_path: &Path) -> Option<Self> {
+        let name = file_path.file_name()?;
+        let root = file_path
+            .ancestors()
+            .skip(1)
+            .take(3)
+            .find(|folder| folder.extension().is_some_and(|ext| ext == "framework"))?;
+        if root.file_stem()? != name {
+            return None;
+        }
+        let binary = file_path.strip_prefix(root).ok()?.to_path_buf();
+        let components: Vec<Component> = binary.components().collect();
+        let is_versioned = matches!(
+            components[..],
+            [Component::Normal(versions), Component::Normal(_), Component::Normal(_)]
+                if versions == "Versions"
+        );
+        if components.len() != 1 && !is_versioned {
+            return None;
+        }
+        Some(Framework {
+            root: root.to_path_buf(),
+            binary,
+        })
+    }
+
+    /// File name of the framework folder, e.g. `QtCore.framework`.
+    pub fn folder_name(&self) -> &Path {
+        Path::new(self.root.file_name().unwrap_or_default())
+    }
+
+    /// Folder signed as a whole, `Versions/A` or the framework itself.
+    pub fn signed_folder(&self) -> PathBuf {
+        match self.binary.parent() {
+            Some(parent) => self.root.join(parent),
+            None => self.root.clone(),
+        }
+    }
+
+    /// Install name of the binary inside a libraries folder.
+    pub fn install_name(&self) -> String {
+        format!("@rpath/{}", self.folder_name().join(&self.binary).display())
+    }
+}
+
+/// Name of the framework a folder belongs to, e.g. `QtCore` for
+/// `QtCore.framework/Versions/A`.
+pub fn framework_name(folder: &Path) -> Option<String> {
+    folder
+        .ancestors()
+        .take(3)
+        .find(|folder| folder.extension().is_some_and(|ext| ext == "framework"))
+        .and_then(Path::file_stem)
+        .map(|stem| stem.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framework_of() {
+        let framework =
+            Framework::of(Path::new("/opt/qt/lib/QtCore.framework/Versions/A/QtCore")).unwrap();
+        assert_eq!(framework.root, Path::new("/opt/qt/lib/QtCore.framework"));
+        assert_eq!(framework.binary, Path::new("Versions/A/QtCore"));
+        assert_eq!(
+            framework.signed_folder(),
+            Path::new("/opt/qt/lib/QtCore.framework/Versions/A")
+        );
+        assert_eq!(
+            framework.install_name(),
+            "@rpath/QtCore.framework/Versions/A/QtCore"
+        );
+
+        let flat = Framework::of(Path::new("libs/Foo.framework/Foo")).unwrap();
+        assert_eq!(flat.signed_folder(), Path::new("libs/Foo.framework"));
+
+        for path in [
+            "/opt/qt/lib/QtCore.framework/Versions/A/Resources/libfoo.dylib",
+            "/opt/qt/lib/QtCore.framework/Versions/A/Other",
+            "/opt/homebrew/lib/libpng16.16.dylib",
+        ] {
+            assert_eq!(Framework::of(Path::new(path)), None, "{}", path);
+        }
+        assert_eq!(
+            framework_name(Path::new("QtCore.framework/Versions/A")).as_deref(),
+            Some("QtCore")
+        );
+    }
+}
@@ -1,13 +1,20 @@
+use crate::codesign::sign_file;
+use crate::error::Error;
+use crate::macho::{
+    Dylib, FileKind, MH_DYLIB, MH_EXECUTE, MH_OBJECT, MachO, detect_kind, parse_header,
+    parse_slices,
+};
+use crate::resolver::{LoaderImage, Resolver};
+use crate::rewrite::{Edit, edit_file, edit_file_with};
 use anyhow::{Context, Result, anyhow};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
 
-// !!! TODO: This tool is not working with universal binaries! The reason is
-// that when we run "otool -l" or "otool -L" commands, we assume the output is
-// structured specifically for one arctitecture. But it turns out that if we
-// dealing with universal binaries, actually there are multiple dynamic library
-// groups or multiple LC_RPATH commands. So for now this tool is not expected
-// to work with universal binaries.
+// Universal binaries carry one set of load commands per architecture slice.
+// Every helper below reads all slices and merges their results, dependencies
+// and ids have to agree between slices while rpaths are simply collected
+// from all of them.
 
 #[derive(Debug, PartialEq)]
 pub enum BinType<'a> {
@@ -26,116 +33,170 @@ enum RPath<'a> {
     Unrecongized(&'a str),
 }
 
-pub fn get_load_commands(file_path: &Path) -> Result<String> {
-    let output = Command::new("otool")
-        .arg("-l")
-        .arg(file_path)
-        .output()
-        .context("Error while running otool for load commands")?;
-    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+/// Parses every architecture slice of the Mach-O file at `file_path`.
+pub fn read_slices(file_path: &Path) -> Result<Vec<MachO>> {
+    let data = std::fs::read(file_path).map_err(Error::io("reading", file_path))?;
+    if !matches!(detect_kind(&data), FileKind::MachO | FileKind::Fat) {
+        return Err(Error::UnsupportedBinary {
+            path: file_path.to_path_buf(),
+            reason: "Not a Mach-O file".to_string(),
+        }
+        .into());
+    }
+    parse_slices(&data).with_context(|| format!("Error while parsing: {}", file_path.display()))
 }
 
-pub fn get_shared_libs(file_path: &Path) -> Result<String> {
-    let output = Command::new("otool")
-        .arg("-L")
-        .arg(file_path)
-        .output()
-        .context("Error while running otool for shared libraries")?;
-    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+/// Dependencies of a binary. All architecture slices of a universal binary
+/// have to link against the same libraries, otherwise bundling one of them
+/// would leave the other slices broken.
+pub fn get_dependencies(file_path: &Path) -> Result<Vec<Dylib>> {
+    merge_dependencies(file_path, &read_slices(file_path)?)
 }
 
-// This function get all rpaths entries for a binary, convert to an absolute
-// path, check if the path is valid and return the first valid one. If there
-// is no, then returns Err.
-pub fn canonicalize_rpath(binary_path: &Path, rpath_install_name: &str) -> Result<PathBuf> {
-    let rpaths = get_rpaths(binary_path, false)?;
-    log::debug!("All rpaths: {:?}", rpaths);
-    let Some(binary_folder_path) = binary_path.parent() else {
-        return Err(anyhow!(
-            "Error while getting parent folder of: {}",
-            binary_path.display()
-        ));
+/// Dependencies of the already parsed `slices` of `file_path`, which have to
+/// agree between slices.
+pub fn merge_dependencies(file_path: &Path, slices: &[MachO]) -> Result<Vec<Dylib>> {
+    let Some((first, rest)) = slices.split_first() else {
+        return Err(anyhow!("No Mach-O slice found in: {}", file_path.display()));
     };
-    for rpath in rpaths {
-        let abs_rpath = binary_folder_path.join(rpath);
-        if !abs_rpath.exists() {
-            log::debug!(
-                "Rpath canonicalized but directory not exists: {}",
-                abs_rpath.display()
-            );
-            continue;
+
+    let dependencies: Vec<Dylib> = first.dependencies().cloned().collect();
+    let expected = dependency_set(first);
+    for macho in rest {
+        let found = dependency_set(macho);
+        if found != expected {
+            let missing: Vec<_> = expected.difference(&found).collect();
+            let extra: Vec<_> = found.difference(&expected).collect();
+            return Err(anyhow!(
+                "Architecture slices disagree on dependencies of: {}\n{} lacks: {:?}\n{} adds: {:?}",
+                file_path.display(),
+                macho.header.arch_name(),
+                missing,
+                macho.header.arch_name(),
+                extra
+            ));
         }
+    }
+    Ok(dependencies)
+}
 
-        let base_install_name = remove_rpath_prefix(rpath_install_name)?;
-        let abs_lib_path = abs_rpath.join(base_install_name);
-        log::debug!("Absolute path of library: {}", abs_lib_path.display());
+fn dependency_set(macho: &MachO) -> BTreeSet<(&str, &'static str)> {
+    macho
+        .dependencies()
+        .map(|d| (d.name.as_str(), d.kind.name()))
+        .collect()
+}
+
+/// Removes `.` and `..` components without touching the file system, the
+/// path may point to folders which don't exist. Leading `..` of a relative
+/// path are kept.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(component),
+            },
+            Component::CurDir => {}
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
 
-        if !abs_lib_path.exists() {
-            log::debug!("Library not exists: {}", abs_lib_path.display());
+/// Copies the contents of `from` into the existing folder `to`, except the
+/// paths in `skip`. Symlinks are copied as symlinks, frameworks rely on them.
+pub fn copy_tree(from: &Path, to: &Path, skip: &[PathBuf]) -> Result<()> {
+    for entry in std::fs::read_dir(from).map_err(Error::io("reading folder", from))? {
+        let entry = entry.map_err(Error::io("reading folder", from))?;
+        let source = entry.path();
+        if skip.contains(&source) {
+            log::debug!("Not keeping: {}", source.display());
             continue;
         }
-        log::debug!("Library exists: {}", abs_lib_path.display());
-        return Ok(abs_lib_path);
+        let dest = to.join(entry.file_name());
+        let file_type = entry.file_type().map_err(Error::io("reading", &source))?;
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&source).map_err(Error::io("reading link", &source))?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(&target, &dest)
+                .map_err(Error::io("creating link", &dest))?;
+            #[cfg(not(unix))]
+            std::fs::copy(from.join(&target), &dest).map_err(Error::io("copying", &source))?;
+        } else if file_type.is_dir() {
+            std::fs::create_dir(&dest).map_err(Error::io("creating folder", &dest))?;
+            copy_tree(&source, &dest, skip)?;
+        } else {
+            std::fs::copy(&source, &dest).map_err(Error::io("copying", &source))?;
+        }
     }
-    Err(anyhow!(
-        "Canonicalization failed for: {},\nRpath: {}",
-        binary_path.display(),
-        rpath_install_name,
-    ))
+    Ok(())
+}
+
+/// Whether `folder` belongs to macOS itself, e.g. `/usr/lib/swift`. Rpaths
+/// into these folders are kept in a bundle.
+pub fn is_system_folder(folder: &Path) -> bool {
+    folder.starts_with("/usr/lib") || folder.starts_with("/System")
+}
+
+// This function resolves an `@rpath/...` install name against the rpaths of
+// the binary referencing it, the same way dyld would if that binary was the
+// main executable. Returns Err if no rpath leads to an existing library.
+pub fn canonicalize_rpath(binary_path: &Path, rpath_install_name: &str) -> Result<PathBuf> {
+    let image = LoaderImage::read(binary_path)?;
+    log::debug!("All rpaths: {:?}", image.rpaths);
+    Resolver::new(binary_path)
+        .resolve(rpath_install_name, &[image])
+        .with_context(|| {
+            format!(
+                "Canonicalization failed for: {},\nRpath: {}",
+                binary_path.display(),
+                rpath_install_name,
+            )
+        })
 }
 
 pub fn get_rpaths(file_path: &Path, with_prefix: bool) -> Result<Vec<String>> {
     log::debug!("Searching @rpath values for: {}", file_path.display());
 
-    let output = get_load_commands(file_path)?;
+    let slices = read_slices(file_path)?;
     let mut rpath_list: Vec<String> = vec![];
 
-    const LINES_TO_SEARCH: i32 = 3;
-    let mut counter = 0;
-
-    for line in output.lines() {
-        let line = line.trim();
-
-        if line.contains("LC_RPATH") {
-            counter = LINES_TO_SEARCH;
-            continue;
-        }
-
-        if counter > 0 {
-            if line.starts_with("path") {
-                if let Some(rpath) = line.split_whitespace().nth(1) {
-                    log::debug!("@rpath value found: {}", rpath);
-                    let rpath = match check_rpath(rpath) {
-                        RPath::LoaderPath(rp) => {
-                            if with_prefix {
-                                rp
-                            } else {
-                                remove_loader_path_prefix(rp)?
-                            }
-                        }
-                        RPath::ExecutablePath(rp) => {
-                            if with_prefix {
-                                rp
-                            } else {
-                                remove_executable_path_prefix(rp)?
-                            }
-                        }
-                        RPath::Absolute(rp) => {
-                            log::debug!(
-                                "@rpath value is an absolute system path: {}\nskipping...",
-                                rp,
-                            );
-                            continue;
-                        }
-                        RPath::Unrecongized(rp) => {
-                            log::debug!("Unreconized @rpath value: {}", rp);
-                            continue;
-                        }
-                    };
-                    rpath_list.push(rpath.to_string());
+    for rpath in slices.iter().flat_map(|macho| macho.rpaths()) {
+        log::debug!("@rpath value found: {}", rpath);
+        let rpath = match check_rpath(rpath) {
+            RPath::LoaderPath(rp) => {
+                if with_prefix {
+                    rp
+                } else {
+                    remove_loader_path_prefix(rp)?
                 }
             }
-            counter -= 1;
+            RPath::ExecutablePath(rp) => {
+                if with_prefix {
+                    rp
+                } else {
+                    remove_executable_path_prefix(rp)?
+                }
+            }
+            RPath::Absolute(rp) => {
+                log::debug!(
+                    "@rpath value is an absolute system path: {}\nskipping...",
+                    rp,
+                );
+                continue;
+            }
+            RPath::Unrecongized(rp) => {
+                log::debug!("Unreconized @rpath value: {}", rp);
+                continue;
+            }
+        };
+        if !rpath_list.iter().any(|rp| rp == rpath) {
+            rpath_list.push(rpath.to_string());
         }
     }
     if rpath_list.is_empty() {
@@ -188,13 +249,6 @@ fn remove_executable_path_prefix(value: &str) -> Result<&str> {
     remove_prefix(value, prefix)
 }
 
-fn remove_rpath_prefix(value: &str) -> Result<&str> {
-    let prefix = "@rpath/";
-    remove_prefix(value, prefix)
-}
-
-// [-] TEST: Not tested yet.
-// [+] TODO: Not implemented yet.
 pub fn get_id(file_path: &Path) -> Result<String> {
     let file_path = match check_file_type(file_path)? {
         BinType::Dylib(fp) => fp,
@@ -206,48 +260,39 @@ pub fn get_id(file_path: &Path) -> Result<String> {
         }
     };
 
-    let output = Command::new("otool").arg("-D").arg(file_path).output()?;
-
-    // !!! TODO:
-    // This implementation is not compatible with universal binaries.
-    // It is assumed that we have two lines like:
-    //      foo.dylib:
-    //      @rpath/foo.dylib
-    // However in universal binaries there could be more than two such as:
-    //      foobar (architecture x86_64):
-    //      @rpath/foobar.framework/Versions/A/foobar
-    //      foobar (architecture arm64):
-    //      @rpath/foobar.framework/Versions/A/foobar
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let Some(id) = stdout.lines().nth(1) else {
+    merge_id(file_path, &read_slices(file_path)?)
+}
+
+/// Id of the already parsed `slices` of `file_path`, which has to agree
+/// between slices.
+pub fn merge_id(file_path: &Path, slices: &[MachO]) -> Result<String> {
+    let mut ids = slices
+        .iter()
+        .map(|macho| macho.id().map(|d| d.name.as_str()));
+    let Some(Some(id)) = ids.next() else {
         return Err(anyhow!(
             "No id found in the library: {}",
             file_path.display()
         ));
     };
+    if let Some(other) = ids.find(|other| *other != Some(id)) {
+        return Err(anyhow!(
+            "Architecture slices disagree on the id of: {}\n{} vs {}",
+            file_path.display(),
+            id,
+            other.unwrap_or("<none>")
+        ));
+    }
 
     log::debug!("Id found in: {}\nId: {}", file_path.display(), id);
     Ok(id.to_string())
 }
 
 pub fn fix_id(file_path: &Path, id: &str) -> Result<()> {
-    let output = Command::new("install_name_tool")
-        .arg("-id")
-        .arg(id)
-        .arg(file_path)
-        .output()?;
-    if output.status.success() {
-        log::info!("Id is set for: {}", file_path.display());
-        log::info!("Id is set to: {}", id);
-    } else {
-        let err = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!(
-            "Error while setting id for: {}\n{}",
-            file_path.display(),
-            err
-        ));
-    }
+    edit_file(file_path, &[Edit::SetId(id.to_string())])
+        .with_context(|| format!("Error while setting id for: {}", file_path.display()))?;
+    log::info!("Id is set for: {}", file_path.display());
+    log::info!("Id is set to: {}", id);
     Ok(())
 }
 
@@ -255,80 +300,66 @@ pub fn fix_id(file_path: &Path, id: &str) -> Result<()> {
 // Otherwise this command will change the binary already in use and this
 // most probably breaks the dependency cycle.
 pub fn fix_install_name(file_path: &Path, old: &str, new: &str) -> Result<()> {
-    let output = Command::new("install_name_tool")
-        .arg("-change")
-        .arg(old)
-        .arg(new)
-        .arg(file_path)
-        .output()?;
-    if output.status.success() {
-        log::info!("install_name changed for: {}", file_path.display());
-        log::info!("Install name was: {}", old);
-        log::info!("Install name is now: {}", old);
-    } else {
-        let err = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!(
-            "Error while changing install name for: {}\n{}",
-            file_path.display(),
-            err
-        ));
-    }
+    let edit = Edit::ChangeInstallName {
+        old: old.to_string(),
+        new: new.to_string(),
+    };
+    edit_file(file_path, &[edit]).with_context(|| {
+        format!(
+            "Error while changing install name for: {}",
+            file_path.display()
+        )
+    })?;
+    log::info!("install_name changed for: {}", file_path.display());
+    log::info!("Install name was: {}", old);
+    log::info!("Install name is now: {}", new);
     Ok(())
 }
 
-// [-] TODO: Not implemented yet.
-// Considering to add a support to remove old rpaths unnecessary.
-// pub fn remove_rpath(file_path: &Path, old_rpath: &str) -> Result<()> {
-//     todo!()
-// }
-
-pub fn add_rpath(file_path: &Path, new_rpath: &Path) -> Result<()> {
-    let output = Command::new("install_name_tool")
-        .arg("-add_rpath")
-        .arg(new_rpath)
-        .arg(file_path)
-        .output()?;
-    if output.status.success() {
-        log::debug!(
-            "Rpath added to: {}\nnew rpath: {}",
-            file_path.display(),
-            new_rpath.display()
-        );
-    } else {
-        let err = String::from_utf8_lossy(&output.stderr);
-        if err.contains("file already has LC_RPATH") {
-            log::debug!("Already has a valid rpath: {}", file_path.display());
-            return Ok(());
-        }
-        return Err(anyhow!(
-            "Error while adding rpath to: {}\n{}",
-            file_path.display(),
-            err
-        ));
-    }
+pub fn remove_rpath(file_path: &Path, old_rpath: &str) -> Result<()> {
+    edit_file(file_path, &[Edit::DeleteRpath(old_rpath.to_string())])
+        .with_context(|| format!("Error while removing rpath from: {}", file_path.display()))?;
+    log::debug!(
+        "Rpath removed from: {}\nold rpath: {}",
+        file_path.display(),
+        old_rpath
+    );
     Ok(())
 }
 
+/// Adds `new_rpath` to every slice missing it. Returns false if there was
+/// nothing to add.
+pub fn add_rpath(file_path: &Path, new_rpath: &Path) -> Result<bool> {
+    let new_rpath = new_rpath.to_string_lossy();
+    let has_rpath = |macho: &MachO| macho.rpaths().any(|rp| rp == new_rpath);
+    if read_slices(file_path)?.iter().all(has_rpath) {
+        log::debug!("Already has a valid rpath: {}", file_path.display());
+        return Ok(false);
+    }
+    edit_file_with(file_path, |macho| {
+        if has_rpath(macho) {
+            vec![]
+        } else {
+            vec![Edit::AddRpath(new_rpath.to_string())]
+        }
+    })
+    .with_context(|| format!("Error while adding rpath to: {}", file_path.display()))?;
+    log::debug!(
+        "Rpath added to: {}\nnew rpath: {}",
+        file_path.display(),
+        new_rpath
+    );
+    Ok(true)
+}
+
 // DONE
 pub fn sign_binary(file_path: &Path) -> Result<()> {
-    let output = Command::new("codesign")
-        .arg("--force")
-        .arg("--sign")
-        .arg("-")
-        .arg(file_path)
-        .output()?;
-    if output.status.success() {
-        log::info!("Binary singned successfully: {}", file_path.display());
-        return Ok(());
-    } else {
-        return Err(anyhow!(
-            "Error while signing binary: {}",
-            file_path.display()
-        ));
-    }
+    sign_file(file_path)
+        .with_context(|| format!("Error while signing binary: {}", file_path.display()))?;
+    log::info!("Binary singned successfully: {}", file_path.display());
+    Ok(())
 }
 
-// [-] Change name to `check_file_type`
 pub fn check_file_type(file_path: &'_ Path) -> Result<BinType<'_>> {
     if !file_path.exists() {
         return Err(anyhow!(
@@ -337,32 +368,53 @@ pub fn check_file_type(file_path: &'_ Path) -> Result<BinType<'_>> {
         ));
     }
 
-    let output = Command::new("file").arg(file_path).output()?;
+    // Magic bytes and the Mach-O header are all we need to classify the file.
+    let mut data = Vec::with_capacity(32);
+    std::fs::File::open(file_path)
+        .and_then(|file| file.take(32).read_to_end(&mut data))
+        .map_err(Error::io("opening", file_path))?;
 
-    if !output.status.success() {
-        return Err(anyhow!("File command failed on: {}", file_path.display()));
-    }
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    if stdout.contains("Mach-O") && stdout.contains("executable") {
-        log::debug!("File is an executable: {}", file_path.display());
-        Ok(BinType::Executable(file_path))
-    } else if stdout.contains("Mach-O") && stdout.contains("dynamically linked shared") {
-        log::debug!("File is a dynamic library: {}", file_path.display());
-        Ok(BinType::Dylib(file_path))
-    } else if stdout.contains("ar archive") {
-        log::debug!("File is a static library: {}", file_path.display());
-        Ok(BinType::StaticLib(file_path))
-    } else if stdout.contains("Mach-O") && stdout.contains("object") {
-        log::debug!("File is an object file: {}", file_path.display());
-        Ok(BinType::ObjectFile(file_path))
-    } else if stdout.contains("Mach-O") && stdout.contains("universal") {
-        log::debug!("File is a universal binary file: {}", file_path.display());
-        Ok(BinType::UBinary(file_path))
-    } else {
-        log::warn!("File not recognized: {}", file_path.display());
-        log::warn!("Stdout: {}", stdout);
-        Ok(BinType::Unrecognized(file_path))
+    match detect_kind(&data) {
+        FileKind::Archive => {
+            log::debug!("File is a static library: {}", file_path.display());
+            Ok(BinType::StaticLib(file_path))
+        }
+        FileKind::Fat => {
+            log::debug!("File is a universal binary file: {}", file_path.display());
+            // The fat header alone doesn't tell what the slices are, so look
+            // at all of them. Mixed universal files stay unrecognized.
+            let slices = read_slices(file_path)?;
+            if slices.iter().all(MachO::is_executable) {
+                Ok(BinType::Executable(file_path))
+            } else if slices.iter().all(MachO::is_dylib) {
+                Ok(BinType::Dylib(file_path))
+            } else {
+                Ok(BinType::UBinary(file_path))
+            }
+        }
+        FileKind::MachO => match parse_header(&data)?.filetype {
+            MH_EXECUTE => {
+                log::debug!("File is an executable: {}", file_path.display());
+                Ok(BinType::Executable(file_path))
+            }
+            MH_DYLIB => {
+                log::debug!("File is a dynamic library: {}", file_path.display());
+                Ok(BinType::Dylib(file_path))
+            }
+            MH_OBJECT => {
+                log::debug!("File is an object file: {}", file_path.display());
+                Ok(BinType::ObjectFile(file_path))
+            }
+            filetype => {
+                log::warn!("File not recognized: {}", file_path.display());
+                log::warn!("Mach-O file type: 0x{:x}", filetype);
+                Ok(BinType::Unrecognized(file_path))
+            }
+        },
+        FileKind::Unknown => {
+            log::warn!("File not recognized: {}", file_path.display());
+            Ok(BinType::Unrecognized(file_path))
+        }
     }
 }
 
@@ -411,19 +463,41 @@ mod tests {
             let res = check_file_type(&file);
             assert!(res.is_err());
         }
+
+        #[test]
+        fn test_check_input_file_5() {
+            let folder = tempfile::tempdir().unwrap();
+            let err = check_file_type(folder.path()).unwrap_err();
+            assert_eq!(crate::error::exit_code(&err), 15);
+            assert!(
+                err.to_string()
+                    .contains(&folder.path().display().to_string())
+            );
+        }
     }
 
     mod tests_sign_binary {
         use super::*;
+
+        // Signing rewrites the file, so work on a copy of the resource.
+        fn _copy_to_temp(relative: &str, dir: &Path) -> PathBuf {
+            let src = _get_resource_path(relative);
+            let dest = dir.join(src.file_name().unwrap());
+            std::fs::copy(&src, &dest).unwrap();
+            dest
+        }
+
         #[test]
         fn test_sign_binary_1() {
-            let file = _get_resource_path(EXECUTABLE_BINARY);
+            let dir = tempfile::tempdir().unwrap();
+            let file = _copy_to_temp(EXECUTABLE_BINARY, dir.path());
             let res = sign_binary(&file);
             assert!(res.is_ok());
         }
         #[test]
         fn test_sign_binary_2() {
-            let file = _get_resource_path(DYLIB_BINARY_1);
+            let dir = tempfile::tempdir().unwrap();
+            let file = _copy_to_temp(DYLIB_BINARY_1, dir.path());
             let res = sign_binary(&file);
             assert!(res.is_ok());
         }
@@ -440,9 +514,7 @@ mod tests {
         #[test]
         fn test_get_rpats_1() {
             let file = _get_resource_path(EXECUTABLE_BINARY);
-            let mut expected: Vec<String> = Vec::new();
-            expected.push("../lib".to_string());
-            expected.push("libs".to_string());
+            let expected: Vec<String> = vec!["../lib".to_string(), "libs".to_string()];
             let res = get_rpaths(&file, false);
             assert!(res.is_ok());
             assert_eq!(res.unwrap(), expected);
@@ -451,9 +523,10 @@ mod tests {
         #[test]
         fn test_get_rpats_2() {
             let file = _get_resource_path(EXECUTABLE_BINARY);
-            let mut expected: Vec<String> = Vec::new();
-            expected.push("@loader_path/../lib".to_string());
-            expected.push("@loader_path/libs".to_string());
+            let expected: Vec<String> = vec![
+                "@loader_path/../lib".to_string(),
+                "@loader_path/libs".to_string(),
+            ];
             let res = get_rpaths(&file, true);
             assert!(res.is_ok());
             assert_eq!(res.unwrap(), expected);
@@ -462,14 +535,36 @@ mod tests {
         #[test]
         fn test_get_rpats_3() {
             let file = _get_resource_path(DYLIB_BINARY_1);
-            let mut expected: Vec<String> = Vec::new();
-            expected.push("@loader_path".to_string());
+            let expected: Vec<String> = vec!["@loader_path".to_string()];
             let res = get_rpaths(&file, true);
             assert!(res.is_ok());
             assert_eq!(res.unwrap(), expected);
         }
     }
 
+    mod tests_normalize_path {
+        use super::*;
+
+        #[test]
+        fn test_normalize_path_1() {
+            assert_eq!(normalize_path(Path::new("/a/./b/../c")), Path::new("/a/c"));
+            assert_eq!(normalize_path(Path::new("/a/../../b")), Path::new("/b"));
+        }
+
+        #[test]
+        fn test_normalize_path_2() {
+            assert_eq!(normalize_path(Path::new("libs/../lib")), Path::new("lib"));
+            assert_eq!(
+                normalize_path(Path::new("../../libs")),
+                Path::new("../../libs")
+            );
+            assert_eq!(
+                normalize_path(Path::new("a/../../libs")),
+                Path::new("../libs")
+            );
+        }
+    }
+
     mod tests_canonicalize_rpath {
         use super::*;
 
@@ -513,4 +608,61 @@ mod tests {
             assert_eq!(res.unwrap(), expected);
         }
     }
+
+    mod tests_universal {
+        use super::*;
+        use crate::macho::{build_fat, tests::_fake_x86_64};
+        use crate::rewrite::apply_edits;
+
+        fn _write_fat(dir: &Path, x86_64: &[u8], arm64: &[u8]) -> PathBuf {
+            let file = dir.join("libfreetype.6.dylib");
+            std::fs::write(&file, build_fat(&[(x86_64, 12), (arm64, 14)]).unwrap()).unwrap();
+            file
+        }
+
+        #[test]
+        fn test_universal_dylib() {
+            let arm64 = std::fs::read(_get_resource_path(DYLIB_BINARY_2)).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let file = _write_fat(dir.path(), &_fake_x86_64(&arm64), &arm64);
+
+            assert_eq!(check_file_type(&file).unwrap(), BinType::Dylib(&file));
+            assert_eq!(get_id(&file).unwrap(), "@rpath/libfreetype.6.dylib");
+            let deps = get_dependencies(&file).unwrap();
+            assert!(deps.iter().any(|d| d.name == "@rpath/libpng16.16.dylib"));
+        }
+
+        #[test]
+        fn test_universal_slices_disagree() {
+            let arm64 = std::fs::read(_get_resource_path(DYLIB_BINARY_2)).unwrap();
+            let mut x86_64 = _fake_x86_64(&arm64);
+            let edit = Edit::ChangeInstallName {
+                old: "@rpath/libpng16.16.dylib".to_string(),
+                new: "@rpath/libpng16.15.dylib".to_string(),
+            };
+            apply_edits(&mut x86_64, &[edit]).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let file = _write_fat(dir.path(), &x86_64, &arm64);
+
+            let err = get_dependencies(&file).unwrap_err().to_string();
+            assert!(err.contains("disagree"), "{}", err);
+        }
+
+        #[test]
+        fn test_universal_add_rpath() {
+            let arm64 = std::fs::read(_get_resource_path(DYLIB_BINARY_2)).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let file = _write_fat(dir.path(), &_fake_x86_64(&arm64), &arm64);
+
+            add_rpath(&file, Path::new("@loader_path/../Frameworks")).unwrap();
+            add_rpath(&file, Path::new("@loader_path/../Frameworks")).unwrap();
+            for macho in read_slices(&file).unwrap() {
+                let count = macho
+                    .rpaths()
+                    .filter(|rp| *rp == "@loader_path/../Frameworks")
+                    .count();
+                assert_eq!(count, 1);
+            }
+        }
+    }
 }
@@ -0,0 +1,28 @@
+// Macbinbundler copies an executable or a dynamic library together with all
+// the libraries it depends on into a folder and rewrites their load commands
+// so that the result is portable. `Bundler` is the entry point, the other
+// modules are exposed for tools which need finer control.
+
+pub mod app;
+pub mod backend;
+pub mod bundler;
+pub mod codesign;
+pub mod deployment;
+pub mod error;
+pub mod export;
+pub mod framework;
+pub mod helpers;
+pub mod macho;
+pub mod model;
+pub mod plan;
+pub mod policy;
+pub mod report;
+pub mod resolver;
+pub mod rewrite;
+pub mod symbols;
+pub mod sysroot;
+pub mod verify;
+
+pub use bundler::Bundler;
+pub use error::Error;
+pub use report::BundleReport;
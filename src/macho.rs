@@ -0,0 +1,1087 @@
+// Native Mach-O reader.
+//
+// Parses the Mach-O header and load commands straight from the file instead
+// of scraping the text output of `otool -l` / `otool -L`. Only the load
+// commands this tool cares about are decoded into typed structs, anything
+// else is kept as `Command::Other` together with its position so that it can
+// be carried around untouched.
+
+use crate::error::Error;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const MH_MAGIC: u32 = 0xfeed_face;
+pub const MH_CIGAM: u32 = 0xcefa_edfe;
+pub const MH_MAGIC_64: u32 = 0xfeed_facf;
+pub const MH_CIGAM_64: u32 = 0xcffa_edfe;
+pub const FAT_MAGIC: u32 = 0xcafe_babe;
+pub const FAT_MAGIC_64: u32 = 0xcafe_babf;
+pub const AR_MAGIC: &[u8; 8] = b"!<arch>\n";
+
+pub const MH_OBJECT: u32 = 0x1;
+pub const MH_EXECUTE: u32 = 0x2;
+pub const MH_DYLIB: u32 = 0x6;
+pub const MH_BUNDLE: u32 = 0x8;
+
+pub const MH_TWOLEVEL: u32 = 0x80;
+
+pub const LC_REQ_DYLD: u32 = 0x8000_0000;
+pub const LC_SEGMENT: u32 = 0x1;
+pub const LC_SYMTAB: u32 = 0x2;
+pub const LC_LOAD_DYLIB: u32 = 0xc;
+pub const LC_ID_DYLIB: u32 = 0xd;
+pub const LC_LOAD_WEAK_DYLIB: u32 = 0x18 | LC_REQ_DYLD;
+pub const LC_SEGMENT_64: u32 = 0x19;
+pub const LC_UUID: u32 = 0x1b;
+pub const LC_RPATH: u32 = 0x1c | LC_REQ_DYLD;
+pub const LC_CODE_SIGNATURE: u32 = 0x1d;
+pub const LC_REEXPORT_DYLIB: u32 = 0x1f | LC_REQ_DYLD;
+pub const LC_LAZY_LOAD_DYLIB: u32 = 0x20;
+pub const LC_DYLD_INFO: u32 = 0x22;
+pub const LC_DYLD_INFO_ONLY: u32 = 0x22 | LC_REQ_DYLD;
+pub const LC_LOAD_UPWARD_DYLIB: u32 = 0x23 | LC_REQ_DYLD;
+pub const LC_VERSION_MIN_MACOSX: u32 = 0x24;
+pub const LC_VERSION_MIN_IPHONEOS: u32 = 0x25;
+pub const LC_VERSION_MIN_TVOS: u32 = 0x2f;
+pub const LC_VERSION_MIN_WATCHOS: u32 = 0x30;
+pub const LC_BUILD_VERSION: u32 = 0x32;
+pub const LC_DYLD_EXPORTS_TRIE: u32 = 0x33 | LC_REQ_DYLD;
+pub const LC_DYLD_CHAINED_FIXUPS: u32 = 0x34 | LC_REQ_DYLD;
+
+pub const PLATFORM_MACOS: u32 = 1;
+pub const PLATFORM_IOS: u32 = 2;
+pub const PLATFORM_TVOS: u32 = 3;
+pub const PLATFORM_WATCHOS: u32 = 4;
+pub const PLATFORM_MACCATALYST: u32 = 6;
+pub const PLATFORM_IOSSIMULATOR: u32 = 7;
+
+pub const CPU_ARCH_ABI64: i32 = 0x0100_0000;
+pub const CPU_TYPE_X86: i32 = 7;
+pub const CPU_TYPE_X86_64: i32 = CPU_TYPE_X86 | CPU_ARCH_ABI64;
+pub const CPU_TYPE_ARM: i32 = 12;
+pub const CPU_TYPE_ARM64: i32 = CPU_TYPE_ARM | CPU_ARCH_ABI64;
+pub const CPU_TYPE_POWERPC: i32 = 18;
+
+/// Kind of file detected from the leading magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    MachO,
+    Fat,
+    Archive,
+    Unknown,
+}
+
+/// Entry of the fat header describing one architecture slice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatArch {
+    pub cputype: i32,
+    pub cpusubtype: i32,
+    pub offset: u64,
+    pub size: u64,
+    pub align: u32,
+}
+
+impl FatArch {
+    pub fn arch_name(&self) -> &'static str {
+        arch_name(self.cputype, self.cpusubtype)
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.size) as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachHeader {
+    pub magic: u32,
+    pub cputype: i32,
+    pub cpusubtype: i32,
+    pub filetype: u32,
+    pub ncmds: u32,
+    pub sizeofcmds: u32,
+    pub flags: u32,
+    pub is_64: bool,
+    pub little_endian: bool,
+}
+
+impl MachHeader {
+    /// Size of the header itself, load commands start right after it.
+    pub fn size(&self) -> usize {
+        if self.is_64 { 32 } else { 28 }
+    }
+
+    pub fn arch_name(&self) -> &'static str {
+        arch_name(self.cputype, self.cpusubtype)
+    }
+}
+
+/// Packed `xxxx.yy.zz` version as stored in dylib and build version commands.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct Version(pub u32);
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Version((major << 16) | ((minor & 0xff) << 8) | (patch & 0xff))
+    }
+
+    pub fn major(&self) -> u32 {
+        self.0 >> 16
+    }
+    pub fn minor(&self) -> u32 {
+        (self.0 >> 8) & 0xff
+    }
+    pub fn patch(&self) -> u32 {
+        self.0 & 0xff
+    }
+}
+
+/// Parses `11`, `10.15` or `10.14.4`.
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = [0u32; 3];
+        for (index, part) in s.split('.').enumerate() {
+            let Some(slot) = parts.get_mut(index) else {
+                return Err(anyhow!("Invalid version: {}", s));
+            };
+            *slot = part
+                .parse()
+                .map_err(|_| anyhow!("Invalid version: {}", s))?;
+        }
+        if parts[0] > 0xffff || parts[1] > 0xff || parts[2] > 0xff {
+            return Err(anyhow!("Invalid version: {}", s));
+        }
+        Ok(Version::new(parts[0], parts[1], parts[2]))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major(), self.minor(), self.patch())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DylibKind {
+    Id,
+    Load,
+    Weak,
+    Reexport,
+    Lazy,
+    Upward,
+}
+
+impl DylibKind {
+    pub fn from_cmd(cmd: u32) -> Option<Self> {
+        match cmd {
+            LC_ID_DYLIB => Some(DylibKind::Id),
+            LC_LOAD_DYLIB => Some(DylibKind::Load),
+            LC_LOAD_WEAK_DYLIB => Some(DylibKind::Weak),
+            LC_REEXPORT_DYLIB => Some(DylibKind::Reexport),
+            LC_LAZY_LOAD_DYLIB => Some(DylibKind::Lazy),
+            LC_LOAD_UPWARD_DYLIB => Some(DylibKind::Upward),
+            _ => None,
+        }
+    }
+
+    pub fn cmd(&self) -> u32 {
+        match self {
+            DylibKind::Id => LC_ID_DYLIB,
+            DylibKind::Load => LC_LOAD_DYLIB,
+            DylibKind::Weak => LC_LOAD_WEAK_DYLIB,
+            DylibKind::Reexport => LC_REEXPORT_DYLIB,
+            DylibKind::Lazy => LC_LAZY_LOAD_DYLIB,
+            DylibKind::Upward => LC_LOAD_UPWARD_DYLIB,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DylibKind::Id => "LC_ID_DYLIB",
+            DylibKind::Load => "LC_LOAD_DYLIB",
+            DylibKind::Weak => "LC_LOAD_WEAK_DYLIB",
+            DylibKind::Reexport => "LC_REEXPORT_DYLIB",
+            DylibKind::Lazy => "LC_LAZY_LOAD_DYLIB",
+            DylibKind::Upward => "LC_LOAD_UPWARD_DYLIB",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dylib {
+    pub kind: DylibKind,
+    pub name: String,
+    pub timestamp: u32,
+    pub current_version: Version,
+    pub compatibility_version: Version,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkeditData {
+    pub dataoff: u32,
+    pub datasize: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symtab {
+    pub symoff: u32,
+    pub nsyms: u32,
+    pub stroff: u32,
+    pub strsize: u32,
+}
+
+/// Offsets of the compressed dyld info, only the parts needed to find
+/// imported and exported symbols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DyldInfo {
+    pub bind: LinkeditData,
+    pub lazy_bind: LinkeditData,
+    pub export: LinkeditData,
+}
+
+/// LC_BUILD_VERSION, the LC_VERSION_MIN_* commands of older toolchains are
+/// read as build versions without tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildVersion {
+    pub platform: u32,
+    pub minos: Version,
+    pub sdk: Version,
+    pub tools: Vec<(u32, Version)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub sectname: String,
+    pub segname: String,
+    pub addr: u64,
+    pub size: u64,
+    pub offset: u32,
+    pub align: u32,
+    pub flags: u32,
+}
+
+impl Section {
+    pub fn is_zerofill(&self) -> bool {
+        const SECTION_TYPE: u32 = 0xff;
+        const S_ZEROFILL: u32 = 0x1;
+        const S_GB_ZEROFILL: u32 = 0xc;
+        const S_THREAD_LOCAL_ZEROFILL: u32 = 0x12;
+        matches!(
+            self.flags & SECTION_TYPE,
+            S_ZEROFILL | S_GB_ZEROFILL | S_THREAD_LOCAL_ZEROFILL
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub segname: String,
+    pub vmaddr: u64,
+    pub vmsize: u64,
+    pub fileoff: u64,
+    pub filesize: u64,
+    pub maxprot: u32,
+    pub initprot: u32,
+    pub flags: u32,
+    pub sections: Vec<Section>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Dylib(Dylib),
+    Rpath(String),
+    CodeSignature(LinkeditData),
+    BuildVersion(BuildVersion),
+    Symtab(Symtab),
+    DyldInfo(DyldInfo),
+    ExportsTrie(LinkeditData),
+    ChainedFixups(LinkeditData),
+    Uuid([u8; 16]),
+    Segment(Segment),
+    Other,
+}
+
+/// A load command together with its location inside the Mach-O image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadCommand {
+    pub cmd: u32,
+    pub offset: usize,
+    pub cmdsize: u32,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachO {
+    pub header: MachHeader,
+    pub commands: Vec<LoadCommand>,
+}
+
+impl MachO {
+    pub fn parse(data: &[u8]) -> Result<MachO> {
+        let header = parse_header(data)?;
+        let le = header.little_endian;
+
+        let lc_end = header.size() + header.sizeofcmds as usize;
+        if lc_end > data.len() {
+            return Err(anyhow!(
+                "Load commands exceed file size: {} > {}",
+                lc_end,
+                data.len()
+            ));
+        }
+
+        // `ncmds` comes from the file, every command takes at least 8 bytes.
+        let mut commands =
+            Vec::with_capacity((header.ncmds as usize).min(header.sizeofcmds as usize / 8));
+        let mut offset = header.size();
+        for _ in 0..header.ncmds {
+            let cmd = read_u32(data, offset, le)?;
+            let cmdsize = read_u32(data, offset + 4, le)?;
+            if cmdsize < 8 || offset + cmdsize as usize > lc_end {
+                return Err(anyhow!(
+                    "Malformed load command 0x{:x} at offset {}",
+                    cmd,
+                    offset
+                ));
+            }
+            let raw = &data[offset..offset + cmdsize as usize];
+            let command = parse_command(cmd, raw, le, header.is_64)?;
+            commands.push(LoadCommand {
+                cmd,
+                offset,
+                cmdsize,
+                command,
+            });
+            offset += cmdsize as usize;
+        }
+
+        Ok(MachO { header, commands })
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.header.filetype == MH_EXECUTE
+    }
+
+    pub fn is_dylib(&self) -> bool {
+        self.header.filetype == MH_DYLIB
+    }
+
+    pub fn is_bundle(&self) -> bool {
+        self.header.filetype == MH_BUNDLE
+    }
+
+    /// LC_ID_DYLIB of a dynamic library.
+    pub fn id(&self) -> Option<&Dylib> {
+        self.all_dylibs().find(|d| d.kind == DylibKind::Id)
+    }
+
+    /// Every dylib this image links against, in load command order which is
+    /// also the order of the two-level namespace library ordinals.
+    pub fn dependencies(&self) -> impl Iterator<Item = &Dylib> {
+        self.all_dylibs().filter(|d| d.kind != DylibKind::Id)
+    }
+
+    pub fn rpaths(&self) -> impl Iterator<Item = &str> {
+        self.commands.iter().filter_map(|lc| match lc.command {
+            Command::Rpath(ref path) => Some(path.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        self.commands.iter().find_map(|lc| match lc.command {
+            Command::Uuid(uuid) => Some(uuid),
+            _ => None,
+        })
+    }
+
+    pub fn code_signature(&self) -> Option<&LinkeditData> {
+        self.commands.iter().find_map(|lc| match lc.command {
+            Command::CodeSignature(ref cs) => Some(cs),
+            _ => None,
+        })
+    }
+
+    pub fn build_version(&self) -> Option<&BuildVersion> {
+        self.build_versions().next()
+    }
+
+    /// Every platform the image was built for, a zippered library carries
+    /// both macOS and Mac Catalyst.
+    pub fn build_versions(&self) -> impl Iterator<Item = &BuildVersion> {
+        self.commands.iter().filter_map(|lc| match lc.command {
+            Command::BuildVersion(ref bv) => Some(bv),
+            _ => None,
+        })
+    }
+
+    pub fn symtab(&self) -> Option<&Symtab> {
+        self.commands.iter().find_map(|lc| match lc.command {
+            Command::Symtab(ref symtab) => Some(symtab),
+            _ => None,
+        })
+    }
+
+    pub fn dyld_info(&self) -> Option<&DyldInfo> {
+        self.commands.iter().find_map(|lc| match lc.command {
+            Command::DyldInfo(ref info) => Some(info),
+            _ => None,
+        })
+    }
+
+    pub fn exports_trie(&self) -> Option<&LinkeditData> {
+        self.commands.iter().find_map(|lc| match lc.command {
+            Command::ExportsTrie(ref trie) => Some(trie),
+            _ => None,
+        })
+    }
+
+    pub fn chained_fixups(&self) -> Option<&LinkeditData> {
+        self.commands.iter().find_map(|lc| match lc.command {
+            Command::ChainedFixups(ref fixups) => Some(fixups),
+            _ => None,
+        })
+    }
+
+    /// Whether imported symbols are bound to a library ordinal rather than
+    /// looked up in every loaded image.
+    pub fn is_two_level(&self) -> bool {
+        self.header.flags & MH_TWOLEVEL != 0
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.commands.iter().filter_map(|lc| match lc.command {
+            Command::Segment(ref seg) => Some(seg),
+            _ => None,
+        })
+    }
+
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments().find(|seg| seg.segname == name)
+    }
+
+    /// End of the load command region.
+    pub fn load_commands_end(&self) -> usize {
+        self.header.size() + self.header.sizeofcmds as usize
+    }
+
+    /// File offset of the first byte of section or segment content, i.e. the
+    /// limit the load command region is allowed to grow to.
+    pub fn content_start(&self, file_size: usize) -> usize {
+        let mut start = file_size;
+        for segment in self.segments() {
+            if segment.fileoff > 0 && segment.filesize > 0 {
+                start = start.min(segment.fileoff as usize);
+            }
+            for section in &segment.sections {
+                if section.offset > 0 && section.size > 0 && !section.is_zerofill() {
+                    start = start.min(section.offset as usize);
+                }
+            }
+        }
+        start
+    }
+
+    /// Free bytes between the end of the load commands and the first content.
+    pub fn header_padding(&self, file_size: usize) -> usize {
+        self.content_start(file_size)
+            .saturating_sub(self.load_commands_end())
+    }
+
+    fn all_dylibs(&self) -> impl Iterator<Item = &Dylib> {
+        self.commands.iter().filter_map(|lc| match lc.command {
+            Command::Dylib(ref dylib) => Some(dylib),
+            _ => None,
+        })
+    }
+}
+
+pub fn detect_kind(data: &[u8]) -> FileKind {
+    if data.len() >= AR_MAGIC.len() && &data[..AR_MAGIC.len()] == AR_MAGIC {
+        return FileKind::Archive;
+    }
+    if data.len() < 8 {
+        return FileKind::Unknown;
+    }
+    let magic = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    match magic {
+        MH_MAGIC | MH_CIGAM | MH_MAGIC_64 | MH_CIGAM_64 => FileKind::MachO,
+        // Java class files share the fat magic, but their second word is a
+        // class file version which is way larger than any sane slice count.
+        FAT_MAGIC | FAT_MAGIC_64 => {
+            let nfat_arch = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            if nfat_arch > 0 && nfat_arch < 0x20 {
+                FileKind::Fat
+            } else {
+                FileKind::Unknown
+            }
+        }
+        _ => FileKind::Unknown,
+    }
+}
+
+/// Reads the first few bytes of a file to find out what kind of file it is.
+pub fn detect_file_kind(file_path: &Path) -> Result<FileKind> {
+    use std::io::Read;
+    let mut buf = Vec::with_capacity(8);
+    std::fs::File::open(file_path)
+        .and_then(|file| file.take(8).read_to_end(&mut buf))
+        .map_err(Error::io("opening", file_path))?;
+    Ok(detect_kind(&buf))
+}
+
+/// Parses the fat header of a universal binary.
+pub fn parse_fat_header(data: &[u8]) -> Result<Vec<FatArch>> {
+    let magic = read_u32(data, 0, false)?;
+    let is_64 = match magic {
+        FAT_MAGIC => false,
+        FAT_MAGIC_64 => true,
+        _ => return Err(anyhow!("Not a universal binary, magic: 0x{:08x}", magic)),
+    };
+    let nfat_arch = read_u32(data, 4, false)? as usize;
+    let entry_size = if is_64 { 32 } else { 20 };
+
+    let mut arches = Vec::with_capacity(nfat_arch.min(data.len() / entry_size));
+    for i in 0..nfat_arch {
+        let off = 8 + i * entry_size;
+        let arch = if is_64 {
+            FatArch {
+                cputype: read_u32(data, off, false)? as i32,
+                cpusubtype: read_u32(data, off + 4, false)? as i32,
+                offset: read_u64(data, off + 8, false)?,
+                size: read_u64(data, off + 16, false)?,
+                align: read_u32(data, off + 24, false)?,
+            }
+        } else {
+            FatArch {
+                cputype: read_u32(data, off, false)? as i32,
+                cpusubtype: read_u32(data, off + 4, false)? as i32,
+                offset: read_u32(data, off + 8, false)? as u64,
+                size: read_u32(data, off + 12, false)? as u64,
+                align: read_u32(data, off + 16, false)?,
+            }
+        };
+        let end = arch.offset.checked_add(arch.size);
+        if end.is_none_or(|end| end > data.len() as u64) {
+            return Err(anyhow!(
+                "Slice {} exceeds file size: {}+{} > {}",
+                arch.arch_name(),
+                arch.offset,
+                arch.size,
+                data.len()
+            ));
+        }
+        arches.push(arch);
+    }
+    Ok(arches)
+}
+
+/// Byte ranges of every Mach-O image in a thin or universal file.
+pub fn slice_ranges(data: &[u8]) -> Result<Vec<Range<usize>>> {
+    match detect_kind(data) {
+        FileKind::MachO => Ok(std::iter::once(0..data.len()).collect()),
+        FileKind::Fat => Ok(parse_fat_header(data)?.iter().map(FatArch::range).collect()),
+        _ => Err(anyhow!("Not a Mach-O file")),
+    }
+}
+
+/// Parses every architecture slice of a thin or universal file.
+pub fn parse_slices(data: &[u8]) -> Result<Vec<MachO>> {
+    slice_ranges(data)?
+        .into_iter()
+        .map(|range| MachO::parse(&data[range]))
+        .collect()
+}
+
+/// Slice alignment (as a power of two) `lipo` uses for a cpu type.
+pub fn default_align(cputype: i32) -> u32 {
+    match cputype {
+        CPU_TYPE_ARM64 | CPU_TYPE_ARM => 14,
+        _ => 12,
+    }
+}
+
+/// Assembles a universal binary from `(image, align)` pairs, placing every
+/// image at the next offset aligned to `2^align`.
+pub fn build_fat(slices: &[(&[u8], u32)]) -> Result<Vec<u8>> {
+    let mut arches = Vec::with_capacity(slices.len());
+    let mut offset = 8 + 20 * slices.len() as u64;
+    for (image, align) in slices {
+        let header = parse_header(image)?;
+        offset = offset.next_multiple_of(1 << align);
+        arches.push(FatArch {
+            cputype: header.cputype,
+            cpusubtype: header.cpusubtype,
+            offset,
+            size: image.len() as u64,
+            align: *align,
+        });
+        offset += image.len() as u64;
+    }
+    if offset > u32::MAX as u64 {
+        return Err(anyhow!(
+            "Universal binary too large for a 32-bit fat header"
+        ));
+    }
+
+    let mut data = vec![0u8; offset as usize];
+    write_u32(&mut data, 0, FAT_MAGIC, false)?;
+    write_u32(&mut data, 4, arches.len() as u32, false)?;
+    for (i, (arch, (image, _))) in arches.iter().zip(slices).enumerate() {
+        let off = 8 + i * 20;
+        write_u32(&mut data, off, arch.cputype as u32, false)?;
+        write_u32(&mut data, off + 4, arch.cpusubtype as u32, false)?;
+        write_u32(&mut data, off + 8, arch.offset as u32, false)?;
+        write_u32(&mut data, off + 12, arch.size as u32, false)?;
+        write_u32(&mut data, off + 16, arch.align, false)?;
+        data[arch.range()].copy_from_slice(image);
+    }
+    Ok(data)
+}
+
+pub fn parse_header(data: &[u8]) -> Result<MachHeader> {
+    if data.len() < 28 {
+        return Err(anyhow!("File too small for a Mach-O header"));
+    }
+    let magic = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    let (is_64, little_endian) = match magic {
+        MH_MAGIC => (false, false),
+        MH_CIGAM => (false, true),
+        MH_MAGIC_64 => (true, false),
+        MH_CIGAM_64 => (true, true),
+        FAT_MAGIC | FAT_MAGIC_64 => {
+            return Err(anyhow!("Universal binaries are not a single Mach-O image"));
+        }
+        _ => return Err(anyhow!("Not a Mach-O file, magic: 0x{:08x}", magic)),
+    };
+    let le = little_endian;
+    let header = MachHeader {
+        magic,
+        cputype: read_u32(data, 4, le)? as i32,
+        cpusubtype: read_u32(data, 8, le)? as i32,
+        filetype: read_u32(data, 12, le)?,
+        ncmds: read_u32(data, 16, le)?,
+        sizeofcmds: read_u32(data, 20, le)?,
+        flags: read_u32(data, 24, le)?,
+        is_64,
+        little_endian,
+    };
+    if data.len() < header.size() {
+        return Err(anyhow!("File too small for a Mach-O header"));
+    }
+    Ok(header)
+}
+
+fn parse_command(cmd: u32, raw: &[u8], le: bool, is_64: bool) -> Result<Command> {
+    if let Some(kind) = DylibKind::from_cmd(cmd) {
+        let name_offset = read_u32(raw, 8, le)? as usize;
+        return Ok(Command::Dylib(Dylib {
+            kind,
+            name: read_cstr(raw, name_offset)?,
+            timestamp: read_u32(raw, 12, le)?,
+            current_version: Version(read_u32(raw, 16, le)?),
+            compatibility_version: Version(read_u32(raw, 20, le)?),
+        }));
+    }
+
+    let command = match cmd {
+        LC_RPATH => {
+            let path_offset = read_u32(raw, 8, le)? as usize;
+            Command::Rpath(read_cstr(raw, path_offset)?)
+        }
+        LC_CODE_SIGNATURE => Command::CodeSignature(LinkeditData {
+            dataoff: read_u32(raw, 8, le)?,
+            datasize: read_u32(raw, 12, le)?,
+        }),
+        LC_SYMTAB => Command::Symtab(Symtab {
+            symoff: read_u32(raw, 8, le)?,
+            nsyms: read_u32(raw, 12, le)?,
+            stroff: read_u32(raw, 16, le)?,
+            strsize: read_u32(raw, 20, le)?,
+        }),
+        LC_DYLD_INFO | LC_DYLD_INFO_ONLY => {
+            let linkedit = |offset| -> Result<LinkeditData> {
+                Ok(LinkeditData {
+                    dataoff: read_u32(raw, offset, le)?,
+                    datasize: read_u32(raw, offset + 4, le)?,
+                })
+            };
+            Command::DyldInfo(DyldInfo {
+                bind: linkedit(16)?,
+                lazy_bind: linkedit(32)?,
+                export: linkedit(40)?,
+            })
+        }
+        LC_DYLD_EXPORTS_TRIE => Command::ExportsTrie(LinkeditData {
+            dataoff: read_u32(raw, 8, le)?,
+            datasize: read_u32(raw, 12, le)?,
+        }),
+        LC_DYLD_CHAINED_FIXUPS => Command::ChainedFixups(LinkeditData {
+            dataoff: read_u32(raw, 8, le)?,
+            datasize: read_u32(raw, 12, le)?,
+        }),
+        LC_UUID => {
+            let bytes = raw
+                .get(8..24)
+                .ok_or_else(|| anyhow!("Truncated LC_UUID command"))?;
+            let mut uuid = [0u8; 16];
+            uuid.copy_from_slice(bytes);
+            Command::Uuid(uuid)
+        }
+        LC_BUILD_VERSION => {
+            let ntools = read_u32(raw, 20, le)? as usize;
+            let mut tools = Vec::with_capacity(ntools.min(raw.len() / 8));
+            for i in 0..ntools {
+                let off = 24 + i * 8;
+                tools.push((
+                    read_u32(raw, off, le)?,
+                    Version(read_u32(raw, off + 4, le)?),
+                ));
+            }
+            Command::BuildVersion(BuildVersion {
+                platform: read_u32(raw, 8, le)?,
+                minos: Version(read_u32(raw, 12, le)?),
+                sdk: Version(read_u32(raw, 16, le)?),
+                tools,
+            })
+        }
+        LC_VERSION_MIN_MACOSX
+        | LC_VERSION_MIN_IPHONEOS
+        | LC_VERSION_MIN_TVOS
+        | LC_VERSION_MIN_WATCHOS => Command::BuildVersion(BuildVersion {
+            platform: match cmd {
+                LC_VERSION_MIN_MACOSX => PLATFORM_MACOS,
+                LC_VERSION_MIN_IPHONEOS => PLATFORM_IOS,
+                LC_VERSION_MIN_TVOS => PLATFORM_TVOS,
+                _ => PLATFORM_WATCHOS,
+            },
+            minos: Version(read_u32(raw, 8, le)?),
+            sdk: Version(read_u32(raw, 12, le)?),
+            tools: vec![],
+        }),
+        LC_SEGMENT_64 if is_64 => Command::Segment(parse_segment(raw, le, true)?),
+        LC_SEGMENT if !is_64 => Command::Segment(parse_segment(raw, le, false)?),
+        _ => Command::Other,
+    };
+    Ok(command)
+}
+
+fn parse_segment(raw: &[u8], le: bool, is_64: bool) -> Result<Segment> {
+    let segname = read_fixed_str(raw, 8, 16)?;
+    let (mut segment, nsects, mut off) = if is_64 {
+        let segment = Segment {
+            segname,
+            vmaddr: read_u64(raw, 24, le)?,
+            vmsize: read_u64(raw, 32, le)?,
+            fileoff: read_u64(raw, 40, le)?,
+            filesize: read_u64(raw, 48, le)?,
+            maxprot: read_u32(raw, 56, le)?,
+            initprot: read_u32(raw, 60, le)?,
+            flags: read_u32(raw, 68, le)?,
+            sections: vec![],
+        };
+        (segment, read_u32(raw, 64, le)?, 72)
+    } else {
+        let segment = Segment {
+            segname,
+            vmaddr: read_u32(raw, 24, le)? as u64,
+            vmsize: read_u32(raw, 28, le)? as u64,
+            fileoff: read_u32(raw, 32, le)? as u64,
+            filesize: read_u32(raw, 36, le)? as u64,
+            maxprot: read_u32(raw, 40, le)?,
+            initprot: read_u32(raw, 44, le)?,
+            flags: read_u32(raw, 52, le)?,
+            sections: vec![],
+        };
+        (segment, read_u32(raw, 48, le)?, 56)
+    };
+
+    for _ in 0..nsects {
+        let section = if is_64 {
+            let section = Section {
+                sectname: read_fixed_str(raw, off, 16)?,
+                segname: read_fixed_str(raw, off + 16, 16)?,
+                addr: read_u64(raw, off + 32, le)?,
+                size: read_u64(raw, off + 40, le)?,
+                offset: read_u32(raw, off + 48, le)?,
+                align: read_u32(raw, off + 52, le)?,
+                flags: read_u32(raw, off + 64, le)?,
+            };
+            off += 80;
+            section
+        } else {
+            let section = Section {
+                sectname: read_fixed_str(raw, off, 16)?,
+                segname: read_fixed_str(raw, off + 16, 16)?,
+                addr: read_u32(raw, off + 32, le)? as u64,
+                size: read_u32(raw, off + 36, le)? as u64,
+                offset: read_u32(raw, off + 40, le)?,
+                align: read_u32(raw, off + 44, le)?,
+                flags: read_u32(raw, off + 56, le)?,
+            };
+            off += 68;
+            section
+        };
+        segment.sections.push(section);
+    }
+    Ok(segment)
+}
+
+pub fn arch_name(cputype: i32, cpusubtype: i32) -> &'static str {
+    const CPU_SUBTYPE_MASK: i32 = 0x00ff_ffff;
+    match (cputype, cpusubtype & CPU_SUBTYPE_MASK) {
+        (CPU_TYPE_ARM64, 2) => "arm64e",
+        (CPU_TYPE_ARM64, _) => "arm64",
+        (CPU_TYPE_X86_64, 8) => "x86_64h",
+        (CPU_TYPE_X86_64, _) => "x86_64",
+        (CPU_TYPE_X86, _) => "i386",
+        (CPU_TYPE_ARM, _) => "arm",
+        (CPU_TYPE_POWERPC, _) => "ppc",
+        _ => "unknown",
+    }
+}
+
+/// Name of a build version platform as Apple's tools print it.
+pub fn platform_name(platform: u32) -> &'static str {
+    match platform {
+        PLATFORM_MACOS => "macOS",
+        PLATFORM_IOS => "iOS",
+        PLATFORM_TVOS => "tvOS",
+        PLATFORM_WATCHOS => "watchOS",
+        5 => "bridgeOS",
+        PLATFORM_MACCATALYST => "Mac Catalyst",
+        PLATFORM_IOSSIMULATOR => "iOS Simulator",
+        8 => "tvOS Simulator",
+        9 => "watchOS Simulator",
+        10 => "DriverKit",
+        11 => "visionOS",
+        12 => "visionOS Simulator",
+        _ => "unknown",
+    }
+}
+
+pub fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Unexpected end of data at offset {}", offset))?;
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    Ok(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+pub fn read_u64(data: &[u8], offset: usize, little_endian: bool) -> Result<u64> {
+    let lo = read_u32(data, offset, little_endian)? as u64;
+    let hi = read_u32(data, offset + 4, little_endian)? as u64;
+    Ok(if little_endian {
+        (hi << 32) | lo
+    } else {
+        (lo << 32) | hi
+    })
+}
+
+pub fn write_u32(data: &mut [u8], offset: usize, value: u32, little_endian: bool) -> Result<()> {
+    let bytes = data
+        .get_mut(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Unexpected end of data at offset {}", offset))?;
+    if little_endian {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    } else {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    Ok(())
+}
+
+pub fn write_u64(data: &mut [u8], offset: usize, value: u64, little_endian: bool) -> Result<()> {
+    let (first, second) = if little_endian {
+        (value as u32, (value >> 32) as u32)
+    } else {
+        ((value >> 32) as u32, value as u32)
+    };
+    write_u32(data, offset, first, little_endian)?;
+    write_u32(data, offset + 4, second, little_endian)
+}
+
+fn read_cstr(data: &[u8], offset: usize) -> Result<String> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| anyhow!("String offset {} out of bounds", offset))?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+fn read_fixed_str(data: &[u8], offset: usize, len: usize) -> Result<String> {
+    let bytes = data
+        .get(offset..offset + len)
+        .ok_or_else(|| anyhow!("Unexpected end of data at offset {}", offset))?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    fn _load(relative: &str) -> MachO {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources")
+            .join(relative);
+        let data = std::fs::read(path).unwrap();
+        MachO::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_executable() {
+        let macho = _load("poppler/pdftoppm");
+        assert!(macho.is_executable());
+        assert_eq!(macho.header.arch_name(), "arm64");
+        assert_eq!(macho.commands.len(), macho.header.ncmds as usize);
+        assert!(macho.id().is_none());
+        assert!(macho.uuid().is_some());
+        assert!(macho.build_version().is_some());
+        assert!(macho.segment("__LINKEDIT").is_some());
+        let rpaths: Vec<&str> = macho.rpaths().collect();
+        assert_eq!(rpaths, vec!["@loader_path/../lib", "@loader_path/libs"]);
+        assert!(
+            macho
+                .dependencies()
+                .any(|d| d.name == "@rpath/libpoppler.154.0.0.dylib" && d.kind == DylibKind::Load)
+        );
+    }
+
+    #[test]
+    fn test_parse_dylib() {
+        let macho = _load("poppler/libs/libassuan.9.dylib");
+        assert!(macho.is_dylib());
+        let id = macho.id().unwrap();
+        assert_eq!(id.name, "@rpath/libassuan.9.dylib");
+        assert_eq!(id.kind, DylibKind::Id);
+        assert!(macho.dependencies().all(|d| d.kind != DylibKind::Id));
+        assert!(macho.code_signature().is_some());
+    }
+
+    #[test]
+    fn test_detect_kind() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources");
+        let kind = detect_file_kind(&root.join("poppler/pdftoppm")).unwrap();
+        assert_eq!(kind, FileKind::MachO);
+        let kind = detect_file_kind(&root.join("libzstd.a")).unwrap();
+        assert_eq!(kind, FileKind::Archive);
+        assert_eq!(
+            detect_kind(&[0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 2]),
+            FileKind::Fat
+        );
+        assert_eq!(
+            detect_kind(&[0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 0x34]),
+            FileKind::Unknown
+        );
+    }
+
+    #[test]
+    fn test_fat_roundtrip() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources");
+        let arm64 = std::fs::read(root.join("poppler/libs/libassuan.9.dylib")).unwrap();
+        let x86_64 = _fake_x86_64(&arm64);
+        let fat = build_fat(&[(&x86_64, 12), (&arm64, 14)]).unwrap();
+        assert_eq!(detect_kind(&fat), FileKind::Fat);
+
+        let arches = parse_fat_header(&fat).unwrap();
+        assert_eq!(arches.len(), 2);
+        assert_eq!(arches[0].arch_name(), "x86_64");
+        assert_eq!(arches[1].arch_name(), "arm64");
+        assert_eq!(arches[1].offset % (1 << 14), 0);
+        assert_eq!(&fat[arches[1].range()], &arm64[..]);
+
+        let slices = parse_slices(&fat).unwrap();
+        assert_eq!(slices.len(), 2);
+        assert_eq!(slices[0].id(), slices[1].id());
+    }
+
+    #[test]
+    fn test_corrupt_counts() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources");
+        let mut thin = std::fs::read(root.join("poppler/libs/libassuan.9.dylib")).unwrap();
+        write_u32(&mut thin, 16, u32::MAX, true).unwrap();
+        assert!(MachO::parse(&thin).is_err());
+
+        let mut fat = vec![0u8; 8 + 32];
+        write_u32(&mut fat, 0, FAT_MAGIC_64, false).unwrap();
+        write_u32(&mut fat, 4, u32::MAX, false).unwrap();
+        assert!(parse_fat_header(&fat).is_err());
+
+        write_u32(&mut fat, 4, 1, false).unwrap();
+        write_u64(&mut fat, 8 + 8, u64::MAX, false).unwrap();
+        write_u64(&mut fat, 8 + 16, 2, false).unwrap();
+        assert!(parse_fat_header(&fat).is_err());
+    }
+
+    /// Changes the kind of the load command of `install_name` in every
+    /// slice, e.g. to weak link a dependency. Nothing is re-signed.
+    pub fn _set_dylib_kind(file_path: &Path, install_name: &str, kind: DylibKind) {
+        _write_dylib_field(file_path, install_name, 0, kind.cmd());
+    }
+
+    /// Changes the compatibility version the load command of `install_name`
+    /// requires.
+    pub fn _set_compatibility_version(file_path: &Path, install_name: &str, version: Version) {
+        _write_dylib_field(file_path, install_name, 20, version.0);
+    }
+
+    fn _write_dylib_field(file_path: &Path, install_name: &str, field: usize, value: u32) {
+        let mut data = std::fs::read(file_path).unwrap();
+        for range in slice_ranges(&data).unwrap() {
+            let macho = MachO::parse(&data[range.clone()]).unwrap();
+            for command in &macho.commands {
+                if matches!(command.command, Command::Dylib(ref d) if d.kind != DylibKind::Id && d.name == install_name)
+                {
+                    let le = macho.header.little_endian;
+                    write_u32(&mut data[range.clone()], command.offset + field, value, le).unwrap();
+                }
+            }
+        }
+        std::fs::write(file_path, data).unwrap();
+    }
+
+    /// Pretends an arm64 image is an x86_64 one, good enough for exercising
+    /// code that only looks at load commands.
+    pub fn _fake_x86_64(arm64: &[u8]) -> Vec<u8> {
+        let mut data = arm64.to_vec();
+        write_u32(&mut data, 4, CPU_TYPE_X86_64 as u32, true).unwrap();
+        write_u32(&mut data, 8, 3, true).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_version_display() {
+        assert_eq!(Version(0x000a_0f02).to_string(), "10.15.2");
+        assert!(Version(0x000b_0000) > Version(0x000a_0f02));
+    }
+
+    #[test]
+    fn test_version_min() {
+        let mut raw = vec![0u8; 16];
+        write_u32(&mut raw, 0, LC_VERSION_MIN_MACOSX, true).unwrap();
+        write_u32(&mut raw, 4, 16, true).unwrap();
+        write_u32(&mut raw, 8, 0x000a_0d00, true).unwrap();
+        write_u32(&mut raw, 12, 0x000a_0e00, true).unwrap();
+        let Command::BuildVersion(build) =
+            parse_command(LC_VERSION_MIN_MACOSX, &raw, true, true).unwrap()
+        else {
+            panic!("LC_VERSION_MIN_MACOSX not parsed");
+        };
+        assert_eq!(build.platform, PLATFORM_MACOS);
+        assert_eq!(build.minos.to_string(), "10.13.0");
+        assert_eq!(build.sdk.to_string(), "10.14.0");
+        assert_eq!(platform_name(PLATFORM_IOSSIMULATOR), "iOS Simulator");
+    }
+}
@@ -1,15 +1,36 @@
-use crate::{
-    helpers::{BinType, check_file_type},
-    model::Binary,
-};
 use anyhow::anyhow;
+use macbinbundler::{
+    BundleReport, Bundler, Error,
+    app::{AppInfo, BundleKind},
+    backend::{NativeBackend, SystemRunner, ToolsBackend},
+    error::exit_code,
+    export::{GraphFormat, export},
+    model::{Binary, Graph},
+    plan::Plan,
+    policy::SystemPolicy,
+    resolver::Resolver,
+    sysroot::{PathMapper, PrefixMap},
+    verify::verify_bundle,
+};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
 
-pub mod cli;
-pub mod helpers;
-pub mod model;
+mod cli;
 
-fn main() -> anyhow::Result<()> {
+// Failures are printed the way anyhow does it for `main`, the exit code tells
+// wrapper scripts what kind of failure it was.
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {:?}", error);
+            ExitCode::from(exit_code(&error))
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let cli = cli::init_cli();
 
     if let Some(log_level) = cli.get_one::<String>("LOG_LEVEL") {
@@ -23,62 +44,240 @@ fn main() -> anyhow::Result<()> {
 
     env_logger::init();
 
-    let Some(binary_path) = cli.get_one::<String>("BINARY_PATH") else {
+    let mapper = path_mapper(&cli)?;
+    let policy = system_policy(&cli)?;
+
+    match cli.subcommand() {
+        Some(("graph", sub)) => return print_graph(sub, &mapper, policy),
+        Some(("verify", sub)) => return verify(sub, policy),
+        Some(("apply", sub)) => return apply(sub, policy),
+        _ => {}
+    }
+
+    let mut binary_paths = cli.get_many::<String>("BINARY_PATH").unwrap_or_default();
+    let Some(binary_path) = binary_paths.next() else {
         return Err(anyhow!(
             "Cli Error: Failed to retrieve required argument `BINARY_PATH` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
         ));
     };
 
-    let Some(output_path) = cli.get_one::<String>("OUTPUT_PATH") else {
+    let Ok(libs_path) = cli.try_get_one::<String>("LIBS_PATH") else {
         return Err(anyhow!(
-            "Cli Error: Failed to retrieve required argument `OUTPUT_PATH` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
+            "Cli Error: Failed to retrieve required argument `LIBS_PATH` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
         ));
     };
 
-    let Ok(libs_path) = cli.try_get_one::<String>("LIBS_PATH") else {
+    let bundler = match cli.get_one::<String>("OUTPUT_PATH") {
+        Some(output_path) => {
+            let mut bundler = Bundler::new(binary_path, output_path);
+            for binary_path in binary_paths {
+                bundler = bundler.input(binary_path);
+            }
+            if cli.get_flag("APP") {
+                bundler = bundler.app(app_info(&cli)?);
+            }
+            bundler
+        }
+        None => {
+            // Without an output only a single bundle can be repaired in
+            // place.
+            if binary_paths.next().is_some()
+                || BundleKind::of(Path::new(binary_path)).is_none()
+                || cli.get_flag("APP")
+            {
+                return Err(anyhow!(
+                    "Destination folder is missing, use <-o | --output>.\nOnly a single `.app` or `.framework` is repaired in place without it."
+                ));
+            }
+            Bundler::in_place(binary_path)?
+        }
+    };
+    let mut bundler = configure(bundler, &cli)
+        .path_mapper(mapper)
+        .system_policy(policy)
+        .prune_unused(cli.get_flag("PRUNE_UNUSED"))
+        .allow_incompatible_versions(cli.get_flag("ALLOW_INCOMPATIBLE_VERSIONS"));
+    if let Some(libs_path) = libs_path {
+        bundler = bundler.libs_path(libs_path);
+    }
+    if let Some(version) = cli.get_one::<String>("DEPLOYMENT_TARGET") {
+        bundler = bundler.deployment_target(version.parse()?);
+    }
+
+    if let Some(plan_path) = cli.get_one::<String>("PLAN") {
+        let plan = bundler.plan()?;
+        if plan_path == "-" {
+            print!("{}", plan.to_json()?);
+        } else {
+            plan.save(Path::new(plan_path))?;
+            log::info!("Plan written: {}", plan_path);
+        }
+        return Ok(());
+    }
+
+    log_report(&bundler.run()?);
+    Ok(())
+}
+
+// Options shared by bundling and `apply`.
+fn configure(bundler: Bundler, cli: &clap::ArgMatches) -> Bundler {
+    let bundler = bundler
+        .create_output(cli.get_flag("CREATE_OUTPUT_PATH"))
+        .clean_output(cli.get_flag("CLEAN_OUTPUT"))
+        .validate(!cli.get_flag("NO_VALIDATE"));
+    if cli
+        .get_one::<String>("BACKEND")
+        .is_some_and(|backend| backend == "tools")
+    {
+        bundler.backend(ToolsBackend::new(SystemRunner))
+    } else {
+        bundler
+    }
+}
+
+fn app_info(cli: &clap::ArgMatches) -> anyhow::Result<AppInfo> {
+    let Some(identifier) = cli.get_one::<String>("BUNDLE_ID") else {
         return Err(anyhow!(
-            "Cli Error: Failed to retrieve required argument `LIBS_PATH` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
+            "Cli Error: Failed to retrieve required argument `BUNDLE_ID` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
         ));
     };
+    Ok(AppInfo {
+        identifier: identifier.clone(),
+        version: cli
+            .get_one::<String>("BUNDLE_VERSION")
+            .cloned()
+            .unwrap_or_else(|| "1.0".to_string()),
+        name: cli.get_one::<String>("BUNDLE_NAME").cloned(),
+        executable: cli.get_one::<String>("EXECUTABLE").cloned(),
+        minimum_system_version: cli.get_one::<String>("MIN_OS").cloned(),
+    })
+}
 
-    let create_bundle_path = cli.get_flag("CREATE_OUTPUT_PATH");
-
-    let binary_path = PathBuf::from(binary_path);
-    let output_path = PathBuf::from(output_path);
-    let libs_path = libs_path.map(|s| Path::new(s));
+fn log_report(report: &BundleReport) {
+    log::info!(
+        "Bundled {} files, {} load commands rewritten",
+        report.copied.len(),
+        report.rewritten.len()
+    );
+    if !report.pruned.is_empty() {
+        log::info!(
+            "Pruned {} unused libraries, {:.1} MB saved",
+            report.pruned.len(),
+            report.pruned_size() as f64 / 1_000_000.0
+        );
+    }
+}
 
-    if output_path.exists() && output_path.is_file() {
+fn apply(cli: &clap::ArgMatches, policy: SystemPolicy) -> anyhow::Result<()> {
+    let Some(plan_path) = cli.get_one::<String>("PLAN") else {
         return Err(anyhow!(
-            "Output path is a file!\nOutput path must be a folder."
+            "Cli Error: Failed to retrieve required argument `PLAN` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
         ));
+    };
+
+    let plan = Plan::load(Path::new(plan_path))?;
+    let output = match cli.get_one::<String>("OUTPUT_PATH") {
+        Some(output) => PathBuf::from(output),
+        None => plan.output.clone(),
+    };
+    let mut inputs = plan.inputs.iter();
+    let mut bundler = Bundler::new(inputs.next().cloned().unwrap_or_default(), output);
+    for input in inputs {
+        bundler = bundler.input(input);
     }
+    let bundler = configure(bundler, cli).system_policy(policy);
+    log_report(&bundler.apply(&plan)?);
+    Ok(())
+}
 
-    if !output_path.exists() {
-        if create_bundle_path {
-            let _ = std::fs::create_dir_all(&output_path)?;
-        } else {
-            return Err(anyhow!(
-                "Destination path not exist: {}\nPlease make sure it exists or consider using <-c | --create-bundle-path> flag to create folder!",
-                output_path.display()
-            ));
-        }
+fn path_mapper(cli: &clap::ArgMatches) -> anyhow::Result<PathMapper> {
+    let sysroot = cli.get_one::<String>("SYSROOT").map(PathBuf::from);
+    let prefixes = cli
+        .get_many::<String>("PREFIX_MAP")
+        .unwrap_or_default()
+        .map(|rule| rule.parse::<PrefixMap>())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if let Some(ref sysroot) = sysroot
+        && !sysroot.is_dir()
+    {
+        return Err(anyhow!("Sysroot is not a folder: {}", sysroot.display()));
     }
+    Ok(PathMapper::new(sysroot, prefixes))
+}
 
-    let res = check_file_type(&binary_path)?;
+fn system_policy(cli: &clap::ArgMatches) -> anyhow::Result<SystemPolicy> {
+    let globs = |id: &str| -> Vec<String> {
+        cli.get_many::<String>(id)
+            .unwrap_or_default()
+            .cloned()
+            .collect()
+    };
+    let mut policy = SystemPolicy::default()
+        .allow(&globs("ALLOW_SYSTEM"))?
+        .deny(&globs("DENY_SYSTEM"))?;
+    if let Some(version) = cli.get_one::<String>("MACOS_VERSION") {
+        policy = policy.macos_version(version.parse()?);
+    }
+    if let Some(sdk) = cli.get_one::<String>("SDK") {
+        policy = policy.sdk(Path::new(sdk))?;
+    }
+    Ok(policy)
+}
 
-    let mut binary = match res {
-        BinType::Executable(_) => Binary::new(binary_path, true, true)?,
-        BinType::Dylib(_) => Binary::new(binary_path, false, true)?,
-        _ => {
-            return Err(anyhow!(
-                "Input file not recognized!\nMust be an executable or a dynamic library: {}",
-                binary_path.display()
-            ));
-        }
+fn print_graph(
+    cli: &clap::ArgMatches,
+    mapper: &PathMapper,
+    policy: SystemPolicy,
+) -> anyhow::Result<()> {
+    let Some(binary_path) = cli.get_one::<String>("BINARY_PATH") else {
+        return Err(anyhow!(
+            "Cli Error: Failed to retrieve required argument `BINARY_PATH` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
+        ));
+    };
+    let format = match cli.get_one::<String>("FORMAT") {
+        Some(format) => format.parse::<GraphFormat>()?,
+        None => GraphFormat::Dot,
     };
 
-    binary.run(&output_path, libs_path)?;
+    let binary_path = PathBuf::from(binary_path);
+    let resolver = Resolver::new(&binary_path)
+        .with_mapper(mapper.clone())
+        .with_policy(Arc::new(policy));
+    let mut graph = Graph::collect(
+        Binary::open(binary_path, &NativeBackend)?,
+        &resolver,
+        &NativeBackend,
+    )?;
+    graph.calculate_install_names()?;
+    let text = export(&graph, format)?;
+
+    match cli.get_one::<String>("GRAPH_OUTPUT") {
+        Some(path) => std::fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+fn verify(cli: &clap::ArgMatches, policy: SystemPolicy) -> anyhow::Result<()> {
+    let Some(bundle_path) = cli.get_one::<String>("BUNDLE_PATH") else {
+        return Err(anyhow!(
+            "Cli Error: Failed to retrieve required argument `BUNDLE_PATH` after argument validation. Please report an issue on `https://github.com/fisaogullari/macbinbundler`."
+        ));
+    };
 
+    let issues = verify_bundle(Path::new(bundle_path), Arc::new(policy))?;
+    if !issues.is_empty() {
+        for issue in &issues {
+            log::error!("{}", issue);
+        }
+        return Err(Error::VerificationFailed {
+            path: PathBuf::from(bundle_path),
+            problems: issues.len(),
+        }
+        .into());
+    }
+    log::info!("Bundle verified: {}", bundle_path);
     Ok(())
 }
 
@@ -1,26 +1,78 @@
-use crate::helpers::*;
-use anyhow::{Result, anyhow};
-use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+// Dependency graph of a binary.
+//
+// Every Mach-O image is stored once in an arena and identified by its real
+// path and LC_UUIDs, edges point from the image carrying a load command to the
+// library it resolves to and keep the install name written in that command.
+// A library shared by many others is therefore copied, fixed and signed
+// exactly once. System libraries are kept as leaf nodes which are never
+// bundled so the graph shows everything a binary loads.
+//
+// Edges keep the kind of their load command. A weak library which can't be
+// found is optional at runtime, it becomes a missing leaf node whose install
+// name still points into the bundle in case it is added later. Every edge
+// keeps the compatibility version its load command requires, dyld refuses a
+// library whose own compatibility version is lower. Upward links
+// point back to a library loading them and don't make it a dependency of
+// its own dependents when placing base binaries.
+//
+// Load commands nothing is imported through, common with over-linked
+// formulas, are found from the symbols each binary binds. They can be
+// pruned: the load commands are dropped and libraries nothing else loads
+// are left out of the bundle.
+
+use crate::backend::{Backend, ImageKind};
+use crate::error::Error;
+use crate::framework::Framework;
+use crate::helpers::{is_system_folder, normalize_path};
+use crate::macho::{Dylib, DylibKind, Version};
+use crate::plan::{Plan, PlannedFile, PlannedFramework};
+use crate::report::{BundleReport, PrunedLibrary};
+use crate::resolver::{LoaderImage, Resolver};
+use crate::rewrite::Edit;
+use crate::symbols;
+use crate::sysroot::PathMapper;
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+pub type NodeId = usize;
+
+// A required compatibility version matching every library.
+const ANY_VERSION: Version = Version(u32::MAX);
 
 #[derive(Debug, Default)]
 pub struct Binary {
     file_path: PathBuf,
+    uuids: Vec<[u8; 16]>,
+    archs: Vec<String>,
+    dependencies: Vec<Dylib>,
+    rpaths: Vec<String>,
     is_executable: bool,
     is_base: bool,
-    install_name_old: Option<String>,
+    // Folder of a base binary inside the bundle, relative to the output.
+    base_folder: PathBuf,
+    // Part of a bundle repaired in place, it keeps its location even when
+    // other binaries depend on it.
+    is_in_place: bool,
+    is_system: bool,
+    // A weak library not found while collecting, it is never bundled.
+    is_missing: bool,
     install_name_id: Option<String>,
-    dest_folder_path: Option<PathBuf>,
+    // Versions of a library's id.
+    current_version: Option<Version>,
+    compatibility_version: Option<Version>,
+    install_name_new: Option<String>,
     dest_file_path: Option<PathBuf>,
-    libs_path: Option<PathBuf>,
-    rpath: Option<String>,
-    libs: Vec<Binary>,
+    // Install names of pruned load commands.
+    removed_dependencies: Vec<String>,
 }
 
 impl Binary {
     pub fn new(file_path: PathBuf, is_executable: bool, is_base: bool) -> Result<Self> {
         if !file_path.is_file() || !file_path.exists() {
-            return Err(anyhow!("Path not exist or a file: {}", file_path.display()));
+            return Err(
+                Error::io("opening", &file_path)(std::io::ErrorKind::NotFound.into()).into(),
+            );
         }
 
         Ok(Binary {
@@ -31,316 +83,1261 @@ impl Binary {
         })
     }
 
-    pub fn run(&mut self, dest_folder: &Path, libs_path: Option<&Path>) -> Result<()> {
-        let mut libs_checked = HashSet::<PathBuf>::new();
+    /// Opens the binary to bundle, it has to be an executable, a dynamic
+    /// library or a loadable bundle.
+    pub fn open(file_path: PathBuf, backend: &dyn Backend) -> Result<Self> {
+        let kind = backend.inspect(&file_path).map(|image| image.kind);
+        match kind {
+            Ok(ImageKind::Executable) => Binary::new(file_path, true, true),
+            Ok(ImageKind::Dylib) => Binary::new(file_path, false, true),
+            // Like an executable a plugin has no id, nothing links against it.
+            Ok(ImageKind::Bundle) => Binary::new(file_path, true, true),
+            Ok(ImageKind::Other) => Err(Error::UnsupportedBinary {
+                path: file_path,
+                reason: "Input file not recognized!\nMust be an executable, a dynamic library or a loadable bundle"
+                    .to_string(),
+            }
+            .into()),
+            Err(error) => Err(error),
+        }
+    }
 
-        self.get_libs(&mut libs_checked)?;
-        self.resolve_symlinks()?;
-        self.calculate_rpaths()?;
-        log::trace!("Binary Structure:\n {:#?}", self);
-        self.set_libs_path(libs_path);
-        self.set_dest_folder(dest_folder);
-        self.copy_to_dest()?;
-        self.fix_install_names()?;
-        self.sign_all()?;
+    pub fn run(
+        self,
+        dest_folder: &Path,
+        libs_path: Option<&Path>,
+        mapper: &PathMapper,
+        backend: &dyn Backend,
+    ) -> Result<Graph> {
+        let resolver = Resolver::new(&self.file_path).with_mapper(mapper.clone());
 
-        Ok(())
-    }
+        let mut graph = Graph::collect(self, &resolver, backend)?;
+        log::trace!("Dependency graph:\n {:#?}", graph);
+        graph.bundle(dest_folder, libs_path, backend)?;
 
-    fn set_libs_path(&mut self, libs_path: Option<&Path>) {
-        if let Some(libs_path) = libs_path {
-            self.libs_path = Some(libs_path.to_path_buf());
-        } else {
-            self.libs_path = Some(PathBuf::from("libs"))
-        }
-        for lib in &mut self.libs {
-            let _ = lib.set_libs_path(libs_path);
-        }
+        Ok(graph)
     }
 
-    fn set_dest_folder(&mut self, dest_folder: &Path) {
-        if self.is_base {
+    // Symlinks are followed before anything else so that a library reached
+    // through different links ends up as the same node.
+    fn identify(&mut self, mapper: &PathMapper, backend: &dyn Backend) -> Result<()> {
+        let real_path = mapper.canonicalize(&self.file_path)?;
+        if real_path != self.file_path {
             log::debug!(
-                "Destination folder set for: {}\nto: {}",
+                "Real path resolved for: {}\nto: {}",
                 self.file_path.display(),
-                dest_folder.display()
-            );
-            self.dest_folder_path = Some(dest_folder.to_path_buf());
-        } else {
-            if let Some(ref libs_path) = self.libs_path {
-                let dest_folder = dest_folder.join(libs_path);
-                log::debug!(
-                    "Destination folder set for: {}\nto: {}",
-                    self.file_path.display(),
-                    dest_folder.display()
-                );
-                self.dest_folder_path = Some(dest_folder);
-            } else {
-                let dest_folder = dest_folder.join("libs");
-                log::debug!(
-                    "Destination folder set for: {}\nto: {}",
-                    self.file_path.display(),
-                    dest_folder.display()
-                );
-                self.dest_folder_path = Some(dest_folder);
-            }
-        }
-
-        for lib in &mut self.libs {
-            let _ = lib.set_dest_folder(dest_folder);
-        }
-    }
-    // [-] TODO: <@executable_path> should be handled as well.
-    fn get_libs(&mut self, libs_checked: &mut HashSet<PathBuf>) -> Result<()> {
-        if libs_checked.contains(&self.file_path) {
-            log::debug!(
-                "Library already collected: {}\nSkipping",
-                self.file_path.display()
+                real_path.display()
             );
-            return Ok(());
+            self.file_path = real_path;
         }
 
-        let output = get_shared_libs(&self.file_path)?;
-        let mut lines = output.lines().skip(1);
+        let image = backend
+            .inspect(&self.file_path)
+            .with_context(|| format!("Error while inspecting: {}", self.file_path.display()))?;
 
-        // If Binary is not an executable, we need to treat first line separately
-        // since it is the id of the shared library.
+        // If Binary is not an executable, we need to read its id since it is
+        // the install name of the shared library.
         if !self.is_executable {
-            let Some(id) = lines.next() else {
-                return Err(anyhow!(
-                    "Error while reading the id of: {}",
-                    self.file_path.display()
-                ));
+            let Some(id) = image.id else {
+                return Err(Error::UnsupportedBinary {
+                    path: self.file_path.clone(),
+                    reason: "File not a dynamic library".to_string(),
+                }
+                .into());
             };
-            let id = id
-                .trim()
-                .split_whitespace()
-                .nth(0)
-                .ok_or_else(|| anyhow!("Error while processing line:{}", id))?;
-            self.install_name_id = Some(id.to_string());
-        }
-
-        for line in lines {
-            let line = line
-                .trim()
-                .split_whitespace()
-                .nth(0)
-                .ok_or_else(|| anyhow!("Error while processing line: {}", line))?;
-            log::debug!("Processing library: {}", line);
-
-            if line.starts_with("/usr/lib") || line.starts_with("/System/Library") {
-                log::info!("Skipping system library: {}", line);
-                continue;
-            }
+            self.install_name_id = Some(id);
+            self.current_version = image.current_version;
+            self.compatibility_version = image.compatibility_version;
+        }
+        self.uuids = image.uuids;
+        self.archs = image.archs;
+        self.dependencies = image.dependencies;
+        self.rpaths = image.rpaths;
+        Ok(())
+    }
 
-            if line.starts_with("@rpath") {
-                let abs_path = canonicalize_rpath(&self.file_path, line)?;
-                let mut lib = Binary::new(abs_path, false, false)?;
-                lib.install_name_old = Some(line.to_string());
-                self.libs.push(lib);
+    // System libraries are not read from disk, they are only known by their
+    // install name.
+    fn system(install_name: &str) -> Self {
+        Binary {
+            file_path: PathBuf::from(install_name),
+            install_name_id: Some(install_name.to_string()),
+            is_system: true,
+            ..Default::default()
+        }
+    }
 
-                continue;
-            }
+    // Missing weak libraries are only known by their install name as well.
+    fn missing(install_name: &str) -> Self {
+        Binary {
+            file_path: PathBuf::from(install_name),
+            is_missing: true,
+            ..Default::default()
+        }
+    }
 
-            if Path::new(line).is_absolute() {
-                let mut lib = Binary::new(PathBuf::from(line), false, false)?;
-                lib.install_name_old = Some(line.to_string());
-                self.libs.push(lib);
-                continue;
-            }
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
 
-            panic!("Unrecognized library: {}", line);
-        }
+    pub fn install_name_id(&self) -> Option<&str> {
+        self.install_name_id.as_deref()
+    }
 
-        libs_checked.insert(self.file_path.clone());
+    pub fn current_version(&self) -> Option<Version> {
+        self.current_version
+    }
 
-        for lib in &mut self.libs {
-            let _ = lib.get_libs(libs_checked)?;
-        }
+    pub fn compatibility_version(&self) -> Option<Version> {
+        self.compatibility_version
+    }
 
-        Ok(())
+    /// The install name the binary gets inside the bundle.
+    pub fn install_name_new(&self) -> Option<&str> {
+        self.install_name_new.as_deref()
     }
 
-    fn resolve_symlinks(&mut self) -> Result<()> {
-        if self.file_path.is_symlink() {
-            log::debug!("Symlink found for: {}", self.file_path.display());
-            let real_path = self.file_path.canonicalize()?;
-            self.file_path = real_path;
-        }
+    pub fn archs(&self) -> &[String] {
+        &self.archs
+    }
 
-        for lib in &mut self.libs {
-            let _ = lib.resolve_symlinks()?;
-        }
+    pub fn is_executable(&self) -> bool {
+        self.is_executable
+    }
 
-        Ok(())
+    pub fn is_system(&self) -> bool {
+        self.is_system
     }
 
-    fn copy_to_dest(&mut self) -> Result<()> {
-        let Some(ref dest_folder_path) = self.dest_folder_path else {
+    /// A weakly linked library which doesn't exist on this machine.
+    pub fn is_missing(&self) -> bool {
+        self.is_missing
+    }
+
+    fn dest_file_path(&self) -> Result<&Path> {
+        let Some(ref dest_file_path) = self.dest_file_path else {
             return Err(anyhow!(
-                "Error while retrieving destionation path of: {}",
+                "No destination file path found for: {}",
                 self.file_path.display()
             ));
         };
+        Ok(dest_file_path)
+    }
+}
 
-        log::debug!("Creating folder: {}", dest_folder_path.display());
-        std::fs::create_dir_all(dest_folder_path)?;
+/// A load command of `from` that resolved to `to`.
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub install_name: String,
+    pub kind: DylibKind,
+    /// Lowest compatibility version of `to` the load command accepts.
+    pub compatibility_version: Version,
+}
 
-        let Some(file_name) = self.file_path.file_name() else {
-            return Err(anyhow!(
-                "Error while retrieving file name of: {}",
-                self.file_path.display()
-            ));
-        };
+#[derive(Debug, Default)]
+pub struct Graph {
+    nodes: Vec<Binary>,
+    edges: Vec<Edge>,
+    roots: Vec<NodeId>,
+    by_path: HashMap<PathBuf, NodeId>,
+    by_uuids: HashMap<Vec<[u8; 16]>, NodeId>,
+    // Libraries left out by `prune_unused`.
+    pruned: Vec<PathBuf>,
+}
 
-        let dest_file_path = dest_folder_path.join(file_name);
-        log::debug!("Destination full path: {}", dest_file_path.display());
-        self.dest_file_path = Some(dest_file_path.clone());
+impl Graph {
+    /// Walks the dependencies of `base` the way dyld loads them. The base
+    /// binary is always node 0.
+    pub fn collect(base: Binary, resolver: &Resolver, backend: &dyn Backend) -> Result<Self> {
+        let mut graph = Graph::default();
+        graph.add(base, Path::new(""), resolver, backend)?;
+        Ok(graph)
+    }
 
-        if !dest_file_path.exists() {
-            let _ = std::fs::copy(&self.file_path, &dest_file_path)?;
-            log::info!(
-                "Copying: {}\nto: {}",
-                self.file_path.display(),
-                dest_file_path.display()
+    /// Adds another base binary, placed in `folder` relative to the output
+    /// folder. Libraries already collected for earlier bases are shared.
+    /// `resolver` has to be made for `base` since `@executable_path` depends
+    /// on it.
+    pub fn add(
+        &mut self,
+        mut base: Binary,
+        folder: &Path,
+        resolver: &Resolver,
+        backend: &dyn Backend,
+    ) -> Result<NodeId> {
+        base.is_base = true;
+        base.base_folder = folder.to_path_buf();
+        let (root, is_new) = self.insert(base, resolver.mapper(), backend)?;
+        if is_new {
+            self.visit(root, resolver, backend, &mut vec![])?;
+        } else {
+            log::debug!(
+                "Input already collected: {}",
+                self.nodes[root].file_path.display()
             );
         }
+        if !self.roots.contains(&root) {
+            self.roots.push(root);
+        }
+        Ok(root)
+    }
+
+    /// Adds a binary of a bundle repaired in place, found in `folder`
+    /// relative to the bundle. Unlike `add` it stays there even when other
+    /// binaries depend on it. Executables should be added first so that
+    /// libraries relying on their rpaths are resolved through them.
+    pub fn add_in_place(
+        &mut self,
+        base: Binary,
+        folder: &Path,
+        resolver: &Resolver,
+        backend: &dyn Backend,
+    ) -> Result<NodeId> {
+        let id = self.add(base, folder, resolver, backend)?;
+        let node = &mut self.nodes[id];
+        node.is_base = true;
+        node.is_in_place = true;
+        node.base_folder = folder.to_path_buf();
+        Ok(id)
+    }
+
+    pub fn root(&self) -> NodeId {
+        0
+    }
+
+    /// Every base binary, in the order they were added.
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn nodes(&self) -> &[Binary] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: NodeId) -> &Binary {
+        &self.nodes[id]
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// Outgoing edges of `id`, in load command order.
+    pub fn dependencies(&self, id: NodeId) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == id)
+    }
+
+    /// Incoming edges of `id`.
+    pub fn dependents(&self, id: NodeId) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.to == id)
+    }
 
-        for lib in &mut self.libs {
-            let _ = lib.copy_to_dest()?;
+    /// Nodes which are copied into the bundle, i.e. everything but system
+    /// libraries and missing weak ones.
+    fn bundled(&self) -> impl Iterator<Item = (NodeId, &Binary)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !node.is_system && !node.is_missing)
+    }
+
+    // Binaries loading `id`, an upward link from one of its own dependencies
+    // doesn't count.
+    fn loaders(&self, id: NodeId) -> impl Iterator<Item = &Edge> {
+        self.dependents(id)
+            .filter(|edge| edge.kind != DylibKind::Upward)
+    }
+
+    /// Finds the node of a real path, e.g. to look up a library by the path
+    /// it was copied from.
+    pub fn find(&self, file_path: &Path) -> Option<NodeId> {
+        self.by_path.get(file_path).copied()
+    }
+
+    // Returns the node of the binary and whether it was newly added. Copies
+    // of the same image at different paths are merged through their UUIDs.
+    fn insert(
+        &mut self,
+        mut binary: Binary,
+        mapper: &PathMapper,
+        backend: &dyn Backend,
+    ) -> Result<(NodeId, bool)> {
+        binary.identify(mapper, backend)?;
+
+        if let Some(&id) = self.by_path.get(&binary.file_path) {
+            return Ok((id, false));
         }
-        Ok(())
+        if let Some(&id) = self.by_uuids.get(&binary.uuids) {
+            log::debug!(
+                "Same image as: {}\nmerging: {}",
+                self.nodes[id].file_path.display(),
+                binary.file_path.display()
+            );
+            self.by_path.insert(binary.file_path, id);
+            return Ok((id, false));
+        }
+
+        Ok((self.push(binary), true))
     }
 
-    fn fix_install_names(&self) -> Result<()> {
-        let Some(ref dest_file_path) = self.dest_file_path else {
-            return Err(anyhow!(
-                "No destination file path found for: {}",
-                self.file_path.display()
-            ));
-        };
-        if !self.is_executable {
-            let Some(ref parent_rpath) = self.rpath else {
-                return Err(anyhow!("No rpath found for: {}", self.file_path.display()));
-            };
-            fix_id(dest_file_path, parent_rpath)?;
+    fn insert_system(&mut self, install_name: &str) -> NodeId {
+        match self.by_path.get(Path::new(install_name)) {
+            Some(&id) => id,
+            None => self.push(Binary::system(install_name)),
+        }
+    }
+
+    fn insert_missing(&mut self, install_name: &str) -> NodeId {
+        match self.by_path.get(Path::new(install_name)) {
+            Some(&id) => id,
+            None => self.push(Binary::missing(install_name)),
         }
+    }
+
+    fn push(&mut self, binary: Binary) -> NodeId {
+        let id = self.nodes.len();
+        self.by_path.insert(binary.file_path.clone(), id);
+        if !binary.uuids.is_empty() {
+            self.by_uuids.insert(binary.uuids.clone(), id);
+        }
+        self.nodes.push(binary);
+        id
+    }
+
+    // `chain` holds every image from the base binary down to this one, dyld
+    // searches the rpaths of all of them when resolving `@rpath/...`. A node
+    // is only visited through the first chain reaching it.
+    fn visit(
+        &mut self,
+        id: NodeId,
+        resolver: &Resolver,
+        backend: &dyn Backend,
+        chain: &mut Vec<LoaderImage>,
+    ) -> Result<()> {
+        let file_path = self.nodes[id].file_path.clone();
+        chain.push(LoaderImage {
+            path: file_path.clone(),
+            rpaths: self.nodes[id].rpaths.clone(),
+        });
 
-        if self.is_base {
-            let Some(ref libs_path) = self.libs_path else {
-                return Err(anyhow!("No path found for libraries"));
+        let mut new_nodes = vec![];
+        for dylib in self.nodes[id].dependencies.clone() {
+            let install_name = dylib.name.as_str();
+            log::debug!("Processing library: {}", install_name);
+
+            let decision = resolver.policy().decide(install_name, resolver.mapper());
+            if let Some(ref warning) = decision.warning {
+                log::warn!("{}\nReferenced from: {}", warning, file_path.display());
+            }
+            if decision.is_system {
+                log::info!("Skipping system library: {}", install_name);
+                let to = self.insert_system(install_name);
+                self.edges.push(Edge {
+                    from: id,
+                    to,
+                    install_name: install_name.to_string(),
+                    kind: dylib.kind,
+                    compatibility_version: dylib.compatibility_version,
+                });
+                continue;
+            }
+
+            // `@rpath`, `@executable_path`, `@loader_path` and absolute install
+            // names are all resolved the way dyld would do it at runtime.
+            if !install_name.starts_with('@') && !Path::new(install_name).is_absolute() {
+                return Err(anyhow::Error::new(Error::MissingDependency {
+                    install_name: install_name.to_string(),
+                    loader: file_path.clone(),
+                })
+                .context(format!(
+                    "Unrecognized library: {}\nRelative install names depend on the working directory at runtime.",
+                    install_name
+                )));
+            }
+
+            let abs_path = match resolver.resolve(install_name, chain) {
+                Ok(abs_path) => abs_path,
+                Err(error) if dylib.kind == DylibKind::Weak => {
+                    log::warn!(
+                        "Weak library not found, it is left out: {}\nReferenced from: {}\n{:#}",
+                        install_name,
+                        file_path.display(),
+                        error
+                    );
+                    let to = self.insert_missing(install_name);
+                    self.edges.push(Edge {
+                        from: id,
+                        to,
+                        install_name: install_name.to_string(),
+                        kind: dylib.kind,
+                        compatibility_version: dylib.compatibility_version,
+                    });
+                    continue;
+                }
+                Err(error) => return Err(error),
             };
-            let rpath = PathBuf::from("@loader_path").join(libs_path);
-            let _ = add_rpath(dest_file_path, &rpath)?;
-        } else {
-            let _ = add_rpath(dest_file_path, Path::new("@loader_path"))?;
-        }
-
-        for lib in &self.libs {
-            if let Some(ref child_rpath) = lib.rpath {
-                let Some(ref old_install_name) = lib.install_name_old else {
-                    return Err(anyhow!(
-                        "No old install name found for: {}",
-                        lib.file_path.display()
-                    ));
-                };
-                fix_install_name(dest_file_path, old_install_name, child_rpath)?;
+            let lib = Binary::new(abs_path, false, false)?;
+            let (to, is_new) = self.insert(lib, resolver.mapper(), backend)?;
+            if is_new {
+                new_nodes.push(to);
+            } else {
                 log::debug!(
-                    "Install name fixed for: {}\nold: {}\nnew: {}",
-                    dest_file_path.display(),
-                    old_install_name,
-                    child_rpath
+                    "Library already collected: {}\nSkipping",
+                    self.nodes[to].file_path.display()
                 );
-            };
+            }
+            self.edges.push(Edge {
+                from: id,
+                to,
+                install_name: install_name.to_string(),
+                kind: dylib.kind,
+                compatibility_version: dylib.compatibility_version,
+            });
         }
 
-        for lib in &self.libs {
-            let _ = lib.fix_install_names()?;
+        for lib in new_nodes {
+            self.visit(lib, resolver, backend, chain)?;
         }
+
+        chain.pop();
         Ok(())
     }
 
-    fn calculate_rpaths(&mut self) -> Result<()> {
-        if !self.is_executable {
-            let Some(rpath) = self
+    /// Load commands of bundled binaries to bundled libraries no imported
+    /// symbol is bound to. Re-exported, upward and missing libraries are
+    /// never unused, neither are the dependencies of a binary looking symbols up
+    /// in every loaded image. Libraries only used through `dlsym` can't be
+    /// told apart.
+    pub fn unused_dependencies(&self) -> Vec<Edge> {
+        let mut unused = vec![];
+        for (id, node) in self.bundled() {
+            let used = match symbols::used_dependencies(&node.file_path) {
+                Ok(Some(used)) => used,
+                Ok(None) => continue,
+                Err(error) => {
+                    log::warn!(
+                        "Imported symbols can't be read: {}\n{:#}",
+                        node.file_path.display(),
+                        error
+                    );
+                    continue;
+                }
+            };
+            unused.extend(
+                self.dependencies(id)
+                    .filter(|edge| {
+                        matches!(
+                            edge.kind,
+                            DylibKind::Load | DylibKind::Weak | DylibKind::Lazy
+                        ) && !self.nodes[edge.to].is_system
+                            && !self.nodes[edge.to].is_missing
+                            && !used.contains(&edge.install_name)
+                    })
+                    .cloned(),
+            );
+        }
+        unused
+    }
+
+    /// Load commands whose bundled library is older than the
+    /// compatibility version they require. Like dyld the compatibility
+    /// version of the library's id is compared, its current version may be
+    /// lower, e.g. `2.5.4` of a library compatible with `7.0.0`. Libraries
+    /// without a known version are not checked.
+    pub fn incompatible_versions(&self) -> Vec<&Edge> {
+        self.edges
+            .iter()
+            .filter(|edge| {
+                let node = &self.nodes[edge.to];
+                !node.is_system
+                    && !node.is_missing
+                    && edge.compatibility_version != ANY_VERSION
+                    && node
+                        .compatibility_version
+                        .is_some_and(|version| version < edge.compatibility_version)
+            })
+            .collect()
+    }
+
+    /// Drops the load commands found by `unused_dependencies`. Libraries no
+    /// other binary loads are not bundled anymore, they are returned.
+    pub fn prune_unused(&mut self) -> Vec<PathBuf> {
+        let unused = self.unused_dependencies();
+        for edge in &unused {
+            log::info!(
+                "Pruning unused library: {}\nfrom: {}",
+                edge.install_name,
+                self.nodes[edge.from].file_path.display()
+            );
+            self.nodes[edge.from]
+                .removed_dependencies
+                .push(edge.install_name.clone());
+        }
+        self.edges.retain(|edge| {
+            !unused
+                .iter()
+                .any(|other| other.from == edge.from && other.install_name == edge.install_name)
+        });
+
+        let dropped: Vec<PathBuf> = self
+            .retain_reachable()
+            .into_iter()
+            .filter(|node| !node.is_system && !node.is_missing)
+            .map(|node| node.file_path)
+            .collect();
+        self.pruned.extend(dropped.iter().cloned());
+        dropped
+    }
+
+    // Removes the nodes no base binary reaches anymore and returns them.
+    // Node ids keep their order, the base binary stays node 0.
+    fn retain_reachable(&mut self) -> Vec<Binary> {
+        let mut reached = vec![false; self.nodes.len()];
+        let mut queue = self.roots.clone();
+        while let Some(id) = queue.pop() {
+            if std::mem::replace(&mut reached[id], true) {
+                continue;
+            }
+            queue.extend(self.dependencies(id).map(|edge| edge.to));
+        }
+
+        let mut new_ids = vec![None; self.nodes.len()];
+        let mut kept = vec![];
+        let mut dropped = vec![];
+        for (id, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if reached[id] {
+                new_ids[id] = Some(kept.len());
+                kept.push(node);
+            } else {
+                dropped.push(node);
+            }
+        }
+        self.nodes = kept;
+        self.edges = std::mem::take(&mut self.edges)
+            .into_iter()
+            .filter_map(|edge| {
+                Some(Edge {
+                    from: new_ids[edge.from]?,
+                    to: new_ids[edge.to]?,
+                    ..edge
+                })
+            })
+            .collect();
+        self.roots = self.roots.iter().filter_map(|&id| new_ids[id]).collect();
+        self.by_path.retain(|_, id| match new_ids[*id] {
+            Some(new) => {
+                *id = new;
+                true
+            }
+            None => false,
+        });
+        self.by_uuids.retain(|_, id| match new_ids[*id] {
+            Some(new) => {
+                *id = new;
+                true
+            }
+            None => false,
+        });
+        dropped
+    }
+
+    /// Copies every node into `dest_folder`, libraries go to `libs_path`
+    /// inside it, then rewrites their load commands and signs them.
+    pub fn bundle(
+        &mut self,
+        dest_folder: &Path,
+        libs_path: Option<&Path>,
+        backend: &dyn Backend,
+    ) -> Result<BundleReport> {
+        self.plan(dest_folder, libs_path)?
+            .apply(dest_folder, backend)
+    }
+
+    /// What `bundle` would do, without writing anything.
+    pub fn plan(&mut self, dest_folder: &Path, libs_path: Option<&Path>) -> Result<Plan> {
+        let libs_path = libs_path.unwrap_or(Path::new("libs"));
+        self.calculate_install_names()?;
+        self.set_dest_paths(Path::new(""), libs_path)?;
+        self.calculate_in_place_install_names(libs_path)?;
+        let mut files = vec![];
+        let mut frameworks = vec![];
+        for (id, node) in self.bundled() {
+            let destination = node.dest_file_path()?.to_path_buf();
+            // Frameworks of a bundle repaired in place are already there.
+            if let Some(framework) = Framework::of(&destination) {
+                frameworks.push(PlannedFramework {
+                    source: match node.is_in_place {
+                        true => None,
+                        false => Framework::of(&node.file_path).map(|source| source.root),
+                    },
+                    destination: framework.root,
+                    binary: framework.binary,
+                });
+            }
+            files.push(PlannedFile {
+                source: node.file_path.clone(),
+                destination,
+                edits: self.edits(id, libs_path),
+            });
+        }
+        let mut pruned = vec![];
+        for source in &self.pruned {
+            let metadata = std::fs::metadata(source).map_err(Error::io("reading", source))?;
+            pruned.push(PrunedLibrary {
+                source: source.clone(),
+                size: metadata.len(),
+            });
+        }
+        Ok(Plan {
+            inputs: self
+                .roots
+                .iter()
+                .map(|&id| self.nodes[id].file_path.clone())
+                .collect(),
+            output: dest_folder.to_path_buf(),
+            files,
+            frameworks,
+            pruned,
+            ..Default::default()
+        })
+    }
+
+    pub fn calculate_install_names(&mut self) -> Result<()> {
+        for node in self
+            .nodes
+            .iter_mut()
+            .filter(|node| !node.is_executable && !node.is_system)
+        {
+            if let Some(framework) = Framework::of(&node.file_path) {
+                node.install_name_new = Some(framework.install_name());
+                continue;
+            }
+            let Some(install_name) = node
                 .file_path
                 .file_name()
                 .map(|f_name| format!("@rpath/{}", f_name.display()))
             else {
                 return Err(anyhow!(
                     "Error while calculating rpath for: {}",
-                    self.file_path.display()
+                    node.file_path.display()
                 ));
             };
-            self.rpath = Some(rpath);
+            node.install_name_new = Some(install_name);
         }
+        Ok(())
+    }
 
-        for lib in &mut self.libs {
-            let _ = lib.calculate_rpaths()?;
+    // Libraries of a bundle repaired in place are named relative to the
+    // libraries folder, e.g. `@rpath/Qt.framework/Versions/A/Qt`. The id of
+    // one nothing in the bundle loads, e.g. the binary of a framework given
+    // as input, belongs to its users and is kept when it is already
+    // relative.
+    fn calculate_in_place_install_names(&mut self, libs_path: &Path) -> Result<()> {
+        for id in 0..self.nodes.len() {
+            let node = &self.nodes[id];
+            if !node.is_in_place || node.is_executable || node.is_system || node.is_missing {
+                continue;
+            }
+            let is_public = self.loaders(id).next().is_none()
+                && node
+                    .install_name_id
+                    .as_deref()
+                    .is_some_and(|name| name.starts_with('@'));
+            let install_name = if is_public {
+                None
+            } else {
+                let relative = relative_path(libs_path, node.dest_file_path()?);
+                Some(format!("@rpath/{}", relative.display()))
+            };
+            self.nodes[id].install_name_new = install_name;
         }
         Ok(())
     }
 
-    // Signing method based on walking in file system
-    // NOT USED
-    #[allow(dead_code)]
-    fn sign_all_alternative(&self) -> Result<()> {
-        let Some(ref base_dest_path) = self.dest_file_path else {
-            return Err(anyhow!(
-                "Error while retrieving destionation path of: {}",
-                self.file_path.display()
-            ));
-        };
-
-        let Some(ref libs_path) = self.libs_path else {
-            return Err(anyhow!(
-                "Error while retrieving libraries path of: {}",
-                self.file_path.display()
-            ));
-        };
+    fn set_dest_paths(&mut self, dest_folder: &Path, libs_path: &Path) -> Result<()> {
+        let mut taken = HashMap::<PathBuf, NodeId>::new();
+        for id in 0..self.nodes.len() {
+            let node = &self.nodes[id];
+            if node.is_system || node.is_missing {
+                continue;
+            }
+            let dest_folder = if self.is_placed_as_base(id) {
+                dest_folder.join(&node.base_folder)
+            } else {
+                dest_folder.join(libs_path)
+            };
+            let Some(file_name) = node.file_path.file_name() else {
+                return Err(anyhow!(
+                    "Error while retrieving file name of: {}",
+                    node.file_path.display()
+                ));
+            };
+            let dest_file_path = match Framework::of(&node.file_path) {
+                Some(framework) if !self.is_placed_as_base(id) => dest_folder
+                    .join(framework.folder_name())
+                    .join(&framework.binary),
+                _ => dest_folder.join(file_name),
+            };
+            log::debug!(
+                "Destination set for: {}\nto: {}",
+                node.file_path.display(),
+                dest_file_path.display()
+            );
+            if let Some(other) = taken.insert(dest_file_path.clone(), id) {
+                return Err(anyhow!(
+                    "Different libraries would be copied to: {}\nfirst: {}\nsecond: {}",
+                    dest_file_path.display(),
+                    self.nodes[other].file_path.display(),
+                    node.file_path.display()
+                ));
+            }
+            self.nodes[id].dest_file_path = Some(dest_file_path);
+        }
+        Ok(())
+    }
 
-        sign_binary(base_dest_path)?;
+    // A base binary other binaries depend on, e.g. a plugin library given
+    // as input and linked by an executable, goes to the libraries.
+    fn is_placed_as_base(&self, id: NodeId) -> bool {
+        let node = &self.nodes[id];
+        node.is_in_place || (node.is_base && self.loaders(id).next().is_none())
+    }
 
-        let Some(ref dest_folder_path) = self.dest_folder_path else {
-            return Err(anyhow!(
-                "Error while retrieving destionation folder path of: {}",
-                self.file_path.display()
-            ));
+    // Whether an rpath of a bundled node no longer leads to any bundled
+    // binary once the node is placed at its destination. Those are left
+    // over from the original location, while system locations such as
+    // `/usr/lib/swift` are still needed.
+    fn is_stale_rpath(&self, id: NodeId, rpath: &str) -> bool {
+        let folder = |id: NodeId| {
+            self.nodes[id]
+                .dest_file_path
+                .as_deref()
+                .and_then(Path::parent)
         };
-
-        for lib in std::fs::read_dir(dest_folder_path.join(libs_path))? {
-            let lib_path = lib?.path();
-            match check_file_type(&lib_path)? {
-                BinType::Dylib(_) => sign_binary(&lib_path)?,
-                _ => log::debug!("Not dynamic library, skipping signing..."),
+        let stale = |loader: NodeId, rest: &str| {
+            let Some(loader_folder) = folder(loader) else {
+                return false;
             };
+            let resolved = normalize_path(&loader_folder.join(rest.trim_start_matches('/')));
+            !self.bundled().any(|(other, _)| {
+                folder(other).is_some_and(|folder| normalize_path(folder) == resolved)
+            })
+        };
+        if let Some(rest) = rpath.strip_prefix("@loader_path") {
+            stale(id, rest)
+        } else if let Some(rest) = rpath.strip_prefix("@executable_path") {
+            self.roots.iter().all(|root| stale(*root, rest))
+        } else if rpath.starts_with('/') {
+            !is_system_folder(Path::new(rpath))
+        } else {
+            false
         }
-        Ok(())
     }
 
-    fn sign_all(&self) -> Result<()> {
-        let Some(ref dest_path) = self.dest_file_path else {
-            return Err(anyhow!(
-                "Error while retrieving destionation path of: {}",
-                self.file_path.display()
-            ));
-        };
-        sign_binary(dest_path)?;
+    // Load command changes of a bundled node, rpaths of the original
+    // location which lead nowhere inside the bundle are dropped.
+    fn edits(&self, id: NodeId, libs_path: &Path) -> Vec<Edit> {
+        let node = &self.nodes[id];
+        let mut edits = vec![];
+        if let Some(ref install_name) = node.install_name_new {
+            edits.push(Edit::SetId(install_name.clone()));
+        }
 
-        for lib in &self.libs {
-            let _ = lib.sign_all()?;
+        let mut rpath = PathBuf::from("@loader_path");
+        if self.is_placed_as_base(id) {
+            let relative = relative_path(&node.base_folder, libs_path);
+            // A library given as input may already sit in the libraries
+            // folder, `join` would leave a trailing slash.
+            if !relative.as_os_str().is_empty() {
+                rpath.push(relative);
+            }
         }
-        Ok(())
+        let rpath = rpath.to_string_lossy();
+        for old in node.rpaths.iter().filter(|old| **old != rpath) {
+            if self.is_stale_rpath(id, old) {
+                edits.push(Edit::DeleteRpath(old.clone()));
+            }
+        }
+        if !node.rpaths.iter().any(|old| *old == rpath) {
+            edits.push(Edit::AddRpath(rpath.to_string()));
+        }
+
+        for install_name in &node.removed_dependencies {
+            edits.push(Edit::RemoveDylib(install_name.clone()));
+        }
+        for edge in self.dependencies(id) {
+            let Some(ref new_install_name) = self.nodes[edge.to].install_name_new else {
+                continue;
+            };
+            edits.push(Edit::ChangeInstallName {
+                old: edge.install_name.clone(),
+                new: new_install_name.clone(),
+            });
+        }
+        edits
+    }
+}
+
+/// Path of `target` relative to `folder`, both relative to the same folder.
+/// `folder` must not contain `..` components.
+fn relative_path(folder: &Path, target: &Path) -> PathBuf {
+    let mut folder = folder.components().peekable();
+    let mut target = target.components().peekable();
+    while folder.peek().is_some() && folder.peek() == target.peek() {
+        folder.next();
+        target.next();
     }
+    folder.map(|_| Component::ParentDir).chain(target).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::{Call, NativeBackend, RecordBackend, Recording, ReplayBackend};
+    use crate::helpers::*;
+    use crate::macho::tests::_set_dylib_kind;
+    use std::collections::HashSet;
     #[test]
     fn test_binary() {
         let binary = Binary::default();
         println!("Default initialization:\n{:#?}", binary);
         assert!(!binary.is_executable);
     }
+
+    #[test]
+    fn test_fix_install_names() {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let output = tempfile::tempdir().unwrap();
+        let binary = Binary::new(input, true, true).unwrap();
+
+        let resolver = Resolver::new(&binary.file_path);
+        let mut graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+        let plan = graph.plan(output.path(), None).unwrap();
+        assert_eq!(std::fs::read_dir(output.path()).unwrap().count(), 0);
+        let freetype = plan
+            .files
+            .iter()
+            .find(|file| file.destination == Path::new("libs/libfreetype.6.dylib"))
+            .unwrap();
+        assert!(
+            freetype
+                .edits
+                .contains(&Edit::SetId("@rpath/libfreetype.6.dylib".to_string()))
+        );
+        plan.apply(output.path(), &NativeBackend).unwrap();
+
+        let rpaths = get_rpaths(&output.path().join("pdftoppm"), true).unwrap();
+        assert!(rpaths.contains(&"@loader_path/libs".to_string()));
+        let lib = output.path().join("libs/libfreetype.6.dylib");
+        assert_eq!(get_id(&lib).unwrap(), "@rpath/libfreetype.6.dylib");
+        let deps = get_dependencies(&lib).unwrap();
+        assert!(deps.iter().any(|d| d.name == "@rpath/libpng16.16.dylib"));
+    }
+
+    #[test]
+    fn test_system_rpaths_kept() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("libs")).unwrap();
+        for entry in std::fs::read_dir(resources.join("libs")).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(
+                entry.path(),
+                root.path().join("libs").join(entry.file_name()),
+            )
+            .unwrap();
+        }
+        let input = root.path().join("pdftoppm");
+        std::fs::copy(resources.join("pdftoppm"), &input).unwrap();
+        add_rpath(&input, Path::new("/usr/lib/swift")).unwrap();
+        add_rpath(&input, Path::new("/opt/homebrew/lib")).unwrap();
+
+        let binary = Binary::new(input, true, true).unwrap();
+        let resolver = Resolver::new(&binary.file_path);
+        let mut graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let plan = graph.plan(output.path(), None).unwrap();
+        let edits = &plan
+            .files
+            .iter()
+            .find(|file| file.destination == Path::new("pdftoppm"))
+            .unwrap()
+            .edits;
+        assert!(edits.contains(&Edit::DeleteRpath("/opt/homebrew/lib".to_string())));
+        assert!(edits.contains(&Edit::DeleteRpath("@loader_path/../lib".to_string())));
+        assert!(!edits.contains(&Edit::DeleteRpath("/usr/lib/swift".to_string())));
+        assert!(!edits.contains(&Edit::DeleteRpath("@loader_path/libs".to_string())));
+    }
+
+    #[test]
+    fn test_run() {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let output = tempfile::tempdir().unwrap();
+        let binary = Binary::new(input, true, true).unwrap();
+        binary
+            .run(output.path(), None, &PathMapper::default(), &NativeBackend)
+            .unwrap();
+
+        for file in [
+            output.path().join("pdftoppm"),
+            output.path().join("libs/libpoppler.154.0.0.dylib"),
+        ] {
+            let data = std::fs::read(&file).unwrap();
+            crate::codesign::verify_slice(&data).unwrap();
+        }
+    }
+
+    /// Copies the poppler resources into `root/bin` and `root/lib`.
+    fn _copy_poppler(root: &Path) {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::copy(resources.join("pdftoppm"), root.join("bin/pdftoppm")).unwrap();
+        for entry in std::fs::read_dir(resources.join("libs")).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), root.join("lib").join(entry.file_name())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_inherited_rpaths() {
+        // bin/pdftoppm finds its libraries through `@loader_path/../lib`,
+        // libpoppler itself has no rpath left and relies on the executable's.
+        let root = tempfile::tempdir().unwrap();
+        _copy_poppler(root.path());
+        let libpoppler = root.path().join("lib/libpoppler.154.0.0.dylib");
+        remove_rpath(&libpoppler, "@loader_path").unwrap();
+
+        let binary = Binary::new(root.path().join("bin/pdftoppm"), true, true).unwrap();
+        let resolver = Resolver::new(&binary.file_path);
+        let graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+        let poppler = graph.find(&libpoppler.canonicalize().unwrap()).unwrap();
+        assert!(graph.dependencies(poppler).count() > 0);
+    }
+
+    #[test]
+    fn test_loader_and_executable_path_install_names() {
+        let root = tempfile::tempdir().unwrap();
+        _copy_poppler(root.path());
+        let exe = root.path().join("bin/pdftoppm");
+        fix_install_name(
+            &exe,
+            "@rpath/liblcms2.2.dylib",
+            "@executable_path/../lib/liblcms2.2.dylib",
+        )
+        .unwrap();
+        let libfreetype = root.path().join("lib/libfreetype.6.dylib");
+        fix_install_name(
+            &libfreetype,
+            "@rpath/libpng16.16.dylib",
+            "@loader_path/libpng16.16.dylib",
+        )
+        .unwrap();
+
+        let output = tempfile::tempdir().unwrap();
+        let binary = Binary::new(exe, true, true).unwrap();
+        binary
+            .run(output.path(), None, &PathMapper::default(), &NativeBackend)
+            .unwrap();
+
+        let deps = get_dependencies(&output.path().join("pdftoppm")).unwrap();
+        assert!(deps.iter().any(|d| d.name == "@rpath/liblcms2.2.dylib"));
+        let deps = get_dependencies(&output.path().join("libs/libfreetype.6.dylib")).unwrap();
+        assert!(deps.iter().any(|d| d.name == "@rpath/libpng16.16.dylib"));
+        assert!(output.path().join("libs/libpng16.16.dylib").is_file());
+    }
+
+    #[test]
+    fn test_relative_install_name() {
+        let root = tempfile::tempdir().unwrap();
+        _copy_poppler(root.path());
+        let exe = root.path().join("bin/pdftoppm");
+        fix_install_name(&exe, "@rpath/liblcms2.2.dylib", "liblcms2.2.dylib").unwrap();
+
+        let binary = Binary::new(exe, true, true).unwrap();
+        let resolver = Resolver::new(&binary.file_path);
+        assert!(Graph::collect(binary, &resolver, &NativeBackend).is_err());
+    }
+
+    #[test]
+    fn test_missing_weak_library() {
+        let root = tempfile::tempdir().unwrap();
+        _copy_poppler(root.path());
+        let libpoppler = root.path().join("lib/libpoppler.154.0.0.dylib");
+        _set_dylib_kind(
+            &libpoppler,
+            "@rpath/libopenjp2.2.5.4.dylib",
+            DylibKind::Weak,
+        );
+        std::fs::remove_file(root.path().join("lib/libopenjp2.2.5.4.dylib")).unwrap();
+
+        let binary = Binary::new(root.path().join("bin/pdftoppm"), true, true).unwrap();
+        let resolver = Resolver::new(&binary.file_path);
+        let mut graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+        let openjp2 = graph
+            .find(Path::new("@rpath/libopenjp2.2.5.4.dylib"))
+            .unwrap();
+        assert!(graph.node(openjp2).is_missing());
+        assert!(graph.dependents(openjp2).all(|e| e.kind == DylibKind::Weak));
+
+        let plan = graph.plan(Path::new("dist"), None).unwrap();
+        assert!(
+            plan.files
+                .iter()
+                .all(|file| !file.destination.ends_with("libopenjp2.2.5.4.dylib"))
+        );
+        let poppler = plan
+            .files
+            .iter()
+            .find(|file| file.destination == Path::new("libs/libpoppler.154.0.0.dylib"))
+            .unwrap();
+        assert!(poppler.edits.contains(&Edit::ChangeInstallName {
+            old: "@rpath/libopenjp2.2.5.4.dylib".to_string(),
+            new: "@rpath/libopenjp2.2.5.4.dylib".to_string(),
+        }));
+
+        // A missing library which isn't weakly linked is still an error.
+        _set_dylib_kind(
+            &libpoppler,
+            "@rpath/libopenjp2.2.5.4.dylib",
+            DylibKind::Load,
+        );
+        let binary = Binary::new(root.path().join("bin/pdftoppm"), true, true).unwrap();
+        assert!(Graph::collect(binary, &resolver, &NativeBackend).is_err());
+    }
+
+    #[test]
+    fn test_upward_link_to_base_library() {
+        // libfreetype links back to libpoppler given as input, which keeps
+        // its place instead of moving to the libraries folder.
+        let root = tempfile::tempdir().unwrap();
+        _copy_poppler(root.path());
+        let libpoppler = root.path().join("lib/libpoppler.154.0.0.dylib");
+        let libfreetype = root.path().join("lib/libfreetype.6.dylib");
+        fix_install_name(
+            &libfreetype,
+            "@rpath/libpng16.16.dylib",
+            "@rpath/libpoppler.154.0.0.dylib",
+        )
+        .unwrap();
+        _set_dylib_kind(
+            &libfreetype,
+            "@rpath/libpoppler.154.0.0.dylib",
+            DylibKind::Upward,
+        );
+
+        let binary = Binary::new(libpoppler.clone(), false, true).unwrap();
+        let resolver = Resolver::new(&libpoppler);
+        let mut graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+        assert!(
+            graph
+                .dependents(graph.root())
+                .any(|e| e.kind == DylibKind::Upward)
+        );
+        let plan = graph.plan(Path::new("dist"), None).unwrap();
+        let destinations: Vec<_> = plan.files.iter().map(|f| f.destination.as_path()).collect();
+        assert!(destinations.contains(&Path::new("libpoppler.154.0.0.dylib")));
+        assert!(destinations.contains(&Path::new("libs/libfreetype.6.dylib")));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_with_sysroot() {
+        // libfreetype references libpng by an absolute Homebrew path that is a
+        // symlink into the Cellar, lib/libpng16.16.dylib is an absolute
+        // symlink too. Both only exist under the sysroot.
+        let sysroot = tempfile::tempdir().unwrap();
+        let sysroot = sysroot.path().canonicalize().unwrap();
+        _copy_poppler(&sysroot);
+        let cellar = sysroot.join("opt/homebrew/Cellar/libpng/1.6.47/lib");
+        std::fs::create_dir_all(&cellar).unwrap();
+        std::fs::rename(
+            sysroot.join("lib/libpng16.16.dylib"),
+            cellar.join("libpng16.16.dylib"),
+        )
+        .unwrap();
+        std::fs::create_dir_all(sysroot.join("opt/homebrew/opt")).unwrap();
+        std::os::unix::fs::symlink(
+            "/opt/homebrew/Cellar/libpng/1.6.47",
+            sysroot.join("opt/homebrew/opt/libpng"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            "/opt/homebrew/opt/libpng/lib/libpng16.16.dylib",
+            sysroot.join("lib/libpng16.16.dylib"),
+        )
+        .unwrap();
+        fix_install_name(
+            &sysroot.join("lib/libfreetype.6.dylib"),
+            "@rpath/libpng16.16.dylib",
+            "/opt/homebrew/opt/libpng/lib/libpng16.16.dylib",
+        )
+        .unwrap();
+
+        let output = tempfile::tempdir().unwrap();
+        let exe = sysroot.join("bin/pdftoppm");
+        let binary = Binary::new(exe.clone(), true, true).unwrap();
+        assert!(
+            binary
+                .run(output.path(), None, &PathMapper::default(), &NativeBackend)
+                .is_err()
+        );
+
+        let output = tempfile::tempdir().unwrap();
+        let mapper = PathMapper::new(Some(sysroot.clone()), vec![]);
+        let binary = Binary::new(exe, true, true).unwrap();
+        binary
+            .run(output.path(), None, &mapper, &NativeBackend)
+            .unwrap();
+        let deps = get_dependencies(&output.path().join("libs/libfreetype.6.dylib")).unwrap();
+        assert!(deps.iter().any(|d| d.name == "@rpath/libpng16.16.dylib"));
+        assert!(output.path().join("libs/libpng16.16.dylib").is_file());
+    }
+
+    #[test]
+    fn test_shared_libraries_collected_once() {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let binary = Binary::new(input, true, true).unwrap();
+        let resolver = Resolver::new(&binary.file_path);
+        let graph = Graph::collect(binary, &resolver, &NativeBackend).unwrap();
+
+        let paths: HashSet<_> = graph.nodes().iter().map(|n| n.file_path()).collect();
+        assert_eq!(paths.len(), graph.nodes().len());
+        assert!(graph.edges().len() > graph.nodes().len() - 1);
+        let libpng = graph
+            .nodes()
+            .iter()
+            .position(|n| n.file_path().ends_with("libpng16.16.dylib"))
+            .unwrap();
+        assert!(graph.dependents(libpng).count() > 1);
+        assert!(
+            graph
+                .dependents(libpng)
+                .all(|edge| edge.install_name == "@rpath/libpng16.16.dylib")
+        );
+    }
+
+    #[test]
+    fn test_same_uuid_merged() {
+        // libfreetype loads a byte identical copy of libpng from another
+        // folder, both copies have to end up as a single node.
+        let root = tempfile::tempdir().unwrap();
+        _copy_poppler(root.path());
+        let lib = root.path().join("lib");
+        std::fs::create_dir_all(root.path().join("other")).unwrap();
+        let copy = root.path().join("other/libpng16.16.dylib");
+        std::fs::copy(lib.join("libpng16.16.dylib"), &copy).unwrap();
+        let copy = copy.canonicalize().unwrap();
+        fix_install_name(
+            &lib.join("libfreetype.6.dylib"),
+            "@rpath/libpng16.16.dylib",
+            copy.to_str().unwrap(),
+        )
+        .unwrap();
+
+        let output = tempfile::tempdir().unwrap();
+        let binary = Binary::new(root.path().join("bin/pdftoppm"), true, true).unwrap();
+        let graph = binary
+            .run(output.path(), None, &PathMapper::default(), &NativeBackend)
+            .unwrap();
+        assert_eq!(
+            graph.find(&copy),
+            graph.find(&lib.join("libpng16.16.dylib").canonicalize().unwrap())
+        );
+        let deps = get_dependencies(&output.path().join("libs/libfreetype.6.dylib")).unwrap();
+        assert!(deps.iter().any(|d| d.name == "@rpath/libpng16.16.dylib"));
+    }
+
+    const REPLAY_FIXTURE: &str = "test_resources/poppler/replay.json";
+
+    // Refreshes the replay fixture, run with `cargo test -- --ignored` after
+    // changing the poppler resources.
+    #[test]
+    #[ignore]
+    fn record_replay_fixture() {
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources/poppler")
+            .canonicalize()
+            .unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let output_path = output.path().canonicalize().unwrap();
+        let recorder = RecordBackend::new(
+            NativeBackend,
+            &[("$INPUT", &resources), ("$OUTPUT", &output_path)],
+        );
+        Binary::new(resources.join("pdftoppm"), true, true)
+            .unwrap()
+            .run(&output_path, None, &PathMapper::default(), &recorder)
+            .unwrap();
+        recorder
+            .recording()
+            .save(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(REPLAY_FIXTURE))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_replayed() {
+        // Empty files stand in for the binaries, everything the bundler
+        // needs to know about them comes from the recording.
+        let recording =
+            Recording::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(REPLAY_FIXTURE))
+                .unwrap();
+        let input = tempfile::tempdir().unwrap();
+        let input_path = input.path().canonicalize().unwrap();
+        for key in recording.images.keys() {
+            let file = input_path.join(key.strip_prefix("$INPUT/").unwrap());
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, b"").unwrap();
+        }
+        let output = tempfile::tempdir().unwrap();
+        let output_path = output.path().canonicalize().unwrap();
+
+        let replay = ReplayBackend::new(
+            &recording,
+            &[("$INPUT", &input_path), ("$OUTPUT", &output_path)],
+        );
+        let graph = Binary::new(input_path.join("pdftoppm"), true, true)
+            .unwrap()
+            .run(&output_path, None, &PathMapper::default(), &replay)
+            .unwrap();
+
+        assert_eq!(replay.calls(), recording.calls);
+        assert_eq!(
+            graph.bundled().count(),
+            recording.images.len(),
+            "every recorded image is bundled once"
+        );
+        assert!(
+            graph
+                .bundled()
+                .filter(|(_, node)| !node.is_executable)
+                .all(|(_, node)| node.compatibility_version.is_some())
+        );
+        assert!(graph.incompatible_versions().is_empty());
+        assert!(output_path.join("libs/libpoppler.154.0.0.dylib").is_file());
+        // pdftoppm already has `@loader_path/libs`, only the other rpath goes.
+        let Some(Call::Change { edits, .. }) = replay
+            .calls()
+            .into_iter()
+            .find(|call| matches!(call, Call::Change { file, .. } if file == "$OUTPUT/pdftoppm"))
+        else {
+            panic!("pdftoppm was not changed");
+        };
+        assert!(edits.contains(&Edit::DeleteRpath("@loader_path/../lib".to_string())));
+        assert!(!edits.iter().any(|edit| matches!(edit, Edit::AddRpath(_))));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let rel = |folder: &str, target: &str| relative_path(Path::new(folder), Path::new(target));
+        assert_eq!(rel("", "libs"), Path::new("libs"));
+        assert_eq!(rel("tools", "libs"), Path::new("../libs"));
+        assert_eq!(rel("a/b", "a/libs"), Path::new("../libs"));
+        assert_eq!(rel("tools", "../Frameworks"), Path::new("../../Frameworks"));
+    }
 }
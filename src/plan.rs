@@ -0,0 +1,309 @@
+// Everything a bundling run is going to do, before it does it.
+//
+// A plan lists every file to copy with its destination and the load command
+// edits applied to the copy, frameworks are copied as whole folders first.
+// It is produced by discovery alone and nothing is written while building
+// it. Plans are saved as JSON or TOML so that they can be reviewed or edited
+// by hand and applied later, applying executes exactly the listed copies and
+// edits and signs every copied file.
+
+use crate::backend::{Backend, Image};
+use crate::error::Error;
+use crate::helpers::copy_tree;
+use crate::report::{BundleReport, CopiedFile, PrunedLibrary, RewrittenCommand};
+use crate::rewrite::Edit;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    /// Binaries and output folder the plan was made for.
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub files: Vec<PlannedFile>,
+    /// Frameworks the files belong to, signed as a whole.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frameworks: Vec<PlannedFramework>,
+    /// Empty folders to create, e.g. `Contents/Resources` of an app.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub folders: Vec<PathBuf>,
+    /// Files written from the plan itself, e.g. `Info.plist` of an app.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub generated: Vec<GeneratedFile>,
+    /// Libraries left out because nothing imports symbols from them, only
+    /// reported.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pruned: Vec<PrunedLibrary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub source: PathBuf,
+    /// Relative to the output folder.
+    pub destination: PathBuf,
+    #[serde(default)]
+    pub edits: Vec<Edit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedFramework {
+    /// The `.framework` folder copied before its binary, nothing is copied
+    /// for a framework already in the output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    /// Relative to the output folder.
+    pub destination: PathBuf,
+    /// Path of the binary inside the framework, e.g. `Versions/A/QtCore`.
+    pub binary: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GeneratedFile {
+    /// Relative to the output folder.
+    pub destination: PathBuf,
+    pub contents: String,
+}
+
+impl Plan {
+    /// Reads a plan, TOML if the file name ends with `.toml` and JSON
+    /// otherwise.
+    pub fn load(file_path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(file_path).map_err(Error::io("reading", file_path))?;
+        let plan = if is_toml(file_path) {
+            toml::from_str(&data).map_err(anyhow::Error::from)
+        } else {
+            serde_json::from_str(&data).map_err(anyhow::Error::from)
+        };
+        plan.with_context(|| format!("Error while parsing plan: {}", file_path.display()))
+    }
+
+    pub fn save(&self, file_path: &Path) -> Result<()> {
+        let text = if is_toml(file_path) {
+            self.to_toml()?
+        } else {
+            self.to_json()?
+        };
+        std::fs::write(file_path, text).map_err(Error::io("writing", file_path))?;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)? + "\n")
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Copies, edits and signs the planned files inside `output`, which
+    /// replaces the output folder of the plan.
+    pub fn apply(&self, output: &Path, backend: &dyn Backend) -> Result<BundleReport> {
+        let mut report = BundleReport {
+            pruned: self.pruned.clone(),
+            ..Default::default()
+        };
+        for folder in &self.folders {
+            let folder = output.join(folder);
+            log::debug!("Creating folder: {}", folder.display());
+            std::fs::create_dir_all(&folder).map_err(Error::io("creating folder", &folder))?;
+        }
+        for file in &self.generated {
+            let destination = output.join(&file.destination);
+            write_file(&destination, &file.contents)?;
+            report.generated.push(destination);
+        }
+
+        for framework in &self.frameworks {
+            if let Some(ref source) = framework.source {
+                copy_framework(source, &output.join(&framework.destination))?;
+            }
+        }
+
+        for file in &self.files {
+            let destination = output.join(&file.destination);
+            copy_file(&file.source, &destination)?;
+            report.copied.push(CopiedFile {
+                source: file.source.clone(),
+                destination,
+            });
+        }
+
+        for file in &self.files {
+            let destination = output.join(&file.destination);
+            if file.edits.is_empty() {
+                continue;
+            }
+            let image = backend.inspect(&file.source)?;
+            for edit in &file.edits {
+                report
+                    .rewritten
+                    .push(rewritten_command(&destination, edit, &image));
+            }
+            backend.change(&destination, &file.edits).with_context(|| {
+                format!(
+                    "Error while changing load commands of: {}",
+                    destination.display()
+                )
+            })?;
+            log::info!(
+                "Load commands fixed for: {}\n{} changes",
+                destination.display(),
+                file.edits.len()
+            );
+        }
+
+        // Framework binaries are signed with their framework, nested
+        // frameworks before the ones containing them.
+        let framework_binaries: Vec<PathBuf> = self
+            .frameworks
+            .iter()
+            .map(|framework| framework.destination.join(&framework.binary))
+            .collect();
+        let mut signed: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|file| !framework_binaries.contains(&file.destination))
+            .map(|file| file.destination.clone())
+            .collect();
+        let mut folders: Vec<PathBuf> = self
+            .frameworks
+            .iter()
+            .map(|framework| match framework.binary.parent() {
+                Some(parent) => framework.destination.join(parent),
+                None => framework.destination.clone(),
+            })
+            .collect();
+        folders.sort_by_key(|folder| std::cmp::Reverse(folder.components().count()));
+        signed.extend(folders);
+
+        for destination in signed {
+            let destination = output.join(destination);
+            backend.sign(&destination).with_context(|| {
+                format!("Error while signing binary: {}", destination.display())
+            })?;
+            log::info!("Binary signed successfully: {}", destination.display());
+            report.signed.push(destination);
+        }
+        Ok(report)
+    }
+}
+
+fn is_toml(file_path: &Path) -> bool {
+    file_path.extension().is_some_and(|ext| ext == "toml")
+}
+
+// A file left by an earlier run may be read-only or outdated, it is always
+// replaced.
+fn copy_file(source: &Path, destination: &Path) -> Result<()> {
+    if let Some(folder) = destination.parent() {
+        log::debug!("Creating folder: {}", folder.display());
+        std::fs::create_dir_all(folder).map_err(Error::io("creating folder", folder))?;
+    }
+    if destination.symlink_metadata().is_ok() {
+        log::debug!("Replacing: {}", destination.display());
+        std::fs::remove_file(destination).map_err(Error::io("removing", destination))?;
+    }
+    std::fs::copy(source, destination).map_err(Error::io("copying", source))?;
+    log::info!(
+        "Copying: {}\nto: {}",
+        source.display(),
+        destination.display()
+    );
+    Ok(())
+}
+
+// A framework left by an earlier run is replaced as a whole.
+fn copy_framework(source: &Path, destination: &Path) -> Result<()> {
+    if destination.symlink_metadata().is_ok() {
+        log::debug!("Replacing: {}", destination.display());
+        std::fs::remove_dir_all(destination).map_err(Error::io("removing", destination))?;
+    }
+    std::fs::create_dir_all(destination).map_err(Error::io("creating folder", destination))?;
+    copy_tree(source, destination, &[])?;
+    log::info!(
+        "Copying framework: {}\nto: {}",
+        source.display(),
+        destination.display()
+    );
+    Ok(())
+}
+
+fn write_file(destination: &Path, contents: &str) -> Result<()> {
+    if let Some(folder) = destination.parent() {
+        std::fs::create_dir_all(folder).map_err(Error::io("creating folder", folder))?;
+    }
+    std::fs::write(destination, contents).map_err(Error::io("writing", destination))?;
+    log::info!("Writing: {}", destination.display());
+    Ok(())
+}
+
+// The old values and load command kinds are taken from the source file, the
+// plan only carries what install_name_tool would be told.
+fn rewritten_command(file: &Path, edit: &Edit, image: &Image) -> RewrittenCommand {
+    let (command, old, new) = match edit {
+        Edit::SetId(new) => ("LC_ID_DYLIB", image.id.clone(), Some(new.clone())),
+        Edit::ChangeInstallName { old, new } => {
+            let command = image
+                .dependencies
+                .iter()
+                .find(|dylib| dylib.name == *old)
+                .map_or("LC_LOAD_DYLIB", |dylib| dylib.kind.name());
+            (command, Some(old.clone()), Some(new.clone()))
+        }
+        Edit::AddRpath(new) => ("LC_RPATH", None, Some(new.clone())),
+        Edit::DeleteRpath(old) => ("LC_RPATH", Some(old.clone()), None),
+        Edit::ChangeRpath { old, new } => ("LC_RPATH", Some(old.clone()), Some(new.clone())),
+        Edit::RemoveDylib(old) => {
+            let command = image
+                .dependencies
+                .iter()
+                .find(|dylib| dylib.name == *old)
+                .map_or("LC_LOAD_DYLIB", |dylib| dylib.kind.name());
+            (command, Some(old.clone()), None)
+        }
+    };
+    RewrittenCommand {
+        file: file.to_path_buf(),
+        command,
+        old,
+        new,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Binary, Graph};
+    use crate::resolver::Resolver;
+
+    fn _poppler_plan() -> Plan {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let resolver = Resolver::new(&input);
+        let binary = Binary::new(input, true, true).unwrap();
+        let mut graph = Graph::collect(binary, &resolver, &crate::backend::NativeBackend).unwrap();
+        graph.plan(Path::new("dist"), None).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let plan = _poppler_plan();
+        assert_eq!(plan.output, Path::new("dist"));
+        assert!(plan.files.iter().any(|file| {
+            file.destination == Path::new("pdftoppm")
+                && file
+                    .edits
+                    .contains(&Edit::DeleteRpath("@loader_path/../lib".to_string()))
+        }));
+
+        let folder = tempfile::tempdir().unwrap();
+        for name in ["plan.json", "plan.toml"] {
+            let file_path = folder.path().join(name);
+            plan.save(&file_path).unwrap();
+            assert_eq!(Plan::load(&file_path).unwrap(), plan);
+        }
+        let toml = std::fs::read_to_string(folder.path().join("plan.toml")).unwrap();
+        assert!(toml.contains("destination = \"libs/libpoppler.154.0.0.dylib\""));
+    }
+}
@@ -0,0 +1,387 @@
+// Which dependencies macOS provides and which are bundled.
+//
+// Every absolute install name is decided in this order:
+//
+// 1. `deny` globs: always bundled, e.g. a newer libcurl than the system one.
+// 2. `allow` globs: always left to the system, e.g. `/Library/Frameworks/**`
+//    installed on every target machine.
+// 3. `/System/Library/...`: system.
+// 4. `/usr/lib/...`: system when macOS ships the library. Since macOS 11 most
+//    of them only exist in the dyld shared cache, so they can't be found on
+//    disk anyway. The libraries shipped are taken from the built-in list
+//    below for the targeted macOS version and from the `.tbd` stubs of an
+//    SDK. Anything else found on disk was installed by someone else, it is
+//    bundled and flagged.
+// 5. Everything else is bundled, `/usr/local/...` is flagged as well since it
+//    is a common leak from the build machine.
+
+use crate::error::Error;
+use crate::macho::Version;
+use crate::sysroot::PathMapper;
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher, GlobSet, GlobSetBuilder};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+// Libraries under `/usr/lib` shipped with macOS, with the first version
+// shipping them and the first one not providing them anymore.
+const SHIPPED_LIBRARIES: &[(&str, Version, Option<Version>)] = &[
+    ("/usr/lib/libSystem.B.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/system/*.dylib", Version::new(10, 7, 0), None),
+    ("/usr/lib/libc++.1.dylib", Version::new(10, 7, 0), None),
+    ("/usr/lib/libc++abi.dylib", Version::new(10, 7, 0), None),
+    ("/usr/lib/libobjc.A.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libgcc_s.1.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libz.1.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libbz2.1.0.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/liblzma.5.dylib", Version::new(10, 13, 0), None),
+    (
+        "/usr/lib/libcompression.dylib",
+        Version::new(10, 11, 0),
+        None,
+    ),
+    ("/usr/lib/libarchive.2.dylib", Version::new(10, 5, 0), None),
+    ("/usr/lib/libiconv.2.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libcharset.1.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libicucore.A.dylib", Version::new(10, 3, 0), None),
+    ("/usr/lib/libxml2.2.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libxslt.1.dylib", Version::new(10, 4, 0), None),
+    ("/usr/lib/libexslt.0.dylib", Version::new(10, 4, 0), None),
+    ("/usr/lib/libexpat.1.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libsqlite3.dylib", Version::new(10, 4, 0), None),
+    ("/usr/lib/libcurl.4.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libedit.3.dylib", Version::new(10, 5, 0), None),
+    (
+        "/usr/lib/libncurses.5.4.dylib",
+        Version::new(10, 0, 0),
+        None,
+    ),
+    ("/usr/lib/libform.5.4.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libmenu.5.4.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libpanel.5.4.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libffi.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libresolv.9.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libpam.2.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libbsm.0.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libpcap.A.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libsasl2.2.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libutil.dylib", Version::new(10, 0, 0), None),
+    ("/usr/lib/libxar.1.dylib", Version::new(10, 5, 0), None),
+    (
+        "/usr/lib/libDiagnosticMessagesClient.dylib",
+        Version::new(10, 7, 0),
+        None,
+    ),
+    (
+        "/usr/lib/swift/libswift*.dylib",
+        Version::new(10, 14, 4),
+        None,
+    ),
+    // Loading the unversioned LibreSSL libraries aborts since Catalina.
+    (
+        "/usr/lib/libcrypto.dylib",
+        Version::new(10, 0, 0),
+        Some(Version::new(10, 15, 0)),
+    ),
+    (
+        "/usr/lib/libssl.dylib",
+        Version::new(10, 0, 0),
+        Some(Version::new(10, 15, 0)),
+    ),
+];
+
+/// Whether a dependency is bundled, with a warning about it if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub is_system: bool,
+    pub warning: Option<String>,
+}
+
+impl Decision {
+    fn system() -> Self {
+        Decision {
+            is_system: true,
+            warning: None,
+        }
+    }
+
+    fn bundle() -> Self {
+        Decision {
+            is_system: false,
+            warning: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemPolicy {
+    allow: GlobSet,
+    deny: GlobSet,
+    macos_version: Option<Version>,
+    shipped: Vec<(GlobMatcher, Version, Option<Version>)>,
+    sdk_libraries: BTreeSet<String>,
+}
+
+impl Default for SystemPolicy {
+    fn default() -> Self {
+        SystemPolicy {
+            allow: GlobSet::empty(),
+            deny: GlobSet::empty(),
+            macos_version: None,
+            shipped: SHIPPED_LIBRARIES
+                .iter()
+                .map(|&(pattern, since, until)| {
+                    let glob = Glob::new(pattern).expect("built-in patterns are valid");
+                    (glob.compile_matcher(), since, until)
+                })
+                .collect(),
+            sdk_libraries: BTreeSet::new(),
+        }
+    }
+}
+
+impl SystemPolicy {
+    /// Install names matching any of `patterns` are left to the system.
+    pub fn allow(mut self, patterns: &[String]) -> Result<Self> {
+        self.allow = glob_set(patterns)?;
+        Ok(self)
+    }
+
+    /// Install names matching any of `patterns` are always bundled.
+    pub fn deny(mut self, patterns: &[String]) -> Result<Self> {
+        self.deny = glob_set(patterns)?;
+        Ok(self)
+    }
+
+    /// Only trust the built-in list for libraries shipped with `version`. By
+    /// default every library macOS ever shipped is trusted.
+    pub fn macos_version(mut self, version: Version) -> Self {
+        self.macos_version = Some(version);
+        self
+    }
+
+    /// Adds the libraries of an SDK, read from the install names of its
+    /// `usr/lib` `.tbd` stubs.
+    pub fn sdk(mut self, sdk: &Path) -> Result<Self> {
+        let folder = sdk.join("usr/lib");
+        let mut stubs = vec![];
+        find_stubs(&folder, &mut stubs)?;
+        for stub in stubs {
+            let text = std::fs::read_to_string(&stub).map_err(Error::io("reading", &stub))?;
+            let names = stub_install_names(&text)
+                .with_context(|| format!("Error while parsing: {}", stub.display()))?;
+            self.sdk_libraries.extend(names);
+        }
+        log::debug!(
+            "Libraries found in SDK: {}\n{}",
+            sdk.display(),
+            self.sdk_libraries.len()
+        );
+        Ok(self)
+    }
+
+    /// Whether macOS provides `install_name` at the targeted version.
+    pub fn is_shipped(&self, install_name: &str) -> bool {
+        if self.sdk_libraries.contains(install_name) {
+            return true;
+        }
+        self.shipped.iter().any(|(matcher, since, until)| {
+            matcher.is_match(install_name)
+                && self.macos_version.is_none_or(|version| {
+                    *since <= version && until.is_none_or(|until| version < until)
+                })
+        })
+    }
+
+    /// Decides an install name, `mapper` tells whether it exists on disk.
+    /// Relative install names are never system libraries.
+    pub fn decide(&self, install_name: &str, mapper: &PathMapper) -> Decision {
+        if self.deny.is_match(install_name) {
+            return Decision::bundle();
+        }
+        if self.allow.is_match(install_name) || install_name.starts_with("/System/Library/") {
+            return Decision::system();
+        }
+        if install_name.starts_with("/usr/lib/") {
+            if self.is_shipped(install_name) {
+                return Decision::system();
+            }
+            if mapper.map(Path::new(install_name)).is_file() {
+                return Decision {
+                    is_system: false,
+                    warning: Some(format!(
+                        "Not shipped with macOS{}, bundled: {}",
+                        self.version_suffix(),
+                        install_name
+                    )),
+                };
+            }
+            return Decision {
+                is_system: true,
+                warning: Some(format!(
+                    "Not known to ship with macOS{} and not found on disk, left to the system: {}",
+                    self.version_suffix(),
+                    install_name
+                )),
+            };
+        }
+        if install_name.starts_with("/usr/local/") {
+            return Decision {
+                is_system: false,
+                warning: Some(format!("Not shipped with macOS, bundled: {}", install_name)),
+            };
+        }
+        Decision::bundle()
+    }
+
+    fn version_suffix(&self) -> String {
+        self.macos_version
+            .map(|version| format!(" {}", Short(version)))
+            .unwrap_or_default()
+    }
+}
+
+// `10.15` instead of `10.15.0`.
+struct Short(Version);
+
+impl fmt::Display for Short {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = self.0;
+        match version.patch() {
+            0 => write!(f, "{}.{}", version.major(), version.minor()),
+            _ => write!(f, "{}", version),
+        }
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob: {}", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+fn find_stubs(folder: &Path, stubs: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    let entries = std::fs::read_dir(folder).map_err(Error::io("reading folder", folder))?;
+    for entry in entries {
+        let path = entry.map_err(Error::io("reading folder", folder))?.path();
+        if path.is_dir() {
+            find_stubs(&path, stubs)?;
+        } else if path.extension().is_some_and(|ext| ext == "tbd") {
+            stubs.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Install names of a text stub, YAML up to version 4 and JSON since version
+// 5. A stub lists the reexported libraries of an umbrella as documents of
+// their own, e.g. `/usr/lib/system/libsystem_c.dylib` in `libSystem.tbd`.
+fn stub_install_names(text: &str) -> Result<Vec<String>> {
+    if text.trim_start().starts_with('{') {
+        let stub: serde_json::Value = serde_json::from_str(text)?;
+        let libraries = std::iter::once(&stub["main_library"]).chain(
+            stub["libraries"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+        );
+        return Ok(libraries
+            .filter_map(|library| library["install_names"].as_array())
+            .flatten()
+            .filter_map(|name| name["name"].as_str())
+            .map(str::to_string)
+            .collect());
+    }
+    Ok(text
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("install-name:"))
+        .map(|name| name.trim().trim_matches(['\'', '"']).to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let mapper = PathMapper::default();
+        let policy = SystemPolicy::default()
+            .allow(&["/Library/Frameworks/**".to_string()])
+            .unwrap()
+            .deny(&["/usr/lib/libcurl*".to_string()])
+            .unwrap();
+        let decide = |name: &str| policy.decide(name, &mapper);
+
+        assert_eq!(decide("/usr/lib/libSystem.B.dylib"), Decision::system());
+        assert_eq!(
+            decide("/System/Library/Frameworks/Foundation.framework/Versions/C/Foundation"),
+            Decision::system()
+        );
+        assert_eq!(
+            decide("/Library/Frameworks/Mono.framework/Versions/6/Mono"),
+            Decision::system()
+        );
+        assert_eq!(decide("/usr/lib/libcurl.4.dylib"), Decision::bundle());
+        assert_eq!(
+            decide("/opt/homebrew/lib/libpng16.16.dylib"),
+            Decision::bundle()
+        );
+
+        let local = decide("/usr/local/lib/libfoo.dylib");
+        assert!(!local.is_system && local.warning.is_some());
+        let unknown = decide("/usr/lib/libnotapple.dylib");
+        assert!(unknown.is_system && unknown.warning.is_some());
+    }
+
+    #[test]
+    fn test_macos_version() {
+        let policy = SystemPolicy::default();
+        assert!(policy.is_shipped("/usr/lib/libcrypto.dylib"));
+        assert!(policy.is_shipped("/usr/lib/swift/libswiftCore.dylib"));
+
+        let mojave = SystemPolicy::default().macos_version("10.14".parse().unwrap());
+        assert!(mojave.is_shipped("/usr/lib/libcrypto.dylib"));
+        assert!(!mojave.is_shipped("/usr/lib/swift/libswiftCore.dylib"));
+
+        let sonoma = SystemPolicy::default().macos_version("14.0".parse().unwrap());
+        assert!(!sonoma.is_shipped("/usr/lib/libcrypto.dylib"));
+        assert!(sonoma.is_shipped("/usr/lib/swift/libswiftCore.dylib"));
+        let warning = sonoma
+            .decide("/usr/lib/libcrypto.dylib", &PathMapper::default())
+            .warning
+            .unwrap();
+        assert!(warning.contains("macOS 14.0"), "{}", warning);
+    }
+
+    #[test]
+    fn test_sdk_stubs() {
+        let sdk = tempfile::tempdir().unwrap();
+        let folder = sdk.path().join("usr/lib/swift");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(
+            sdk.path().join("usr/lib/libfoo.tbd"),
+            "--- !tapi-tbd\ntbd-version: 4\ninstall-name: '/usr/lib/libfoo.1.dylib'\n...\n\
+             --- !tapi-tbd\ninstall-name: '/usr/lib/system/libfoo_bar.dylib'\n...\n",
+        )
+        .unwrap();
+        std::fs::write(
+            folder.join("libswiftBar.tbd"),
+            r#"{"tapi_tbd_version":5,"main_library":{"install_names":[{"name":"/usr/lib/swift/libswiftBar.dylib"}]}}"#,
+        )
+        .unwrap();
+
+        let policy = SystemPolicy::default().sdk(sdk.path()).unwrap();
+        for name in [
+            "/usr/lib/libfoo.1.dylib",
+            "/usr/lib/system/libfoo_bar.dylib",
+            "/usr/lib/swift/libswiftBar.dylib",
+        ] {
+            assert!(policy.sdk_libraries.contains(name), "{}", name);
+        }
+        assert!(policy.is_shipped("/usr/lib/libfoo.1.dylib"));
+    }
+}
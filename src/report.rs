@@ -0,0 +1,74 @@
+// What a bundling run did, for callers embedding the bundler.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BundleReport {
+    pub copied: Vec<CopiedFile>,
+    pub rewritten: Vec<RewrittenCommand>,
+    pub signed: Vec<PathBuf>,
+    /// Files written from the plan, e.g. `Info.plist`.
+    pub generated: Vec<PathBuf>,
+    /// Libraries left out because nothing imports symbols from them.
+    pub pruned: Vec<PrunedLibrary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CopiedFile {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrunedLibrary {
+    pub source: PathBuf,
+    /// Size of the library in bytes.
+    pub size: u64,
+}
+
+/// A load command changed in a copied file. `old` is empty for added
+/// commands and `new` for deleted ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RewrittenCommand {
+    pub file: PathBuf,
+    pub command: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl BundleReport {
+    /// Rewritten load commands of one copied file.
+    pub fn rewritten_in(&self, file: &Path) -> impl Iterator<Item = &RewrittenCommand> {
+        self.rewritten
+            .iter()
+            .filter(move |command| command.file == file)
+    }
+
+    /// Bytes not copied thanks to pruning.
+    pub fn pruned_size(&self) -> u64 {
+        self.pruned.iter().map(|library| library.size).sum()
+    }
+
+    /// Moves every destination path below `from` to `to`, used once a staged
+    /// bundle has been published.
+    pub(crate) fn rebase(&mut self, from: &Path, to: &Path) {
+        let rebase = |path: &mut PathBuf| {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = to.join(rest);
+            }
+        };
+        for copied in &mut self.copied {
+            rebase(&mut copied.destination);
+        }
+        for command in &mut self.rewritten {
+            rebase(&mut command.file);
+        }
+        for signed in &mut self.signed {
+            rebase(signed);
+        }
+        for generated in &mut self.generated {
+            rebase(generated);
+        }
+    }
+}
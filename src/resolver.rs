@@ -0,0 +1,418 @@
+// Static emulation of the dyld library search.
+//
+// dyld resolves an install name in the context of the whole chain of images
+// that led to it being loaded:
+//
+// - `@executable_path/` is replaced by the folder of the main executable, no
+//   matter which image references it.
+// - `@loader_path/` is replaced by the folder of the image containing the load
+//   command (or, inside an LC_RPATH, the image owning that LC_RPATH).
+// - `@rpath/` is tried against the LC_RPATH entries of the loading image
+//   first, then of the image that loaded it and so on up to the main
+//   executable.
+// - Whatever couldn't be found is finally looked up by its leaf name in the
+//   DYLD_FALLBACK_LIBRARY_PATH / DYLD_FALLBACK_FRAMEWORK_PATH defaults.
+//
+// Absolute paths are passed through the `PathMapper` before touching the file
+// system, so that a copied macOS tree can be bundled on another host. Which
+// absolute install names are left to the system is up to the `SystemPolicy`.
+
+use crate::error::Error;
+use crate::helpers::read_slices;
+use crate::policy::SystemPolicy;
+use crate::sysroot::PathMapper;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_FALLBACK_LIBRARY_PATHS: &[&str] = &["/usr/local/lib", "/usr/lib"];
+pub const DEFAULT_FALLBACK_FRAMEWORK_PATHS: &[&str] =
+    &["/Library/Frameworks", "/System/Library/Frameworks"];
+
+/// One image of the loader chain together with its raw LC_RPATH values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoaderImage {
+    pub path: PathBuf,
+    pub rpaths: Vec<String>,
+}
+
+impl LoaderImage {
+    /// Reads the rpaths of every architecture slice of `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut rpaths: Vec<String> = vec![];
+        for macho in read_slices(path)? {
+            for rpath in macho.rpaths() {
+                if !rpaths.iter().any(|rp| rp == rpath) {
+                    rpaths.push(rpath.to_string());
+                }
+            }
+        }
+        Ok(LoaderImage {
+            path: path.to_path_buf(),
+            rpaths,
+        })
+    }
+
+    fn folder(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    executable_folder: PathBuf,
+    fallback_library_paths: Vec<PathBuf>,
+    fallback_framework_paths: Vec<PathBuf>,
+    mapper: PathMapper,
+    policy: Arc<SystemPolicy>,
+}
+
+impl Resolver {
+    /// Creates a resolver for the process started from `executable`. When
+    /// bundling a dynamic library there is no main executable, the library
+    /// itself is used instead.
+    pub fn new(executable: &Path) -> Self {
+        Resolver {
+            executable_folder: executable
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| PathBuf::from(".")),
+            fallback_library_paths: DEFAULT_FALLBACK_LIBRARY_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            fallback_framework_paths: DEFAULT_FALLBACK_FRAMEWORK_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            mapper: PathMapper::default(),
+            policy: Arc::default(),
+        }
+    }
+
+    pub fn with_mapper(mut self, mapper: PathMapper) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn mapper(&self) -> &PathMapper {
+        &self.mapper
+    }
+
+    pub fn with_policy(mut self, policy: Arc<SystemPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> &SystemPolicy {
+        &self.policy
+    }
+
+    pub fn with_fallback_paths(
+        mut self,
+        libraries: Vec<PathBuf>,
+        frameworks: Vec<PathBuf>,
+    ) -> Self {
+        self.fallback_library_paths = libraries;
+        self.fallback_framework_paths = frameworks;
+        self
+    }
+
+    /// Resolves `install_name` as referenced by the last image of `chain`.
+    /// The chain starts with the main executable (or the base library).
+    pub fn resolve(&self, install_name: &str, chain: &[LoaderImage]) -> Result<PathBuf> {
+        let Some(loader) = chain.last() else {
+            return Err(anyhow!("Empty loader chain for: {}", install_name));
+        };
+
+        let mut expanded_rpaths = 0;
+        if let Some(rest) = install_name.strip_prefix("@rpath/") {
+            // The rpath stack: the loading image first, then its loaders.
+            for image in chain.iter().rev() {
+                for rpath in &image.rpaths {
+                    let Some(folder) = self.expand(rpath, image) else {
+                        log::debug!("Skipping rpath: {} of: {}", rpath, image.path.display());
+                        continue;
+                    };
+                    expanded_rpaths += 1;
+                    let candidate = folder.join(rest);
+                    log::debug!("Trying: {}", candidate.display());
+                    if let Some(found) = self.existing(candidate) {
+                        return Ok(found);
+                    }
+                }
+            }
+        } else if let Some(path) = self.expand(install_name, loader) {
+            log::debug!("Trying: {}", path.display());
+            if let Some(found) = self.existing(path) {
+                return Ok(found);
+            }
+        }
+
+        if let Some(path) = self.search_fallback_paths(install_name) {
+            log::debug!("Found in fallback paths: {}", path.display());
+            return Ok(path);
+        }
+
+        if install_name.starts_with("@rpath/") && expanded_rpaths == 0 {
+            return Err(Error::UnresolvedRpath {
+                install_name: install_name.to_string(),
+                loader: loader.path.clone(),
+                rpaths: chain
+                    .iter()
+                    .rev()
+                    .flat_map(|image| image.rpaths.iter().cloned())
+                    .collect(),
+            }
+            .into());
+        }
+        Err(Error::MissingDependency {
+            install_name: install_name.to_string(),
+            loader: loader.path.clone(),
+        }
+        .into())
+    }
+
+    /// Expands `@executable_path` / `@loader_path` of a path or rpath found in
+    /// `image`. Relative paths depend on the working directory of the process
+    /// at runtime, so they can't be resolved statically.
+    pub fn expand(&self, path: &str, image: &LoaderImage) -> Option<PathBuf> {
+        if let Some(rest) = strip_token(path, "@executable_path") {
+            Some(join_rest(&self.executable_folder, rest))
+        } else if let Some(rest) = strip_token(path, "@loader_path") {
+            Some(join_rest(image.folder(), rest))
+        } else if Path::new(path).is_absolute() {
+            Some(self.mapper.map(Path::new(path)))
+        } else {
+            None
+        }
+    }
+
+    fn search_fallback_paths(&self, install_name: &str) -> Option<PathBuf> {
+        if let Some(partial) = framework_partial_path(install_name) {
+            let found = self
+                .fallback_framework_paths
+                .iter()
+                .find_map(|folder| self.existing(self.mapper.map(folder).join(partial)));
+            if found.is_some() {
+                return found;
+            }
+        }
+        let leaf = Path::new(install_name).file_name()?;
+        self.fallback_library_paths
+            .iter()
+            .find_map(|folder| self.existing(self.mapper.map(folder).join(leaf)))
+    }
+
+    /// Returns the candidate if it is an existing file. When paths are mapped
+    /// onto a copied tree, symlinks have to be followed through the mapper
+    /// and the real path is returned instead.
+    fn existing(&self, candidate: PathBuf) -> Option<PathBuf> {
+        if self.mapper.is_identity() {
+            return candidate.is_file().then_some(candidate);
+        }
+        self.mapper
+            .canonicalize(&candidate)
+            .ok()
+            .filter(|path| path.is_file())
+    }
+}
+
+/// Strips `token` when it is the whole path or followed by a separator.
+fn strip_token<'a>(path: &'a str, token: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(token)?;
+    if rest.is_empty() {
+        Some("")
+    } else {
+        rest.strip_prefix('/')
+    }
+}
+
+fn join_rest(folder: &Path, rest: &str) -> PathBuf {
+    if rest.is_empty() {
+        folder.to_path_buf()
+    } else {
+        folder.join(rest)
+    }
+}
+
+/// `Foo.framework/Versions/A/Foo` part of a framework install name.
+fn framework_partial_path(install_name: &str) -> Option<&str> {
+    let end = install_name.find(".framework/")?;
+    let start = install_name[..end].rfind('/').map(|i| i + 1).unwrap_or(0);
+    Some(&install_name[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _touch(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"").unwrap();
+    }
+
+    fn _image(path: &Path, rpaths: &[&str]) -> LoaderImage {
+        LoaderImage {
+            path: path.to_path_buf(),
+            rpaths: rpaths.iter().map(|rp| rp.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_inherited_rpath() {
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("bin/tool");
+        let lib_a = root.path().join("lib/deep/libA.dylib");
+        let lib_b = root.path().join("lib/libB.dylib");
+        _touch(&exe);
+        _touch(&lib_a);
+        _touch(&lib_b);
+
+        let resolver = Resolver::new(&exe);
+        let chain = [
+            _image(&exe, &["@executable_path/../lib"]),
+            _image(&lib_a, &["@loader_path"]),
+        ];
+        let res = resolver.resolve("@rpath/libB.dylib", &chain).unwrap();
+        assert_eq!(res, root.path().join("bin/../lib/libB.dylib"));
+    }
+
+    #[test]
+    fn test_loader_rpath_first() {
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("bin/tool");
+        let lib_a = root.path().join("lib/libA.dylib");
+        _touch(&exe);
+        _touch(&lib_a);
+        _touch(&root.path().join("bin/libB.dylib"));
+        _touch(&root.path().join("lib/libB.dylib"));
+
+        let resolver = Resolver::new(&exe);
+        let chain = [
+            _image(&exe, &["@loader_path"]),
+            _image(&lib_a, &["@loader_path"]),
+        ];
+        let res = resolver.resolve("@rpath/libB.dylib", &chain).unwrap();
+        assert_eq!(res, root.path().join("lib/libB.dylib"));
+    }
+
+    #[test]
+    fn test_executable_path_is_main_executable() {
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("bin/tool");
+        let lib_a = root.path().join("lib/libA.dylib");
+        _touch(&exe);
+        _touch(&lib_a);
+        _touch(&root.path().join("bin/plugins/libC.dylib"));
+
+        let resolver = Resolver::new(&exe);
+        let chain = [
+            _image(&exe, &[]),
+            _image(&lib_a, &["@executable_path/plugins"]),
+        ];
+        let res = resolver.resolve("@rpath/libC.dylib", &chain).unwrap();
+        assert_eq!(res, root.path().join("bin/plugins/libC.dylib"));
+        let res = resolver
+            .resolve("@executable_path/plugins/libC.dylib", &chain)
+            .unwrap();
+        assert_eq!(res, root.path().join("bin/plugins/libC.dylib"));
+        assert!(
+            resolver
+                .resolve("@loader_path/plugins/libC.dylib", &chain)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_missing_and_unresolved() {
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("bin/tool");
+        _touch(&exe);
+
+        let resolver = Resolver::new(&exe);
+        let chain = [_image(&exe, &["@loader_path/../lib", "relative/lib"])];
+        let err = resolver.resolve("@rpath/libB.dylib", &chain).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::MissingDependency { install_name, .. }) if install_name == "@rpath/libB.dylib"
+        ));
+
+        // Only the relative rpath is left, it can't be expanded statically.
+        let chain = [_image(&exe, &["relative/lib"])];
+        let err = resolver.resolve("@rpath/libB.dylib", &chain).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::UnresolvedRpath { rpaths, .. }) if rpaths == &["relative/lib"]
+        ));
+    }
+
+    #[test]
+    fn test_fallback_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("bin/tool");
+        _touch(&exe);
+        _touch(&root.path().join("fallback/libD.dylib"));
+        _touch(&root.path().join("frameworks/Foo.framework/Versions/A/Foo"));
+
+        let resolver = Resolver::new(&exe).with_fallback_paths(
+            vec![root.path().join("fallback")],
+            vec![root.path().join("frameworks")],
+        );
+        let chain = [_image(&exe, &[])];
+        let res = resolver.resolve("@rpath/libD.dylib", &chain).unwrap();
+        assert_eq!(res, root.path().join("fallback/libD.dylib"));
+        let res = resolver
+            .resolve("/opt/missing/lib/libD.dylib", &chain)
+            .unwrap();
+        assert_eq!(res, root.path().join("fallback/libD.dylib"));
+        let res = resolver
+            .resolve("@rpath/Foo.framework/Versions/A/Foo", &chain)
+            .unwrap();
+        assert_eq!(
+            res,
+            root.path().join("frameworks/Foo.framework/Versions/A/Foo")
+        );
+    }
+
+    #[test]
+    fn test_mapped_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let exe = root.path().join("bin/tool");
+        _touch(&exe);
+        _touch(&root.path().join("brew/lib/libpng16.16.dylib"));
+        _touch(&root.path().join("sysroot/usr/local/lib/libz.1.dylib"));
+
+        let mapper = PathMapper::new(
+            Some(root.path().join("sysroot")),
+            vec![
+                format!("/opt/homebrew={}", root.path().join("brew").display())
+                    .parse()
+                    .unwrap(),
+            ],
+        );
+        let resolver = Resolver::new(&exe).with_mapper(mapper);
+        let chain = [_image(&exe, &["/opt/homebrew/lib"])];
+        let res = resolver
+            .resolve("@rpath/libpng16.16.dylib", &chain)
+            .unwrap();
+        assert_eq!(res, root.path().join("brew/lib/libpng16.16.dylib"));
+        let res = resolver
+            .resolve("/opt/homebrew/lib/libpng16.16.dylib", &chain)
+            .unwrap();
+        assert_eq!(res, root.path().join("brew/lib/libpng16.16.dylib"));
+        let res = resolver
+            .resolve("/opt/zlib/lib/libz.1.dylib", &chain)
+            .unwrap();
+        assert_eq!(res, root.path().join("sysroot/usr/local/lib/libz.1.dylib"));
+    }
+
+    #[test]
+    fn test_framework_partial_path() {
+        assert_eq!(
+            framework_partial_path("/opt/qt/lib/QtCore.framework/Versions/A/QtCore"),
+            Some("QtCore.framework/Versions/A/QtCore")
+        );
+        assert_eq!(framework_partial_path("@rpath/libz.dylib"), None);
+    }
+}
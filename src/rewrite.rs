@@ -0,0 +1,450 @@
+// In-process replacement for `install_name_tool`.
+//
+// All edits are applied to the load command region of a single Mach-O image.
+// Commands that are not touched keep their exact bytes, edited commands are
+// re-encoded and the whole region is written back in place. The region may
+// only grow into the padding the linker left between the load commands and
+// the first section, exactly like `install_name_tool` which refuses to touch
+// anything else in the file.
+
+use crate::error::Error;
+use crate::macho::{Command, DylibKind, LC_RPATH, LoadCommand, MachO, slice_ranges, write_u32};
+use crate::symbols;
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A single load command change, mirroring the `install_name_tool` options.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Edit {
+    /// `-id <name>`: changes LC_ID_DYLIB of a dynamic library.
+    SetId(String),
+    /// `-change <old> <new>`: rewrites every LC_*_DYLIB matching `old`.
+    ChangeInstallName { old: String, new: String },
+    /// `-add_rpath <path>`
+    AddRpath(String),
+    /// `-delete_rpath <path>`
+    DeleteRpath(String),
+    /// `-rpath <old> <new>`
+    ChangeRpath { old: String, new: String },
+    /// Drops every LC_*_DYLIB matching the name, the library ordinals of
+    /// imports bound to later dependencies are renumbered. `install_name_tool`
+    /// has no such option.
+    RemoveDylib(String),
+}
+
+/// Applies `edits` in order to the Mach-O image in `data`.
+///
+/// Fails without touching `data` if an edit can not be applied or if the
+/// new load commands do not fit into the available header padding.
+pub fn apply_edits(data: &mut [u8], edits: &[Edit]) -> Result<()> {
+    let macho = MachO::parse(data)?;
+    let le = macho.header.little_endian;
+    let align = if macho.header.is_64 { 8 } else { 4 };
+
+    let mut commands: Vec<RawCommand> = macho
+        .commands
+        .iter()
+        .map(|lc| RawCommand::from_load_command(lc, data))
+        .collect();
+    let mut ordinal = 0;
+    for command in &mut commands {
+        if matches!(command.command, Command::Dylib(ref d) if d.kind != DylibKind::Id) {
+            ordinal += 1;
+            command.ordinal = Some(ordinal);
+        }
+    }
+
+    for edit in edits {
+        apply_edit(&mut commands, edit, le, align)?;
+    }
+
+    let old_end = macho.load_commands_end();
+    let content_start = macho.content_start(data.len());
+    let sizeofcmds: usize = commands.iter().map(|c| c.bytes.len()).sum();
+    let new_end = macho.header.size() + sizeofcmds;
+
+    if new_end > content_start {
+        return Err(Error::InsufficientPadding {
+            action: "rewrite load commands",
+            needed: new_end - content_start,
+            available: content_start.saturating_sub(old_end),
+        }
+        .into());
+    }
+
+    // Imports name their library by its position among the dependencies,
+    // which changes when one before it is removed.
+    let mut ordinals = vec![None; ordinal as usize];
+    for (new, old) in commands.iter().filter_map(|c| c.ordinal).enumerate() {
+        ordinals[old as usize - 1] = Some(new as i32 + 1);
+    }
+    let mut renumbered = None;
+    if ordinals
+        .iter()
+        .enumerate()
+        .any(|(i, new)| *new != Some(i as i32 + 1))
+    {
+        let mut copy = data.to_vec();
+        symbols::renumber_ordinals(&mut copy, &macho, &ordinals)?;
+        renumbered = Some(copy);
+    }
+
+    if macho.code_signature().is_some() {
+        log::debug!("Load commands changed, existing code signature is now invalid");
+    }
+
+    if let Some(renumbered) = renumbered {
+        data.copy_from_slice(&renumbered);
+    }
+    write_u32(data, 16, commands.len() as u32, le)?;
+    write_u32(data, 20, sizeofcmds as u32, le)?;
+    let mut offset = macho.header.size();
+    for command in &commands {
+        data[offset..offset + command.bytes.len()].copy_from_slice(&command.bytes);
+        offset += command.bytes.len();
+    }
+    // Whatever the old commands occupied beyond the new end is padding now.
+    let clear_end = old_end.max(new_end);
+    data[new_end..clear_end].fill(0);
+
+    Ok(())
+}
+
+/// Reads `file_path`, applies `edits` to every architecture slice and writes
+/// it back in place.
+pub fn edit_file(file_path: &Path, edits: &[Edit]) -> Result<()> {
+    edit_file_with(file_path, |_| edits.to_vec())
+}
+
+/// Like `edit_file` but lets the caller decide the edits per slice, e.g. to
+/// add an rpath only to the slices which do not have it yet.
+pub fn edit_file_with<F>(file_path: &Path, edits_for: F) -> Result<()>
+where
+    F: Fn(&MachO) -> Vec<Edit>,
+{
+    let mut data = std::fs::read(file_path).map_err(Error::io("reading", file_path))?;
+
+    for range in slice_ranges(&data)? {
+        let slice = &mut data[range];
+        let macho = MachO::parse(slice)?;
+        let edits = edits_for(&macho);
+        if edits.is_empty() {
+            continue;
+        }
+        apply_edits(slice, &edits).with_context(|| {
+            format!(
+                "Error while editing: {} (architecture {})",
+                file_path.display(),
+                macho.header.arch_name()
+            )
+        })?;
+    }
+
+    std::fs::write(file_path, &data).map_err(Error::io("writing", file_path))?;
+    Ok(())
+}
+
+struct RawCommand {
+    command: Command,
+    bytes: Vec<u8>,
+    /// Library ordinal of a dependency in the original image.
+    ordinal: Option<i32>,
+}
+
+impl RawCommand {
+    fn from_load_command(lc: &LoadCommand, data: &[u8]) -> Self {
+        RawCommand {
+            command: lc.command.clone(),
+            bytes: data[lc.offset..lc.offset + lc.cmdsize as usize].to_vec(),
+            ordinal: None,
+        }
+    }
+
+    fn rpath(path: &str, le: bool, align: usize) -> Result<Self> {
+        let mut bytes = vec![0u8; 12];
+        write_u32(&mut bytes, 0, LC_RPATH, le)?;
+        write_u32(&mut bytes, 8, 12, le)?;
+        append_str(&mut bytes, path, le, align)?;
+        Ok(RawCommand {
+            command: Command::Rpath(path.to_string()),
+            bytes,
+            ordinal: None,
+        })
+    }
+
+    /// Re-encodes a LC_*_DYLIB command with a new name, keeping the
+    /// timestamp and version fields of the original.
+    fn rename_dylib(&mut self, name: &str, le: bool, align: usize) -> Result<()> {
+        let Command::Dylib(ref mut dylib) = self.command else {
+            return Err(anyhow!("Not a dylib load command"));
+        };
+        let mut bytes = self.bytes[..24].to_vec();
+        write_u32(&mut bytes, 8, 24, le)?;
+        append_str(&mut bytes, name, le, align)?;
+        dylib.name = name.to_string();
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    fn is_rpath(&self, path: &str) -> bool {
+        matches!(self.command, Command::Rpath(ref rp) if rp == path)
+    }
+}
+
+fn apply_edit(commands: &mut Vec<RawCommand>, edit: &Edit, le: bool, align: usize) -> Result<()> {
+    match edit {
+        Edit::SetId(id) => {
+            let Some(command) = commands
+                .iter_mut()
+                .find(|c| matches!(c.command, Command::Dylib(ref d) if d.kind == DylibKind::Id))
+            else {
+                return Err(anyhow!(
+                    "No LC_ID_DYLIB found, file is not a dynamic library"
+                ));
+            };
+            command.rename_dylib(id, le, align)?;
+        }
+        Edit::ChangeInstallName { old, new } => {
+            let mut changed = 0;
+            for command in commands.iter_mut() {
+                let matches = matches!(
+                    command.command,
+                    Command::Dylib(ref d) if d.kind != DylibKind::Id && d.name == *old
+                );
+                if matches {
+                    command.rename_dylib(new, le, align)?;
+                    changed += 1;
+                }
+            }
+            if changed == 0 {
+                log::debug!("No load command found for install name: {}", old);
+            }
+        }
+        Edit::AddRpath(path) => {
+            if commands.iter().any(|c| c.is_rpath(path)) {
+                return Err(anyhow!("File already has LC_RPATH for: {}", path));
+            }
+            // New rpaths go after the last existing one so that the search
+            // order of the already present entries does not change.
+            let position = commands
+                .iter()
+                .rposition(|c| matches!(c.command, Command::Rpath(_)))
+                .map(|i| i + 1)
+                .unwrap_or(commands.len());
+            commands.insert(position, RawCommand::rpath(path, le, align)?);
+        }
+        Edit::DeleteRpath(path) => {
+            let Some(position) = commands.iter().position(|c| c.is_rpath(path)) else {
+                return Err(anyhow!("No LC_RPATH load command with path: {}", path));
+            };
+            commands.remove(position);
+        }
+        Edit::ChangeRpath { old, new } => {
+            if commands.iter().any(|c| c.is_rpath(new)) {
+                return Err(anyhow!("File already has LC_RPATH for: {}", new));
+            }
+            let Some(position) = commands.iter().position(|c| c.is_rpath(old)) else {
+                return Err(anyhow!("No LC_RPATH load command with path: {}", old));
+            };
+            commands[position] = RawCommand::rpath(new, le, align)?;
+        }
+        Edit::RemoveDylib(name) => {
+            let count = commands.len();
+            commands.retain(|c| {
+                !matches!(c.command, Command::Dylib(ref d) if d.kind != DylibKind::Id && d.name == *name)
+            });
+            if commands.len() == count {
+                return Err(anyhow!("No dylib load command with install name: {}", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends a NUL terminated string padded to `align` and fixes up cmdsize.
+fn append_str(bytes: &mut Vec<u8>, value: &str, le: bool, align: usize) -> Result<()> {
+    bytes.extend_from_slice(value.as_bytes());
+    bytes.push(0);
+    bytes.resize(bytes.len().next_multiple_of(align), 0);
+    let cmdsize = bytes.len() as u32;
+    write_u32(bytes, 4, cmdsize, le)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn _read_resource(relative: &str) -> Vec<u8> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources")
+            .join(relative);
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn test_set_id() {
+        let mut data = _read_resource("poppler/libs/libassuan.9.dylib");
+        let before = MachO::parse(&data).unwrap();
+        let edits = [Edit::SetId(
+            "@rpath/a/much/longer/id/libassuan.9.dylib".to_string(),
+        )];
+        apply_edits(&mut data, &edits).unwrap();
+
+        let after = MachO::parse(&data).unwrap();
+        let id = after.id().unwrap();
+        assert_eq!(id.name, "@rpath/a/much/longer/id/libassuan.9.dylib");
+        assert_eq!(id.current_version, before.id().unwrap().current_version);
+        assert_eq!(after.header.ncmds, before.header.ncmds);
+        assert_eq!(
+            after.dependencies().collect::<Vec<_>>(),
+            before.dependencies().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_change_install_name() {
+        let mut data = _read_resource("poppler/pdftoppm");
+        let edits = [Edit::ChangeInstallName {
+            old: "@rpath/liblcms2.2.dylib".to_string(),
+            new: "@executable_path/libs/liblcms2.2.dylib".to_string(),
+        }];
+        apply_edits(&mut data, &edits).unwrap();
+
+        let macho = MachO::parse(&data).unwrap();
+        let names: Vec<&str> = macho.dependencies().map(|d| d.name.as_str()).collect();
+        assert!(names.contains(&"@executable_path/libs/liblcms2.2.dylib"));
+        assert!(!names.contains(&"@rpath/liblcms2.2.dylib"));
+        assert!(names.contains(&"@rpath/libpoppler.154.0.0.dylib"));
+    }
+
+    #[test]
+    fn test_rpaths() {
+        let mut data = _read_resource("poppler/pdftoppm");
+        let edits = [
+            Edit::AddRpath("@loader_path/Frameworks".to_string()),
+            Edit::DeleteRpath("@loader_path/../lib".to_string()),
+            Edit::ChangeRpath {
+                old: "@loader_path/libs".to_string(),
+                new: "@executable_path/libs".to_string(),
+            },
+        ];
+        apply_edits(&mut data, &edits).unwrap();
+
+        let macho = MachO::parse(&data).unwrap();
+        let rpaths: Vec<&str> = macho.rpaths().collect();
+        assert_eq!(
+            rpaths,
+            vec!["@executable_path/libs", "@loader_path/Frameworks"]
+        );
+    }
+
+    #[test]
+    fn test_rpath_errors() {
+        let original = _read_resource("poppler/pdftoppm");
+        let mut data = original.clone();
+        let res = apply_edits(
+            &mut data,
+            &[Edit::AddRpath("@loader_path/libs".to_string())],
+        );
+        assert!(res.is_err());
+        let res = apply_edits(
+            &mut data,
+            &[Edit::DeleteRpath("@loader_path/foo".to_string())],
+        );
+        assert!(res.is_err());
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_set_id_on_executable() {
+        let mut data = _read_resource("poppler/pdftoppm");
+        let res = apply_edits(&mut data, &[Edit::SetId("@rpath/pdftoppm".to_string())]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_edit_universal_file() {
+        use crate::macho::{build_fat, parse_slices, tests::_fake_x86_64};
+
+        let arm64 = _read_resource("poppler/libs/libfreetype.6.dylib");
+        let x86_64 = _fake_x86_64(&arm64);
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("libfreetype.6.dylib");
+        std::fs::write(&file, build_fat(&[(&x86_64, 12), (&arm64, 14)]).unwrap()).unwrap();
+
+        edit_file(
+            &file,
+            &[Edit::ChangeInstallName {
+                old: "@rpath/libpng16.16.dylib".to_string(),
+                new: "@loader_path/libpng16.16.dylib".to_string(),
+            }],
+        )
+        .unwrap();
+
+        let slices = parse_slices(&std::fs::read(&file).unwrap()).unwrap();
+        assert_eq!(slices.len(), 2);
+        for macho in slices {
+            assert!(
+                macho
+                    .dependencies()
+                    .any(|d| d.name == "@loader_path/libpng16.16.dylib")
+            );
+        }
+    }
+
+    #[test]
+    fn test_insufficient_padding() {
+        let original = _read_resource("poppler/libs/libassuan.9.dylib");
+        let mut data = original.clone();
+        let padding = MachO::parse(&data).unwrap().header_padding(data.len());
+        let id = format!("@rpath/{}.dylib", "x".repeat(padding + 64));
+        let res = apply_edits(&mut data, &[Edit::SetId(id)]);
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("more bytes needed"), "{}", err);
+        let Some(&Error::InsufficientPadding { available, .. }) = err.downcast_ref::<Error>()
+        else {
+            panic!("Not a padding error: {}", err);
+        };
+        assert_eq!(available, padding);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_remove_dylib() {
+        // libpoppler links gpgme without importing anything from it.
+        let mut data = _read_resource("poppler/libs/libpoppler.154.0.0.dylib");
+        let before = MachO::parse(&data).unwrap();
+        let bound = |data: &[u8], macho: &MachO| -> Vec<(String, String)> {
+            let dependencies: Vec<_> = macho.dependencies().collect();
+            symbols::imports(data, macho)
+                .unwrap()
+                .into_iter()
+                .filter(|import| import.ordinal > 0)
+                .map(|import| {
+                    let dylib = dependencies[import.ordinal as usize - 1];
+                    (import.name, dylib.name.clone())
+                })
+                .collect()
+        };
+        let expected = bound(&data, &before);
+
+        let edits = [Edit::RemoveDylib("@rpath/libgpgme.45.dylib".to_string())];
+        apply_edits(&mut data, &edits).unwrap();
+        let after = MachO::parse(&data).unwrap();
+        assert_eq!(
+            after.dependencies().count(),
+            before.dependencies().count() - 1
+        );
+        assert!(
+            after
+                .dependencies()
+                .all(|d| d.name != "@rpath/libgpgme.45.dylib")
+        );
+        assert_eq!(bound(&data, &after), expected);
+
+        let edits = [Edit::RemoveDylib("@rpath/libfreetype.6.dylib".to_string())];
+        assert!(apply_edits(&mut data, &edits).is_err());
+    }
+}
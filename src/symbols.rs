@@ -0,0 +1,648 @@
+// Imported and exported symbols of a Mach-O image.
+//
+// Imports are collected from the chained fixups or the dyld bind opcodes,
+// and from the undefined entries of the symbol table. With a two-level
+// namespace each import carries the ordinal of the library it has to come
+// from, i.e. its position among the dependencies in load command order.
+// Exports are read from the export trie, or from the symbol table for images
+// without one.
+
+use crate::error::Error;
+use crate::macho::{LinkeditData, MachO, read_u32, read_u64, slice_ranges, write_u32};
+use anyhow::{Result, anyhow};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ops::Range;
+use std::path::Path;
+
+/// Library ordinal of a symbol defined in the image itself.
+pub const SELF_ORDINAL: i32 = 0;
+/// Library ordinal of a symbol looked up in the main executable.
+pub const EXECUTABLE_ORDINAL: i32 = -1;
+/// Library ordinal of a symbol looked up in every loaded image.
+pub const FLAT_LOOKUP_ORDINAL: i32 = -2;
+/// Library ordinal of a weak definition coalesced across images.
+pub const WEAK_LOOKUP_ORDINAL: i32 = -3;
+
+const N_STAB: u8 = 0xe0;
+const N_TYPE: u8 = 0x0e;
+const N_EXT: u8 = 0x01;
+const N_UNDF: u8 = 0x0;
+const N_ABS: u8 = 0x2;
+const N_INDR: u8 = 0xa;
+const N_SECT: u8 = 0xe;
+const N_WEAK_REF: u16 = 0x0040;
+
+const BIND_OPCODE_MASK: u8 = 0xf0;
+const BIND_IMMEDIATE_MASK: u8 = 0x0f;
+const BIND_OPCODE_DONE: u8 = 0x00;
+const BIND_OPCODE_SET_DYLIB_ORDINAL_IMM: u8 = 0x10;
+const BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB: u8 = 0x20;
+const BIND_OPCODE_SET_DYLIB_SPECIAL_IMM: u8 = 0x30;
+const BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM: u8 = 0x40;
+const BIND_OPCODE_SET_TYPE_IMM: u8 = 0x50;
+const BIND_OPCODE_SET_ADDEND_SLEB: u8 = 0x60;
+const BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB: u8 = 0x70;
+const BIND_OPCODE_ADD_ADDR_ULEB: u8 = 0x80;
+const BIND_OPCODE_DO_BIND: u8 = 0x90;
+const BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB: u8 = 0xa0;
+const BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED: u8 = 0xb0;
+const BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB: u8 = 0xc0;
+const BIND_OPCODE_THREADED: u8 = 0xd0;
+const BIND_SUBOPCODE_THREADED_SET_BIND_ORDINAL_TABLE_SIZE_ULEB: u8 = 0x00;
+const BIND_SYMBOL_FLAGS_WEAK_IMPORT: u8 = 0x1;
+
+const DYLD_CHAINED_IMPORT: u32 = 1;
+const DYLD_CHAINED_IMPORT_ADDEND: u32 = 2;
+const DYLD_CHAINED_IMPORT_ADDEND64: u32 = 3;
+
+const EXPORT_SYMBOL_FLAGS_REEXPORT: u64 = 0x08;
+const EXPORT_SYMBOL_FLAGS_STUB_AND_RESOLVER: u64 = 0x10;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Import {
+    pub name: String,
+    /// 1-based index into `MachO::dependencies`, or one of the special
+    /// ordinals.
+    pub ordinal: i32,
+    /// A weak import may be missing at runtime, its address is then null.
+    pub is_weak: bool,
+}
+
+/// Every symbol `data`, a single Mach-O image, binds to another image.
+pub fn imports(data: &[u8], macho: &MachO) -> Result<Vec<Import>> {
+    let mut found = BTreeMap::new();
+    if let Some(fixups) = macho.chained_fixups() {
+        chained_imports(linkedit(data, fixups)?, &mut found)?;
+    }
+    if let Some(info) = macho.dyld_info() {
+        bind_imports(linkedit(data, &info.bind)?, &mut found)?;
+        bind_imports(linkedit(data, &info.lazy_bind)?, &mut found)?;
+    }
+    for symbol in symbol_table(data, macho)? {
+        let is_import = symbol.n_type & N_STAB == 0
+            && symbol.n_type & N_TYPE == N_UNDF
+            && symbol.n_type & N_EXT != 0
+            && symbol.n_value == 0;
+        if !is_import {
+            continue;
+        }
+        let ordinal = match symbol.n_desc >> 8 {
+            0xfe => FLAT_LOOKUP_ORDINAL,
+            0xff => EXECUTABLE_ORDINAL,
+            ordinal => ordinal as i32,
+        };
+        insert(
+            &mut found,
+            symbol.name,
+            ordinal,
+            symbol.n_desc & N_WEAK_REF != 0,
+        );
+    }
+
+    Ok(found
+        .into_iter()
+        .map(|((ordinal, name), is_weak)| {
+            let ordinal = if macho.is_two_level() {
+                ordinal
+            } else {
+                FLAT_LOOKUP_ORDINAL
+            };
+            Import {
+                name,
+                ordinal,
+                is_weak,
+            }
+        })
+        .collect())
+}
+
+/// Every symbol `data`, a single Mach-O image, makes available to others.
+pub fn exports(data: &[u8], macho: &MachO) -> Result<BTreeSet<String>> {
+    let trie = macho
+        .exports_trie()
+        .or(macho.dyld_info().map(|info| &info.export))
+        .filter(|trie| trie.datasize > 0);
+    if let Some(trie) = trie {
+        let mut exports = BTreeSet::new();
+        read_trie(linkedit(data, trie)?, &mut exports)?;
+        return Ok(exports);
+    }
+
+    Ok(symbol_table(data, macho)?
+        .into_iter()
+        .filter(|symbol| {
+            symbol.n_type & N_STAB == 0
+                && symbol.n_type & N_EXT != 0
+                && matches!(symbol.n_type & N_TYPE, N_SECT | N_ABS | N_INDR)
+        })
+        .map(|symbol| symbol.name)
+        .collect())
+}
+
+/// Install names of the dependencies of `file_path` at least one symbol is
+/// imported from, in any architecture slice. `None` when that can't be told
+/// because symbols are looked up in every loaded image.
+pub fn used_dependencies(file_path: &Path) -> Result<Option<HashSet<String>>> {
+    let data = std::fs::read(file_path).map_err(Error::io("reading", file_path))?;
+    let mut used = HashSet::new();
+    for range in slice_ranges(&data)? {
+        let image = &data[range];
+        let macho = MachO::parse(image)?;
+        let dependencies: Vec<_> = macho.dependencies().collect();
+        for import in imports(image, &macho)? {
+            if import.ordinal == FLAT_LOOKUP_ORDINAL {
+                return Ok(None);
+            }
+            let index = (import.ordinal - 1) as usize;
+            if let Some(dylib) = dependencies.get(index).filter(|_| import.ordinal > 0) {
+                used.insert(dylib.name.clone());
+            }
+        }
+    }
+    Ok(Some(used))
+}
+
+/// Rewrites every library ordinal imports of `data`, a single Mach-O image,
+/// are bound to. `ordinals[i]` is the new ordinal of the library with
+/// ordinal `i + 1`, `None` for a library whose load command is removed.
+/// Fails if anything is still imported from a removed one.
+pub fn renumber_ordinals(data: &mut [u8], macho: &MachO, ordinals: &[Option<i32>]) -> Result<()> {
+    let renumber = |ordinal: i32| -> Result<i32> {
+        if ordinal <= SELF_ORDINAL || ordinal as usize > ordinals.len() {
+            return Ok(ordinal);
+        }
+        ordinals[ordinal as usize - 1].ok_or_else(|| {
+            anyhow!(
+                "Symbols are still imported from library ordinal {}",
+                ordinal
+            )
+        })
+    };
+
+    if let Some(fixups) = macho.chained_fixups() {
+        let range = linkedit_range(data, fixups)?;
+        renumber_chained(&mut data[range], &renumber)?;
+    }
+    if let Some(info) = macho.dyld_info() {
+        for program in [&info.bind, &info.lazy_bind] {
+            let range = linkedit_range(data, program)?;
+            let data = &mut data[range];
+            for operand in walk_binds(data)?.ordinals {
+                let ordinal = renumber(operand.ordinal)?;
+                if operand.operand.is_empty() {
+                    data[operand.opcode] = BIND_OPCODE_SET_DYLIB_ORDINAL_IMM | ordinal as u8;
+                } else {
+                    write_uleb(&mut data[operand.operand], ordinal as u64)?;
+                }
+            }
+        }
+    }
+    if macho.is_two_level() {
+        let le = macho.header.little_endian;
+        for symbol in symbol_table(data, macho)? {
+            let ordinal = (symbol.n_desc >> 8) as i32;
+            let is_import = symbol.n_type & (N_STAB | N_TYPE) == N_UNDF && symbol.n_value == 0;
+            if !is_import || !(1..0xfe).contains(&ordinal) {
+                continue;
+            }
+            // The high byte of n_desc.
+            let position = if le {
+                symbol.offset + 7
+            } else {
+                symbol.offset + 6
+            };
+            data[position] = renumber(ordinal)? as u8;
+        }
+    }
+    Ok(())
+}
+
+// An import is only weak if every place binding it says so.
+fn insert(found: &mut BTreeMap<(i32, String), bool>, name: String, ordinal: i32, is_weak: bool) {
+    found
+        .entry((ordinal, name))
+        .and_modify(|weak| *weak &= is_weak)
+        .or_insert(is_weak);
+}
+
+fn linkedit<'a>(data: &'a [u8], range: &LinkeditData) -> Result<&'a [u8]> {
+    Ok(&data[linkedit_range(data, range)?])
+}
+
+fn linkedit_range(data: &[u8], range: &LinkeditData) -> Result<Range<usize>> {
+    let start = range.dataoff as usize;
+    let end = start + range.datasize as usize;
+    if end > data.len() {
+        return Err(anyhow!(
+            "Linkedit data exceeds file size at offset {}",
+            start
+        ));
+    }
+    Ok(start..end)
+}
+
+struct Nlist {
+    /// Position of the entry in the image.
+    offset: usize,
+    name: String,
+    n_type: u8,
+    n_desc: u16,
+    n_value: u64,
+}
+
+fn symbol_table(data: &[u8], macho: &MachO) -> Result<Vec<Nlist>> {
+    let Some(symtab) = macho.symtab() else {
+        return Ok(vec![]);
+    };
+    let le = macho.header.little_endian;
+    let strings = linkedit(
+        data,
+        &LinkeditData {
+            dataoff: symtab.stroff,
+            datasize: symtab.strsize,
+        },
+    )?;
+    let entry_size = if macho.header.is_64 { 16 } else { 12 };
+    // `nsyms` comes from the file, no more entries fit behind `symoff`.
+    let room = data.len().saturating_sub(symtab.symoff as usize) / entry_size;
+    let mut symbols = Vec::with_capacity((symtab.nsyms as usize).min(room));
+    for index in 0..symtab.nsyms as usize {
+        let off = symtab.symoff as usize + index * entry_size;
+        let n_strx = read_u32(data, off, le)? as usize;
+        let Some(&[n_type, _, desc_0, desc_1]) = data.get(off + 4..off + 8) else {
+            return Err(anyhow!("Unexpected end of data at offset {}", off + 4));
+        };
+        let n_desc = if le {
+            u16::from_le_bytes([desc_0, desc_1])
+        } else {
+            u16::from_be_bytes([desc_0, desc_1])
+        };
+        let n_value = if macho.header.is_64 {
+            read_u64(data, off + 8, le)?
+        } else {
+            read_u32(data, off + 8, le)? as u64
+        };
+        symbols.push(Nlist {
+            offset: off,
+            name: read_cstr(strings, n_strx)?.0,
+            n_type,
+            n_desc,
+            n_value,
+        });
+    }
+    Ok(symbols)
+}
+
+fn chained_imports(data: &[u8], found: &mut BTreeMap<(i32, String), bool>) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let imports_offset = read_u32(data, 8, true)? as usize;
+    let symbols_offset = read_u32(data, 12, true)? as usize;
+    let imports_count = read_u32(data, 16, true)? as usize;
+    let imports_format = read_u32(data, 20, true)?;
+    let symbols_format = read_u32(data, 24, true)?;
+    if symbols_format != 0 {
+        return Err(anyhow!(
+            "Compressed chained fixup symbols are not supported"
+        ));
+    }
+    let symbols = data
+        .get(symbols_offset..)
+        .ok_or_else(|| anyhow!("Chained fixup symbols exceed linkedit data"))?;
+
+    for index in 0..imports_count {
+        let (ordinal, is_weak, name_offset) = match imports_format {
+            DYLD_CHAINED_IMPORT | DYLD_CHAINED_IMPORT_ADDEND => {
+                let size = if imports_format == DYLD_CHAINED_IMPORT {
+                    4
+                } else {
+                    8
+                };
+                let import = read_u32(data, imports_offset + index * size, true)?;
+                let ordinal = import & 0xff;
+                (
+                    // Only the top of the range holds the negative special
+                    // ordinals.
+                    if ordinal > 0xf0 {
+                        ordinal as u8 as i8 as i32
+                    } else {
+                        ordinal as i32
+                    },
+                    import & 0x100 != 0,
+                    (import >> 9) as usize,
+                )
+            }
+            DYLD_CHAINED_IMPORT_ADDEND64 => {
+                let import = read_u64(data, imports_offset + index * 16, true)?;
+                let ordinal = import & 0xffff;
+                (
+                    if ordinal > 0xfff0 {
+                        ordinal as u16 as i16 as i32
+                    } else {
+                        ordinal as i32
+                    },
+                    import & 0x1_0000 != 0,
+                    (import >> 32) as usize,
+                )
+            }
+            format => {
+                return Err(anyhow!("Unknown chained fixup import format: {}", format));
+            }
+        };
+        let (name, _) = read_cstr(symbols, name_offset)?;
+        insert(found, name, ordinal, is_weak);
+    }
+    Ok(())
+}
+
+// Ordinals of the imports table are fixed size fields, they are replaced
+// in place.
+fn renumber_chained(data: &mut [u8], renumber: &dyn Fn(i32) -> Result<i32>) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let imports_offset = read_u32(data, 8, true)? as usize;
+    let imports_count = read_u32(data, 16, true)? as usize;
+    let imports_format = read_u32(data, 20, true)?;
+    let (size, limit) = match imports_format {
+        DYLD_CHAINED_IMPORT => (4, 0xf0),
+        DYLD_CHAINED_IMPORT_ADDEND => (8, 0xf0),
+        DYLD_CHAINED_IMPORT_ADDEND64 => (16, 0xfff0),
+        format => {
+            return Err(anyhow!("Unknown chained fixup import format: {}", format));
+        }
+    };
+    for index in 0..imports_count {
+        let offset = imports_offset + index * size;
+        let import = read_u32(data, offset, true)?;
+        let mask = if limit == 0xf0 { 0xff } else { 0xffff };
+        let ordinal = import & mask;
+        if ordinal == 0 || ordinal > limit {
+            continue;
+        }
+        let ordinal = renumber(ordinal as i32)? as u32;
+        write_u32(data, offset, (import & !mask) | ordinal, true)?;
+    }
+    Ok(())
+}
+
+fn bind_imports(data: &[u8], found: &mut BTreeMap<(i32, String), bool>) -> Result<()> {
+    for (name, ordinal, is_weak) in walk_binds(data)?.binds {
+        insert(found, name, ordinal, is_weak);
+    }
+    Ok(())
+}
+
+/// A bind opcode setting a library ordinal. `operand` holds its ULEB
+/// encoded value, it is empty when the ordinal is part of the opcode.
+struct OrdinalOperand {
+    opcode: usize,
+    operand: Range<usize>,
+    ordinal: i32,
+}
+
+#[derive(Default)]
+struct BindProgram {
+    ordinals: Vec<OrdinalOperand>,
+    binds: Vec<(String, i32, bool)>,
+}
+
+// Lazy bindings separate their entries with DONE opcodes, so the whole
+// program is read whatever kind it is.
+fn walk_binds(data: &[u8]) -> Result<BindProgram> {
+    let mut program = BindProgram::default();
+    let mut offset = 0;
+    let mut ordinal = 0;
+    let mut name = String::new();
+    let mut is_weak = false;
+    while offset < data.len() {
+        let opcode = offset;
+        let byte = data[offset];
+        offset += 1;
+        let immediate = byte & BIND_IMMEDIATE_MASK;
+        match byte & BIND_OPCODE_MASK {
+            BIND_OPCODE_DONE | BIND_OPCODE_SET_TYPE_IMM => {}
+            BIND_OPCODE_SET_DYLIB_ORDINAL_IMM => {
+                ordinal = immediate as i32;
+                program.ordinals.push(OrdinalOperand {
+                    opcode,
+                    operand: offset..offset,
+                    ordinal,
+                });
+            }
+            BIND_OPCODE_SET_DYLIB_ORDINAL_ULEB => {
+                ordinal = read_uleb(data, &mut offset)? as i32;
+                program.ordinals.push(OrdinalOperand {
+                    opcode,
+                    operand: opcode + 1..offset,
+                    ordinal,
+                });
+            }
+            BIND_OPCODE_SET_DYLIB_SPECIAL_IMM => {
+                ordinal = if immediate == 0 {
+                    SELF_ORDINAL
+                } else {
+                    (BIND_OPCODE_MASK | immediate) as i8 as i32
+                };
+            }
+            BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM => {
+                let (symbol, length) = read_cstr(data, offset)?;
+                offset += length + 1;
+                name = symbol;
+                is_weak = immediate & BIND_SYMBOL_FLAGS_WEAK_IMPORT != 0;
+            }
+            BIND_OPCODE_SET_ADDEND_SLEB
+            | BIND_OPCODE_SET_SEGMENT_AND_OFFSET_ULEB
+            | BIND_OPCODE_ADD_ADDR_ULEB => {
+                read_uleb(data, &mut offset)?;
+            }
+            BIND_OPCODE_DO_BIND | BIND_OPCODE_DO_BIND_ADD_ADDR_IMM_SCALED => {
+                program.binds.push((name.clone(), ordinal, is_weak));
+            }
+            BIND_OPCODE_DO_BIND_ADD_ADDR_ULEB => {
+                read_uleb(data, &mut offset)?;
+                program.binds.push((name.clone(), ordinal, is_weak));
+            }
+            BIND_OPCODE_DO_BIND_ULEB_TIMES_SKIPPING_ULEB => {
+                read_uleb(data, &mut offset)?;
+                read_uleb(data, &mut offset)?;
+                program.binds.push((name.clone(), ordinal, is_weak));
+            }
+            BIND_OPCODE_THREADED => {
+                if immediate == BIND_SUBOPCODE_THREADED_SET_BIND_ORDINAL_TABLE_SIZE_ULEB {
+                    read_uleb(data, &mut offset)?;
+                }
+            }
+            opcode => return Err(anyhow!("Unknown bind opcode: 0x{:02x}", opcode)),
+        }
+    }
+    Ok(program)
+}
+
+// Walks the export trie depth first. Every terminal node is an exported
+// symbol, re-exports under another name included.
+fn read_trie(data: &[u8], exports: &mut BTreeSet<String>) -> Result<()> {
+    let mut stack = vec![(0usize, Vec::new())];
+    let mut visited = BTreeSet::new();
+    while let Some((node, prefix)) = stack.pop() {
+        if !visited.insert(node) {
+            return Err(anyhow!("Export trie loops at offset {}", node));
+        }
+        let mut offset = node;
+        let terminal_size = read_uleb(data, &mut offset)? as usize;
+        if terminal_size > 0 {
+            let mut terminal = offset;
+            let flags = read_uleb(data, &mut terminal)?;
+            if flags & EXPORT_SYMBOL_FLAGS_REEXPORT != 0 {
+                read_uleb(data, &mut terminal)?;
+            } else if flags & EXPORT_SYMBOL_FLAGS_STUB_AND_RESOLVER != 0 {
+                read_uleb(data, &mut terminal)?;
+                read_uleb(data, &mut terminal)?;
+            } else {
+                read_uleb(data, &mut terminal)?;
+            }
+            exports.insert(String::from_utf8_lossy(&prefix).into_owned());
+        }
+        offset += terminal_size;
+        let children = *data
+            .get(offset)
+            .ok_or_else(|| anyhow!("Export trie exceeds linkedit data"))?;
+        offset += 1;
+        for _ in 0..children {
+            let (edge, length) = read_bytes(data, offset)?;
+            offset += length + 1;
+            let child = read_uleb(data, &mut offset)? as usize;
+            let mut name = prefix.clone();
+            name.extend_from_slice(edge);
+            stack.push((child, name));
+        }
+    }
+    Ok(())
+}
+
+fn read_uleb(data: &[u8], offset: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data
+            .get(*offset)
+            .ok_or_else(|| anyhow!("Unexpected end of data at offset {}", offset))?;
+        *offset += 1;
+        if shift < 64 {
+            value |= ((byte & 0x7f) as u64) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+// Keeps the encoded length so that nothing after the value moves, a
+// smaller value is padded with continuation bytes.
+fn write_uleb(data: &mut [u8], mut value: u64) -> Result<()> {
+    let length = data.len();
+    for (index, byte) in data.iter_mut().enumerate() {
+        *byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if index + 1 < length {
+            *byte |= 0x80;
+        }
+    }
+    if value != 0 {
+        return Err(anyhow!("Value doesn't fit into {} ULEB bytes", length));
+    }
+    Ok(())
+}
+
+fn read_bytes(data: &[u8], offset: usize) -> Result<(&[u8], usize)> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| anyhow!("String offset {} out of bounds", offset))?;
+    let Some(end) = bytes.iter().position(|&b| b == 0) else {
+        return Err(anyhow!("Unterminated string at offset {}", offset));
+    };
+    Ok((&bytes[..end], end))
+}
+
+// Returns the string and its length without the terminating null byte.
+fn read_cstr(data: &[u8], offset: usize) -> Result<(String, usize)> {
+    let (bytes, length) = read_bytes(data, offset)?;
+    Ok((String::from_utf8_lossy(bytes).into_owned(), length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn _read(relative: &str) -> (Vec<u8>, MachO) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources/poppler")
+            .join(relative);
+        let data = std::fs::read(path).unwrap();
+        let macho = MachO::parse(&data).unwrap();
+        (data, macho)
+    }
+
+    #[test]
+    fn test_imports_and_exports() {
+        let (data, macho) = _read("pdftoppm");
+        let imports = imports(&data, &macho).unwrap();
+        let dependencies: Vec<_> = macho.dependencies().collect();
+        let poppler = dependencies
+            .iter()
+            .position(|d| d.name == "@rpath/libpoppler.154.0.0.dylib")
+            .unwrap() as i32
+            + 1;
+        let from_poppler: Vec<_> = imports.iter().filter(|i| i.ordinal == poppler).collect();
+        assert!(!from_poppler.is_empty());
+        assert!(imports.iter().all(|i| i.ordinal == WEAK_LOOKUP_ORDINAL
+            || (i.ordinal > 0 && i.ordinal as usize <= dependencies.len())));
+
+        let (data, macho) = _read("libs/libpoppler.154.0.0.dylib");
+        let exports = exports(&data, &macho).unwrap();
+        for import in from_poppler {
+            assert!(exports.contains(&import.name), "{}", import.name);
+        }
+    }
+
+    #[test]
+    fn test_read_trie() {
+        // _a at the root edge, _ab and _b below a shared `_` node.
+        let trie = [
+            0x00, 0x01, b'_', 0x00, 0x05, //
+            0x00, 0x02, b'a', 0x00, 0x0d, b'b', 0x00, 0x14, //
+            0x02, 0x00, 0x10, 0x01, b'b', 0x00, 0x18, //
+            0x02, 0x00, 0x20, 0x00, //
+            0x02, 0x00, 0x30, 0x00,
+        ];
+        let mut exports = BTreeSet::new();
+        read_trie(&trie, &mut exports).unwrap();
+        assert_eq!(
+            exports.into_iter().collect::<Vec<_>>(),
+            vec!["_a", "_ab", "_b"]
+        );
+    }
+
+    #[test]
+    fn test_bind_imports() {
+        let mut opcodes = vec![BIND_OPCODE_SET_DYLIB_ORDINAL_IMM | 2];
+        opcodes.push(BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM);
+        opcodes.extend_from_slice(b"_png_create\0");
+        opcodes.push(BIND_OPCODE_DO_BIND);
+        opcodes.push(BIND_OPCODE_SET_DYLIB_SPECIAL_IMM | 0x0e);
+        opcodes.push(BIND_OPCODE_SET_SYMBOL_TRAILING_FLAGS_IMM | BIND_SYMBOL_FLAGS_WEAK_IMPORT);
+        opcodes.extend_from_slice(b"_optional\0");
+        opcodes.extend_from_slice(&[BIND_OPCODE_DO_BIND, BIND_OPCODE_DONE]);
+
+        let mut found = BTreeMap::new();
+        bind_imports(&opcodes, &mut found).unwrap();
+        assert_eq!(found.get(&(2, "_png_create".to_string())), Some(&false));
+        assert_eq!(
+            found.get(&(FLAT_LOOKUP_ORDINAL, "_optional".to_string())),
+            Some(&true)
+        );
+    }
+}
@@ -0,0 +1,228 @@
+// Mapping of macOS paths onto the host file system.
+//
+// Absolute install names and rpaths found in binaries refer to the Mac they
+// were built on, e.g. `/opt/homebrew/opt/libpng/lib/libpng16.16.dylib`. When
+// bundling a copied macOS tree on another machine those paths are translated
+// with the longest matching prefix rule first and the sysroot otherwise.
+// Absolute symlinks inside the copied tree are translated the same way.
+
+use crate::error::Error;
+use anyhow::{Result, anyhow};
+use std::collections::VecDeque;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// A `FROM=TO` prefix remapping rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixMap {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl FromStr for PrefixMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((from, to)) = s.split_once('=') else {
+            return Err(anyhow!("Prefix map must look like FROM=TO: {}", s));
+        };
+        if !Path::new(from).is_absolute() {
+            return Err(anyhow!("Prefix map source must be absolute: {}", from));
+        }
+        if to.is_empty() {
+            return Err(anyhow!("Prefix map target is empty: {}", s));
+        }
+        Ok(PrefixMap {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathMapper {
+    sysroot: Option<PathBuf>,
+    prefixes: Vec<PrefixMap>,
+}
+
+impl PathMapper {
+    pub fn new(sysroot: Option<PathBuf>, prefixes: Vec<PrefixMap>) -> Self {
+        PathMapper { sysroot, prefixes }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.sysroot.is_none() && self.prefixes.is_empty()
+    }
+
+    /// Translates an absolute macOS path into a host path. Relative paths
+    /// are returned untouched.
+    pub fn map(&self, path: &Path) -> PathBuf {
+        if !path.is_absolute() {
+            return path.to_path_buf();
+        }
+
+        let best = self
+            .prefixes
+            .iter()
+            .filter(|rule| path.starts_with(&rule.from))
+            .max_by_key(|rule| rule.from.components().count());
+        if let Some(rule) = best {
+            let rest = path.strip_prefix(&rule.from).unwrap_or(path);
+            return join_rest(&rule.to, rest);
+        }
+
+        if let Some(ref sysroot) = self.sysroot {
+            let rest = path.strip_prefix("/").unwrap_or(path);
+            return join_rest(sysroot, rest);
+        }
+
+        path.to_path_buf()
+    }
+
+    /// Like `Path::canonicalize`, but absolute symlink targets are mapped
+    /// before following them so they don't escape into the host system.
+    pub fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        if self.is_identity() {
+            return Ok(path.canonicalize().map_err(Error::io("resolving", path))?);
+        }
+
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            std::env::current_dir()?.join(path)
+        };
+
+        let mut pending: VecDeque<PathBuf> = components(&path);
+        let mut resolved = PathBuf::from("/");
+        let mut hops = 0;
+
+        while let Some(component) = pending.pop_front() {
+            if component == Path::new("..") {
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&component);
+            if !candidate.is_symlink() {
+                resolved = candidate;
+                continue;
+            }
+
+            hops += 1;
+            if hops > MAX_SYMLINK_HOPS {
+                return Err(anyhow!("Too many levels of symlinks: {}", path.display()));
+            }
+            let target =
+                std::fs::read_link(&candidate).map_err(Error::io("reading link", &candidate))?;
+            log::debug!(
+                "Following symlink: {} -> {}",
+                candidate.display(),
+                target.display()
+            );
+            let mut target_components = if target.is_absolute() {
+                resolved = PathBuf::from("/");
+                components(&self.map(&target))
+            } else {
+                components(&target)
+            };
+            target_components.extend(pending);
+            pending = target_components;
+        }
+
+        if !resolved.exists() {
+            return Err(Error::io("resolving", &path)(std::io::ErrorKind::NotFound.into()).into());
+        }
+        Ok(resolved)
+    }
+}
+
+/// Normal and `..` components of a path, roots and `.` are dropped.
+fn components(path: &Path) -> VecDeque<PathBuf> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(PathBuf::from(name)),
+            Component::ParentDir => Some(PathBuf::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+fn join_rest(base: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_map_from_str() {
+        let rule: PrefixMap = "/opt/homebrew=/mnt/mac-sdk/homebrew".parse().unwrap();
+        assert_eq!(rule.from, PathBuf::from("/opt/homebrew"));
+        assert_eq!(rule.to, PathBuf::from("/mnt/mac-sdk/homebrew"));
+        assert!("opt/homebrew=/mnt".parse::<PrefixMap>().is_err());
+        assert!("/opt/homebrew".parse::<PrefixMap>().is_err());
+    }
+
+    #[test]
+    fn test_map() {
+        let mapper = PathMapper::new(
+            Some(PathBuf::from("/mnt/sysroot")),
+            vec![
+                "/opt/homebrew=/mnt/brew".parse().unwrap(),
+                "/opt/homebrew/opt/qt=/mnt/qt".parse().unwrap(),
+            ],
+        );
+        assert_eq!(
+            mapper.map(Path::new("/opt/homebrew/lib/libpng16.16.dylib")),
+            PathBuf::from("/mnt/brew/lib/libpng16.16.dylib")
+        );
+        assert_eq!(
+            mapper.map(Path::new("/opt/homebrew/opt/qt/lib/QtCore")),
+            PathBuf::from("/mnt/qt/lib/QtCore")
+        );
+        assert_eq!(
+            mapper.map(Path::new("/usr/local/lib/libz.dylib")),
+            PathBuf::from("/mnt/sysroot/usr/local/lib/libz.dylib")
+        );
+        assert_eq!(
+            mapper.map(Path::new("/opt/homebrewery/lib")),
+            PathBuf::from("/mnt/sysroot/opt/homebrewery/lib")
+        );
+        assert_eq!(mapper.map(Path::new("libs")), PathBuf::from("libs"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_canonicalize_absolute_symlink() {
+        let root = tempfile::tempdir().unwrap();
+        let sysroot = root.path().canonicalize().unwrap();
+        let cellar = sysroot.join("opt/homebrew/Cellar/libpng/1.6/lib");
+        std::fs::create_dir_all(&cellar).unwrap();
+        std::fs::write(cellar.join("libpng16.16.dylib"), b"").unwrap();
+        std::fs::create_dir_all(sysroot.join("opt/homebrew/opt")).unwrap();
+        std::os::unix::fs::symlink(
+            "/opt/homebrew/Cellar/libpng/1.6",
+            sysroot.join("opt/homebrew/opt/libpng"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("libpng16.16.dylib", cellar.join("libpng16.dylib")).unwrap();
+
+        let mapper = PathMapper::new(Some(sysroot.clone()), vec![]);
+        let path = mapper.map(Path::new("/opt/homebrew/opt/libpng/lib/libpng16.dylib"));
+        assert_eq!(
+            mapper.canonicalize(&path).unwrap(),
+            cellar.join("libpng16.16.dylib")
+        );
+        let path = sysroot.join("opt/homebrew/opt/libpng/lib/../lib/libpng16.16.dylib");
+        assert_eq!(
+            mapper.canonicalize(&path).unwrap(),
+            cellar.join("libpng16.16.dylib")
+        );
+    }
+}
@@ -0,0 +1,532 @@
+// Verification of a bundle after it has been produced.
+//
+// Every Mach-O inside the output folder is parsed and its dependencies are
+// resolved the way dyld would, starting from each executable of the bundle
+// (or from a library itself when no executable loads it). The bundle is
+// broken when a dependency which is not part of the OS resolves outside the
+// folder or not at all, when an rpath points outside the folder, or when a
+// Homebrew / `/usr/local` path is still referenced.
+//
+// Finding a file for every dependency is not enough, a library built from
+// another version can lack symbols. Every symbol an image imports is looked
+// up in the exports of the library its two-level namespace ordinal points
+// to, missing ones are reported per library. Weak imports may be missing.
+// A library's exports include those of the libraries it re-exports, the way
+// dyld follows LC_REEXPORT_DYLIB when binding.
+//
+// LC_LOAD_WEAK_DYLIB libraries are optional, one missing from the bundle is
+// only logged as a warning.
+
+use crate::error::Error;
+use crate::helpers::{get_dependencies, is_system_folder, normalize_path, read_slices};
+use crate::macho::{DylibKind, FileKind, MachO, detect_file_kind, slice_ranges};
+use crate::policy::SystemPolicy;
+use crate::resolver::{LoaderImage, Resolver};
+use crate::symbols;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Absolute paths which only exist on the machine the bundle was built on.
+const FORBIDDEN_PREFIXES: [&str; 2] = ["/opt/homebrew", "/usr/local"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Issue {
+    pub file: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file.display(), self.message)
+    }
+}
+
+/// Exports of every library slice looked at so far, by file and cpu type.
+/// `None` when the library has no slice of that type.
+type ExportCache = HashMap<(PathBuf, i32), Option<BTreeSet<String>>>;
+
+/// Checks the bundle in `root`, libraries `policy` considers part of macOS
+/// are not looked for. Returns every problem found, an empty list means the
+/// bundle is self-contained.
+pub fn verify_bundle(root: &Path, policy: Arc<SystemPolicy>) -> Result<Vec<Issue>> {
+    let root = root
+        .canonicalize()
+        .map_err(Error::io("reading bundle", root))?;
+    let mut files = vec![];
+    find_machos(&root, &mut files)?;
+    files.sort();
+    log::debug!("Mach-O files found in bundle: {}", files.len());
+
+    let mut issues = BTreeSet::new();
+    let mut executables = vec![];
+    for file in &files {
+        let slices = read_slices(file)?;
+        if slices.iter().any(|macho| macho.is_executable()) {
+            executables.push(file.clone());
+        }
+        for macho in &slices {
+            let names = macho
+                .id()
+                .into_iter()
+                .chain(macho.dependencies())
+                .map(|dylib| (dylib.kind.name(), dylib.name.as_str()))
+                .chain(macho.rpaths().map(|rpath| ("LC_RPATH", rpath)));
+            for (kind, name) in names {
+                if FORBIDDEN_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+                {
+                    issues.insert(issue(file, format!("{} still references: {}", kind, name)));
+                }
+            }
+        }
+    }
+
+    let mut reached = HashSet::new();
+    let mut exports = ExportCache::new();
+    for executable in &executables {
+        walk(
+            &root,
+            executable,
+            &policy,
+            &mut reached,
+            &mut exports,
+            &mut issues,
+        )?;
+    }
+    for file in &files {
+        if !reached.contains(file) {
+            walk(
+                &root,
+                file,
+                &policy,
+                &mut reached,
+                &mut exports,
+                &mut issues,
+            )?;
+        }
+    }
+
+    Ok(issues.into_iter().collect())
+}
+
+// Resolves the dependencies of every image loaded by `base`. Images reached
+// from an executable are remembered so that they are not verified again as
+// standalone libraries.
+fn walk(
+    root: &Path,
+    base: &Path,
+    policy: &Arc<SystemPolicy>,
+    reached: &mut HashSet<PathBuf>,
+    exports: &mut ExportCache,
+    issues: &mut BTreeSet<Issue>,
+) -> Result<()> {
+    let resolver = Resolver::new(base).with_policy(policy.clone());
+    let mut visited = HashSet::new();
+    visit(
+        root,
+        &resolver,
+        base,
+        &mut vec![],
+        &mut visited,
+        exports,
+        issues,
+    )?;
+    reached.extend(visited);
+    Ok(())
+}
+
+fn visit(
+    root: &Path,
+    resolver: &Resolver,
+    file: &Path,
+    chain: &mut Vec<LoaderImage>,
+    visited: &mut HashSet<PathBuf>,
+    exports: &mut ExportCache,
+    issues: &mut BTreeSet<Issue>,
+) -> Result<()> {
+    if !visited.insert(file.to_path_buf()) {
+        return Ok(());
+    }
+    let image = LoaderImage::read(file)?;
+    for rpath in &image.rpaths {
+        match resolver.expand(rpath, &image) {
+            Some(folder)
+                if normalize_path(&folder).starts_with(root) || is_system_folder(&folder) => {}
+            Some(folder) => {
+                issues.insert(issue(
+                    file,
+                    format!(
+                        "rpath points outside the bundle: {} ({})",
+                        rpath,
+                        normalize_path(&folder).display()
+                    ),
+                ));
+            }
+            None => {
+                issues.insert(issue(file, format!("rpath can't be resolved: {}", rpath)));
+            }
+        }
+    }
+    chain.push(image);
+
+    let mut children = vec![];
+    let mut libraries = HashMap::new();
+    for dylib in get_dependencies(file)? {
+        if is_system(resolver, &dylib.name) {
+            continue;
+        }
+        match resolver.resolve(&dylib.name, chain) {
+            Ok(path) => {
+                let path = path.canonicalize().map_err(Error::io("resolving", &path))?;
+                libraries.insert(dylib.name.clone(), path.clone());
+                if path.starts_with(root) {
+                    children.push(path);
+                } else {
+                    issues.insert(issue(
+                        file,
+                        format!(
+                            "{} escapes the bundle: {} ({})",
+                            dylib.kind.name(),
+                            dylib.name,
+                            path.display()
+                        ),
+                    ));
+                }
+            }
+            Err(_) if dylib.kind == DylibKind::Weak => {
+                log::warn!(
+                    "{}: {} not found in the bundle: {}",
+                    file.display(),
+                    dylib.kind.name(),
+                    dylib.name
+                );
+            }
+            Err(_) => {
+                issues.insert(issue(
+                    file,
+                    format!(
+                        "{} not found in the bundle: {}",
+                        dylib.kind.name(),
+                        dylib.name
+                    ),
+                ));
+            }
+        }
+    }
+
+    if let Err(err) = check_symbols(file, &libraries, resolver, chain, exports, issues) {
+        issues.insert(issue(
+            file,
+            format!("Imported symbols can't be checked: {:#}", err),
+        ));
+    }
+
+    for child in children {
+        visit(root, resolver, &child, chain, visited, exports, issues)?;
+    }
+    chain.pop();
+    Ok(())
+}
+
+// Looks up the imports of every slice of `file` in the libraries they are
+// bound to. `libraries` maps install names to the files they resolved to,
+// system libraries and ones which couldn't be found are not checked.
+fn check_symbols(
+    file: &Path,
+    libraries: &HashMap<String, PathBuf>,
+    resolver: &Resolver,
+    chain: &[LoaderImage],
+    exports: &mut ExportCache,
+    issues: &mut BTreeSet<Issue>,
+) -> Result<()> {
+    let data = std::fs::read(file).map_err(Error::io("reading", file))?;
+    for range in slice_ranges(&data)? {
+        let image = &data[range];
+        let macho = MachO::parse(image)?;
+        let arch = macho.header.arch_name();
+        let dependencies: Vec<_> = macho.dependencies().collect();
+        let mut missing: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for import in symbols::imports(image, &macho)? {
+            if import.is_weak || import.ordinal <= symbols::SELF_ORDINAL {
+                continue;
+            }
+            let Some(dylib) = dependencies.get(import.ordinal as usize - 1) else {
+                issues.insert(issue(
+                    file,
+                    format!(
+                        "{} is bound to library ordinal {} which doesn't exist ({})",
+                        import.name, import.ordinal, arch
+                    ),
+                ));
+                continue;
+            };
+            let Some(library) = libraries.get(&dylib.name) else {
+                continue;
+            };
+            let key = (library.clone(), macho.header.cputype);
+            if !exports.contains_key(&key) {
+                let found = library_exports(
+                    resolver,
+                    chain,
+                    library,
+                    macho.header.cputype,
+                    &mut HashSet::new(),
+                )?;
+                exports.insert(key.clone(), found);
+            }
+            if let Some(found) = &exports[&key]
+                && !found.contains(&import.name)
+            {
+                missing.entry(&dylib.name).or_default().push(import.name);
+            }
+        }
+        for (library, names) in missing {
+            issues.insert(issue(
+                file,
+                format!(
+                    "Undefined symbols from {} ({}): {}",
+                    library,
+                    arch,
+                    names.join(", ")
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Exports of the slice of `library` matching `cputype` and of every library
+// it re-exports. Re-exported system libraries and ones which can't be found
+// are left out, `seen` breaks re-export cycles.
+fn library_exports(
+    resolver: &Resolver,
+    chain: &[LoaderImage],
+    library: &Path,
+    cputype: i32,
+    seen: &mut HashSet<PathBuf>,
+) -> Result<Option<BTreeSet<String>>> {
+    seen.insert(library.to_path_buf());
+    let data = std::fs::read(library).map_err(Error::io("reading", library))?;
+    for range in slice_ranges(&data)? {
+        let image = &data[range];
+        let macho = MachO::parse(image)?;
+        if macho.header.cputype != cputype {
+            continue;
+        }
+        let mut found = symbols::exports(image, &macho)?;
+        let mut chain = chain.to_vec();
+        chain.push(LoaderImage::read(library)?);
+        for dylib in macho.dependencies() {
+            if dylib.kind != DylibKind::Reexport || is_system(resolver, &dylib.name) {
+                continue;
+            }
+            let Ok(path) = resolver.resolve(&dylib.name, &chain) else {
+                continue;
+            };
+            let path = path.canonicalize().map_err(Error::io("resolving", &path))?;
+            if seen.contains(&path) {
+                continue;
+            }
+            if let Some(reexported) = library_exports(resolver, &chain, &path, cputype, seen)? {
+                found.extend(reexported);
+            }
+        }
+        return Ok(Some(found));
+    }
+    Ok(None)
+}
+
+fn is_system(resolver: &Resolver, install_name: &str) -> bool {
+    resolver
+        .policy()
+        .decide(install_name, resolver.mapper())
+        .is_system
+}
+
+fn issue(file: &Path, message: String) -> Issue {
+    Issue {
+        file: file.to_path_buf(),
+        message,
+    }
+}
+
+// Symlinks are skipped, the files they point to are found on their own.
+fn find_machos(folder: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(folder).map_err(Error::io("reading folder", folder))? {
+        let path = entry.map_err(Error::io("reading folder", folder))?.path();
+        if path.is_symlink() {
+            continue;
+        }
+        if path.is_dir() {
+            find_machos(&path, files)?;
+        } else if matches!(detect_file_kind(&path)?, FileKind::MachO | FileKind::Fat) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NativeBackend;
+    use crate::helpers::{add_rpath, fix_install_name};
+    use crate::macho::tests::_set_dylib_kind;
+    use crate::model::Binary;
+    use crate::sysroot::PathMapper;
+
+    fn _bundle_poppler() -> tempfile::TempDir {
+        let input =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler/pdftoppm");
+        let output = tempfile::tempdir().unwrap();
+        Binary::new(input, true, true)
+            .unwrap()
+            .run(output.path(), None, &PathMapper::default(), &NativeBackend)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_valid_bundle() {
+        let output = _bundle_poppler();
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        assert!(issues.is_empty(), "{:#?}", issues);
+    }
+
+    #[test]
+    fn test_input_is_not_a_bundle() {
+        // The fixtures reference each other through `@loader_path/../lib`
+        // which is outside of the `libs` folder.
+        let resources = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_resources/poppler");
+        let issues = verify_bundle(&resources.join("libs"), Arc::default()).unwrap();
+        assert!(
+            issues
+                .iter()
+                .any(|i| i.message.starts_with("rpath points outside the bundle"))
+        );
+    }
+
+    #[test]
+    fn test_system_rpath() {
+        let output = _bundle_poppler();
+        let exe = output.path().join("pdftoppm");
+        add_rpath(&exe, Path::new("/usr/lib/swift")).unwrap();
+        assert!(
+            verify_bundle(output.path(), Arc::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_forbidden_paths_and_outside_rpaths() {
+        let output = _bundle_poppler();
+        let libpng = output.path().join("libs/libpng16.16.dylib");
+        add_rpath(&libpng, Path::new("/opt/homebrew/lib")).unwrap();
+
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        let messages: Vec<_> = issues.iter().map(|i| i.message.as_str()).collect();
+        assert!(messages.contains(&"LC_RPATH still references: /opt/homebrew/lib"));
+        assert!(
+            messages.contains(
+                &"rpath points outside the bundle: /opt/homebrew/lib (/opt/homebrew/lib)"
+            )
+        );
+    }
+
+    #[test]
+    fn test_undefined_symbols() {
+        let output = _bundle_poppler();
+        let exe = output.path().join("pdftoppm");
+        let data = std::fs::read(&exe).unwrap();
+        let (from, to) = (b"_cmsOpenProfileFromFile\0", b"_cmsOpenProfileFromFilX\0");
+        let mut renamed = data.clone();
+        for index in 0..data.len() - from.len() {
+            if &data[index..index + from.len()] == from {
+                renamed[index..index + to.len()].copy_from_slice(to);
+            }
+        }
+        std::fs::write(&exe, renamed).unwrap();
+
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        let messages: Vec<_> = issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["Undefined symbols from @rpath/liblcms2.2.dylib (arm64): _cmsOpenProfileFromFilX"]
+        );
+    }
+
+    #[test]
+    fn test_reexported_symbols() {
+        // pdftoppm binds its lcms2 imports to libpoppler, which only
+        // provides them once it re-exports liblcms2.
+        let output = _bundle_poppler();
+        let exe = output.path().join("pdftoppm");
+        let mut data = std::fs::read(&exe).unwrap();
+        let macho = MachO::parse(&data).unwrap();
+        symbols::renumber_ordinals(&mut data, &macho, &[Some(1), Some(1), Some(3), Some(4)])
+            .unwrap();
+        std::fs::write(&exe, data).unwrap();
+
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        assert!(issues.iter().any(|i| {
+            i.message
+                .starts_with("Undefined symbols from @rpath/libpoppler.154.0.0.dylib (arm64): ")
+                && i.message.contains("_cmsOpenProfileFromFile")
+        }));
+
+        let libpoppler = output.path().join("libs/libpoppler.154.0.0.dylib");
+        _set_dylib_kind(&libpoppler, "@rpath/liblcms2.2.dylib", DylibKind::Reexport);
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        assert!(issues.is_empty(), "{:#?}", issues);
+    }
+
+    #[test]
+    fn test_missing_weak_library() {
+        let output = _bundle_poppler();
+        let libpoppler = output.path().join("libs/libpoppler.154.0.0.dylib");
+        _set_dylib_kind(
+            &libpoppler,
+            "@rpath/libopenjp2.2.5.4.dylib",
+            DylibKind::Weak,
+        );
+        std::fs::remove_file(output.path().join("libs/libopenjp2.2.5.4.dylib")).unwrap();
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        assert!(issues.is_empty(), "{:#?}", issues);
+
+        _set_dylib_kind(
+            &libpoppler,
+            "@rpath/libopenjp2.2.5.4.dylib",
+            DylibKind::Load,
+        );
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        let messages: Vec<_> = issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec!["LC_LOAD_DYLIB not found in the bundle: @rpath/libopenjp2.2.5.4.dylib"]
+        );
+    }
+
+    #[test]
+    fn test_escaping_and_missing_dependencies() {
+        let output = _bundle_poppler();
+        let outside = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources/poppler/libs/liblcms2.2.dylib");
+        let exe = output.path().join("pdftoppm");
+        fix_install_name(&exe, "@rpath/liblcms2.2.dylib", outside.to_str().unwrap()).unwrap();
+        std::fs::remove_file(output.path().join("libs/libpng16.16.dylib")).unwrap();
+
+        let issues = verify_bundle(output.path(), Arc::default()).unwrap();
+        assert!(issues.iter().any(|i| {
+            i.file.ends_with("pdftoppm")
+                && i.message.starts_with("LC_LOAD_DYLIB escapes the bundle")
+        }));
+        assert!(issues.iter().any(|i| {
+            i.message == "LC_LOAD_DYLIB not found in the bundle: @rpath/libpng16.16.dylib"
+        }));
+    }
+}